@@ -0,0 +1,183 @@
+use crate::midi_parser::MidiMessage;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+/// Entries kept of every kind of event
+pub const HISTORY: usize = 16;
+
+/// Recent events for the terminal dashboard
+///
+/// Global like `METRICS`, the MIDI callback and the device threads record
+/// into it and the dashboard reads it at its own pace. Nothing is recorded
+/// until the dashboard enables it, so the hot paths skip the lock without it.
+pub static ACTIVITY: Activity = Activity::new();
+
+pub struct Activity {
+    enabled: AtomicBool,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    midi: VecDeque<(Instant, MidiMessage)>,
+    /// Held notes of each MIDI channel, one bit per note
+    held: [u128; 16],
+    console: VecDeque<(Instant, String)>,
+    /// Last input report written towards the console
+    report: Option<Vec<u8>>,
+    log: VecDeque<String>,
+}
+
+/// Copy of everything the dashboard shows, newest entries last
+#[derive(Debug, PartialEq, Clone)]
+pub struct ActivitySnapshot {
+    pub midi: Vec<(Instant, MidiMessage)>,
+    pub held: [u128; 16],
+    pub console: Vec<(Instant, String)>,
+    pub report: Option<Vec<u8>>,
+    pub log: Vec<String>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Activity {
+    pub const fn new() -> Activity {
+        Activity {
+            enabled: AtomicBool::new(false),
+            inner: Mutex::new(Inner {
+                midi: VecDeque::new(),
+                held: [0; 16],
+                console: VecDeque::new(),
+                report: None,
+                log: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Starts or stops recording events
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Whether events are recorded, for callers that would build them first
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Decoded MIDI message, note on and off update the held notes of its channel.
+    /// Timing clock and active sensing arrive continuously and are left out.
+    pub fn midi_message(&self, message: &MidiMessage) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut inner = self.lock();
+        match *message {
+            MidiMessage::NoteOn { channel, note, velocity } if velocity > 0 => {
+                inner.held[channel as usize & 0x0F] |= 1u128 << (note & 0x7F)
+            }
+            MidiMessage::NoteOn { channel, note, .. } | MidiMessage::NoteOff { channel, note, .. } => {
+                inner.held[channel as usize & 0x0F] &= !(1u128 << (note & 0x7F))
+            }
+            MidiMessage::TimingClock | MidiMessage::ActiveSensing => return,
+            _ => {}
+        }
+        push(&mut inner.midi, (Instant::now(), message.clone()));
+    }
+
+    /// Decoded packet the console sent, rumble is left out as it arrives continuously
+    pub fn console_packet<P: fmt::Display>(&self, packet: &P) {
+        if self.is_enabled() {
+            push(&mut self.lock().console, (Instant::now(), packet.to_string()));
+        }
+    }
+
+    pub fn input_report(&self, report: &[u8]) {
+        if self.is_enabled() {
+            self.lock().report = Some(report.to_vec());
+        }
+    }
+
+    pub fn log_line(&self, line: String) {
+        push(&mut self.lock().log, line);
+    }
+
+    pub fn snapshot(&self) -> ActivitySnapshot {
+        let inner = self.lock();
+        ActivitySnapshot {
+            midi: inner.midi.iter().cloned().collect(),
+            held: inner.held,
+            console: inner.console.iter().cloned().collect(),
+            report: inner.report.clone(),
+            log: inner.log.iter().cloned().collect(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+fn push<T>(entries: &mut VecDeque<T>, entry: T) {
+    if entries.len() >= HISTORY {
+        entries.pop_front();
+    }
+    entries.push_back(entry);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_held_notes_per_channel() {
+        let activity = Activity::new();
+        activity.set_enabled(true);
+        let note_on = |channel, note, velocity| MidiMessage::NoteOn { channel, note, velocity };
+        activity.midi_message(&note_on(0, 60, 100));
+        activity.midi_message(&note_on(3, 62, 100));
+        activity.midi_message(&note_on(3, 64, 100));
+        activity.midi_message(&MidiMessage::NoteOff { channel: 3, note: 62, velocity: 0 });
+        activity.midi_message(&note_on(0, 64, 0));
+        activity.midi_message(&MidiMessage::TimingClock);
+        activity.midi_message(&MidiMessage::ControlChange { channel: 0, controller: 1, value: 127 });
+
+        let snapshot = activity.snapshot();
+        assert_eq!(snapshot.held[0], 1 << 60);
+        assert_eq!(snapshot.held[3], 1 << 64);
+        assert_eq!(snapshot.midi.len(), 6);
+        assert_eq!(snapshot.midi[5].1, MidiMessage::ControlChange { channel: 0, controller: 1, value: 127 });
+    }
+
+    #[test]
+    fn records_nothing_until_enabled() {
+        let activity = Activity::new();
+        activity.midi_message(&MidiMessage::Start);
+        activity.console_packet(&"subcommand");
+        activity.input_report(&[0x30]);
+        let snapshot = activity.snapshot();
+        assert!(snapshot.midi.is_empty());
+        assert!(snapshot.console.is_empty());
+        assert_eq!(snapshot.report, None);
+    }
+
+    #[test]
+    fn keeps_recent_entries() {
+        let activity = Activity::new();
+        for line in 0..HISTORY + 4 {
+            activity.log_line(line.to_string());
+        }
+        let log = activity.snapshot().log;
+        assert_eq!(log.len(), HISTORY);
+        assert_eq!(log[0], "4");
+        assert_eq!(log[HISTORY - 1], (HISTORY + 3).to_string());
+    }
+}
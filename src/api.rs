@@ -0,0 +1,645 @@
+use crate::control::Control;
+use crate::http::{self, Request, Response};
+use crate::json::{self, Object};
+use crate::learn::Learn;
+use crate::metrics::{self, METRICS};
+use crate::midi::MidiMessageData;
+use crate::nscontroller::Button;
+use crate::profile::{Profile, PROFILE_EXTENSION};
+use crate::report::StickPosition;
+use crate::state::LatestState;
+use crate::stats::{LatencyStats, StatsSnapshot};
+use crate::watchdog;
+use log::info;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+const INVALID_NAME: &str = "Profile names may only use letters, digits, space, '-' and '_'";
+
+/// Mapping editor, a single page talking to the API below
+const EDITOR: &str = include_str!("../web/editor.html");
+
+/// How long an injected press is held unless duration_ms says otherwise
+const DEFAULT_PRESS: Duration = Duration::from_millis(100);
+
+/// Local status and control API
+///
+/// ```text
+/// GET  /                                   mapping editor
+/// GET  /api/status                         everything below in one object
+/// GET  /api/notes                          held MIDI notes
+/// GET  /api/controls                       latest value of every MIDI controller
+/// GET  /api/buttons                        buttons pressed by MIDI or the API
+/// GET  /api/sticks                         stick positions set by MIDI, null when untouched
+/// GET  /api/profile                        active and available profiles
+/// GET  /api/profiles/<name>                profile text
+/// PUT  /api/profiles/<name>                add or replace a profile, saved to --profile-dir
+/// GET  /api/connections                    MIDI port, controller and gadget
+/// GET  /api/clock                          MIDI clock transport, tempo and song position
+/// GET  /api/stats                          latency statistics
+/// POST /api/profile?name=<profile>         switch profile
+/// POST /api/release                        release all notes and injected buttons
+/// POST /api/press?button=A&duration_ms=100 press a button for testing
+/// GET  /api/learn                          learn session: current target, assignments, conflict
+/// POST /api/learn?name=<profile>           start learning a profile by pressing keys
+/// POST /api/learn/skip                     leave the current target unassigned
+/// POST /api/learn/finish                   add the learned profile, saved to --profile-dir
+/// POST /api/learn/cancel                   stop learning without a profile
+/// GET  /metrics                            Prometheus metrics
+/// ```
+pub struct Api {
+    pub midi_state: Arc<LatestState<Vec<MidiMessageData>>>,
+    pub control: Arc<Control>,
+    pub stats: Arc<LatencyStats>,
+    /// Where profiles from the editor are saved, they only live in memory without it
+    pub profile_dir: Option<PathBuf>,
+}
+
+impl Api {
+    pub fn handle(&self, request: &Request) -> Response {
+        if let Some(name) = request.path.strip_prefix("/api/profiles/") {
+            // The editor escapes names, spaces arrive as %20
+            let name = http::decode_path(name);
+            return match request.method.as_str() {
+                "GET" => match self.control.profile(&name) {
+                    Some(profile) => Response::text(200, profile.to_text()),
+                    None => error(404, "Unknown profile"),
+                },
+                "PUT" => self.save_profile(&name, &request.body),
+                _ => error(405, "Method not allowed"),
+            };
+        }
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/") => Response::html(EDITOR),
+            ("GET", "/api/status") => Response::json(200, self.status()),
+            ("GET", "/api/notes") => Response::json(200, self.notes()),
+            ("GET", "/api/controls") => Response::json(200, self.controls()),
+            ("GET", "/api/buttons") => Response::json(200, self.buttons()),
+            ("GET", "/api/sticks") => Response::json(200, self.sticks()),
+            ("GET", "/api/profile") => Response::json(200, self.profile()),
+            ("GET", "/api/connections") => Response::json(200, self.connections()),
+            ("GET", "/api/clock") => Response::json(200, self.clock()),
+            ("GET", "/api/stats") => Response::json(200, stats_json(&self.stats.snapshot())),
+            ("GET", "/metrics") => Response::metrics(self.metrics()),
+            ("POST", "/api/profile") => self.select_profile(request),
+            ("POST", "/api/release") => self.release(),
+            ("POST", "/api/press") => self.press(request),
+            ("GET", "/api/learn") => self.learning(),
+            ("POST", "/api/learn") => self.start_learning(request),
+            ("POST", "/api/learn/skip") => match self.control.skip_learning() {
+                Some(learn) => Response::json(200, learn_json(&learn)),
+                None => error(404, "Not learning"),
+            },
+            ("POST", "/api/learn/finish") => match self.control.finish_learning() {
+                Some(profile) => self.store_profile(profile),
+                None => error(404, "Not learning"),
+            },
+            ("POST", "/api/learn/cancel") => match self.control.cancel_learning() {
+                true => Response::json(200, self.profile()),
+                false => error(404, "Not learning"),
+            },
+            (
+                _,
+                "/" | "/api/status" | "/api/notes" | "/api/controls" | "/api/buttons" | "/api/sticks" | "/api/profile"
+                | "/api/connections" | "/api/clock" | "/api/stats" | "/api/release" | "/api/press" | "/metrics" | "/api/learn"
+                | "/api/learn/skip" | "/api/learn/finish" | "/api/learn/cancel",
+            ) => error(405, "Method not allowed"),
+            _ => error(404, "Not found"),
+        }
+    }
+
+    fn status(&self) -> String {
+        Object::new()
+            .raw("notes", self.notes())
+            .raw("controls", self.controls())
+            .raw("buttons", self.buttons())
+            .raw("sticks", self.sticks())
+            .raw("profile", self.profile())
+            .raw("connections", self.connections())
+            .raw("clock", self.clock())
+            .raw("stats", stats_json(&self.stats.snapshot()))
+            .build()
+    }
+
+    fn notes(&self) -> String {
+        let (held, _) = self.midi_state.get();
+        json::array(
+            held.iter()
+                .filter(|midi_data| midi_data.is_note())
+                .map(|midi_data| midi_data.data_byte1.to_string()),
+        )
+    }
+
+    fn controls(&self) -> String {
+        let (messages, _) = self.midi_state.get();
+        let mut object = Object::new();
+        for midi_data in messages.iter().filter(|midi_data| midi_data.is_control_change()) {
+            object = object.number(&midi_data.data_byte1.to_string(), midi_data.data_byte2);
+        }
+        object.build()
+    }
+
+    fn sticks(&self) -> String {
+        let (messages, _) = self.midi_state.get();
+        let overlay = self.control.overlay(&messages).unwrap_or_default();
+        let stick = |position: Option<StickPosition>| match position {
+            Some(position) => Object::new().number("x", position.x).number("y", position.y).build(),
+            None => String::from("null"),
+        };
+        Object::new()
+            .raw("left", stick(overlay.left_stick))
+            .raw("right", stick(overlay.right_stick))
+            .build()
+    }
+
+    fn buttons(&self) -> String {
+        let (held, _) = self.midi_state.get();
+        let pressed: Vec<String> = match self.control.overlay(&held).and_then(|overlay| overlay.buttons) {
+            Some(report) => Button::ALL
+                .iter()
+                .filter(|button| report.is_pressed(button))
+                .map(|button| json::string(button.name()))
+                .collect(),
+            None => Vec::new(),
+        };
+        json::array(pressed)
+    }
+
+    fn profile(&self) -> String {
+        Object::new()
+            .string("active", &self.control.active_profile().name)
+            .raw("available", json::array(self.control.profile_names().iter().map(|name| json::string(name))))
+            .number("octave", self.control.octave())
+            .build()
+    }
+
+    fn connections(&self) -> String {
+        let connections = self.control.connections();
+        Object::new()
+            .raw("midi", json::optional_string(&connections.midi))
+            .raw("controller", json::optional_string(&connections.controller))
+            .raw("gadget", json::optional_string(&connections.gadget))
+            .build()
+    }
+
+    fn clock(&self) -> String {
+        let clock = self.control.clock();
+        let (bar, beat) = clock.bar_beat();
+        let bpm = match clock.bpm {
+            Some(bpm) => format!("{:.1}", bpm),
+            None => String::from("null"),
+        };
+        Object::new()
+            .raw("playing", clock.playing.to_string())
+            .raw("bpm", bpm)
+            .number("position", clock.position)
+            .number("bar", bar)
+            .number("beat", beat)
+            .build()
+    }
+
+    /// Counters of the trace points plus gauges of the current state
+    fn metrics(&self) -> String {
+        let mut out = String::new();
+        METRICS.render(&mut out);
+
+        let (held, _) = self.midi_state.get();
+        let notes = held.iter().filter(|midi_data| midi_data.is_note()).count();
+        metrics::family(&mut out, "held_notes", "gauge", "MIDI notes currently held", &[(String::new(), notes.to_string())]);
+
+        let connections = self.control.connections();
+        let connected = |device: &str, value: &Option<String>| {
+            (format!("device=\"{}\"", device), if value.is_some() { "1" } else { "0" }.to_string())
+        };
+        metrics::family(
+            &mut out,
+            "connected",
+            "gauge",
+            "Whether the device is connected",
+            &[
+                connected("midi", &connections.midi),
+                connected("controller", &connections.controller),
+                connected("gadget", &connections.gadget),
+            ],
+        );
+
+        let snapshot = self.stats.snapshot();
+        metrics::family(
+            &mut out,
+            "dropped_events_total",
+            "counter",
+            "MIDI events superseded before a report carried them",
+            &[(String::new(), snapshot.dropped.to_string())],
+        );
+        metrics::family(
+            &mut out,
+            "report_rate",
+            "gauge",
+            "Input reports per second over the last second",
+            &[(String::new(), snapshot.report_rate.to_string())],
+        );
+        out
+    }
+
+    fn select_profile(&self, request: &Request) -> Response {
+        let name = match request.query.get("name") {
+            Some(name) => name,
+            None => return error(400, "Missing name"),
+        };
+        match self.control.select_profile(name) {
+            Ok(pedal_held) => {
+                // The new profile may not sustain, so notes only a pedal holds are released
+                if !pedal_held.is_empty() {
+                    self.midi_state.update(|held, _| Some(watchdog::release(held, &pedal_held)));
+                }
+                Response::json(200, self.profile())
+            }
+            Err(e) => error(400, &e.to_string()),
+        }
+    }
+
+    fn learning(&self) -> Response {
+        match self.control.learning() {
+            Some(learn) => Response::json(200, learn_json(&learn)),
+            None => error(404, "Not learning"),
+        }
+    }
+
+    fn start_learning(&self, request: &Request) -> Response {
+        let name = match request.query.get("name") {
+            Some(name) => name,
+            None => return error(400, "Missing name"),
+        };
+        if !valid_profile_name(name) {
+            return error(400, INVALID_NAME);
+        }
+        self.control.start_learning(name);
+        info!("Learning profile {}", name);
+        self.learning()
+    }
+
+    /// Parses the profile text in the body, the name comes from the path
+    fn save_profile(&self, name: &str, body: &[u8]) -> Response {
+        if !valid_profile_name(name) {
+            return error(400, INVALID_NAME);
+        }
+        let text = match std::str::from_utf8(body) {
+            Ok(text) => text,
+            Err(_) => return error(400, "Profile is not UTF-8"),
+        };
+        let mut profile = match Profile::parse(name, text) {
+            Ok(profile) => profile,
+            Err(e) => return error(400, &e.to_string()),
+        };
+        profile.name = name.to_string();
+        self.store_profile(profile)
+    }
+
+    /// Adds the profile and writes it to the profile directory when there is one
+    fn store_profile(&self, profile: Profile) -> Response {
+        let mut saved = false;
+        if let Some(profile_dir) = &self.profile_dir {
+            let path = profile_dir.join(format!("{}.{}", profile.name, PROFILE_EXTENSION));
+            if let Err(e) = fs::write(&path, profile.to_text()) {
+                return error(500, &format!("Unable to save {}: {}", path.display(), e));
+            }
+            info!("Saved profile {} to {}", profile.name, path.display());
+            saved = true;
+        }
+        self.control.save_profile(profile);
+        Response::json(
+            200,
+            Object::new()
+                .raw("profile", self.profile())
+                .raw("saved", saved.to_string())
+                .build(),
+        )
+    }
+
+    fn release(&self) -> Response {
+        self.control.release_injected();
+        let mut notes = Vec::new();
+        self.midi_state.update(|held, _| {
+            notes = held.iter().filter(|entry| entry.is_note()).map(|entry| entry.data_byte1).collect();
+            (!held.is_empty()).then(Vec::new)
+        });
+        // The receivers forget pedal-held notes and drop the notes waiting for the clock
+        self.control.release_all_notes(&notes);
+        Response::json(200, self.buttons())
+    }
+
+    fn press(&self, request: &Request) -> Response {
+        let button = match request.query.get("button").map(|name| Button::from_name(name)) {
+            Some(Some(button)) => button,
+            Some(None) => return error(400, "Unknown button"),
+            None => return error(400, "Missing button"),
+        };
+        let duration = match request.query.get("duration_ms").map(|value| value.parse::<u64>()) {
+            Some(Ok(milliseconds)) => Duration::from_millis(milliseconds),
+            Some(Err(_)) => return error(400, "Invalid duration_ms"),
+            None => DEFAULT_PRESS,
+        };
+        self.control.inject(button, duration);
+        Response::json(200, self.buttons())
+    }
+}
+
+/// Profile names end up as file names in the profile directory
+fn valid_profile_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ' ')
+}
+
+fn learn_json(learn: &Learn) -> String {
+    let (step, steps) = learn.progress();
+    let mut assigned = Object::new();
+    for (source, target) in learn.assigned() {
+        assigned = assigned.string(&source.to_string(), &target.name());
+    }
+    let conflict = match learn.conflict() {
+        Some((source, target)) => Object::new()
+            .string("source", &source.to_string())
+            .string("target", &target.name())
+            .build(),
+        None => String::from("null"),
+    };
+    Object::new()
+        .string("name", learn.name())
+        .number("step", step)
+        .number("steps", steps)
+        .raw("target", json::optional_string(&learn.current().map(|target| target.name())))
+        .raw("finished", learn.is_finished().to_string())
+        .raw("assigned", assigned.build())
+        .raw("conflict", conflict)
+        .build()
+}
+
+/// JSON error body `{"error": message}`
+pub fn error(status: u16, message: &str) -> Response {
+    Response::json(status, Object::new().string("error", message).build())
+}
+
+fn milliseconds(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) => format!("{:.3}", latency.as_secs_f64() * 1000.0),
+        None => String::from("null"),
+    }
+}
+
+fn stats_json(snapshot: &StatsSnapshot) -> String {
+    Object::new()
+        .number("events", snapshot.events)
+        .number("dropped", snapshot.dropped)
+        .raw("min_ms", milliseconds(snapshot.min))
+        .raw("mean_ms", milliseconds(snapshot.mean))
+        .raw("p99_ms", milliseconds(snapshot.p99))
+        .raw("max_ms", milliseconds(snapshot.max))
+        .number("reports", snapshot.reports)
+        .number("report_rate", format!("{:.1}", snapshot.report_rate))
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{control, midi_state, note_on, receiver};
+    use std::collections::HashMap;
+
+    fn api() -> Api {
+        let kart = Profile::parse("kart", "note C = A").unwrap();
+        Api {
+            midi_state: midi_state(Vec::new()),
+            control: Arc::new(Control::new(vec![Profile::default(), kart])),
+            stats: Arc::new(LatencyStats::new()),
+            profile_dir: None,
+        }
+    }
+
+    fn request(method: &str, path: &str, query: &[(&str, &str)]) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<HashMap<String, String>>(),
+            body: Vec::new(),
+        }
+    }
+
+    fn put(path: &str, body: &str) -> Request {
+        Request {
+            body: body.as_bytes().to_vec(),
+            ..request("PUT", path, &[])
+        }
+    }
+
+    fn body(response: &Response) -> String {
+        String::from_utf8(response.body.clone()).unwrap()
+    }
+
+    fn hold_middle_c(api: &Api) {
+        api.midi_state.update(|_, _| Some(vec![note_on(60, 0x40)]));
+    }
+
+    #[test]
+    fn reports_notes_and_buttons() {
+        let api = api();
+        hold_middle_c(&api);
+        assert_eq!(body(&api.handle(&request("GET", "/api/notes", &[]))), "[60]");
+        assert_eq!(body(&api.handle(&request("GET", "/api/buttons", &[]))), r#"["Y"]"#);
+    }
+
+    #[test]
+    fn switches_profile() {
+        let api = api();
+        hold_middle_c(&api);
+        let response = api.handle(&request("POST", "/api/profile", &[("name", "kart")]));
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response), r#"{"active":"kart","available":["default","kart"],"octave":0}"#);
+        assert_eq!(body(&api.handle(&request("GET", "/api/buttons", &[]))), r#"["A"]"#);
+
+        assert_eq!(api.handle(&request("POST", "/api/profile", &[("name", "piano")])).status, 400);
+        assert_eq!(api.handle(&request("POST", "/api/profile", &[])).status, 400);
+    }
+
+    #[test]
+    fn switching_profile_releases_pedal_held_notes() {
+        let piano = Profile::parse("piano", "sustain = on").unwrap();
+        let api = Api {
+            control: Arc::new(Control::new(vec![piano, Profile::parse("kart", "note C = A").unwrap()])),
+            ..api()
+        };
+        let mut receiver = receiver(&api.midi_state, &api.control);
+        receiver.receive(&[0x90, 60, 0x40, 0x90, 62, 0x40, 0xB0, 64, 127, 0x90, 60, 0]);
+        assert_eq!(body(&api.handle(&request("GET", "/api/notes", &[]))), "[60,62]");
+
+        api.handle(&request("POST", "/api/profile", &[("name", "kart")]));
+        assert_eq!(body(&api.handle(&request("GET", "/api/notes", &[]))), "[62]");
+        // Struck again after the switch the note is not held by the pedal any more
+        receiver.receive(&[0x90, 60, 0x40, 0x90, 60, 0, 0xB0, 64, 0]);
+        assert_eq!(body(&api.handle(&request("GET", "/api/notes", &[]))), "[62]");
+    }
+
+    #[test]
+    fn presses_and_releases() {
+        let api = api();
+        let response = api.handle(&request("POST", "/api/press", &[("button", "home"), ("duration_ms", "60000")]));
+        assert_eq!(body(&response), r#"["Home"]"#);
+        assert_eq!(api.handle(&request("POST", "/api/press", &[("button", "Start")])).status, 400);
+        assert_eq!(api.handle(&request("POST", "/api/press", &[("button", "A"), ("duration_ms", "x")])).status, 400);
+
+        hold_middle_c(&api);
+        let response = api.handle(&request("POST", "/api/release", &[]));
+        assert_eq!(body(&response), "[]");
+        assert_eq!(api.midi_state.get(), (Vec::new(), 2));
+    }
+
+    #[test]
+    fn release_drops_quantized_and_pedal_held_notes() {
+        let piano = Profile::parse("piano", "quantize = 1/16\nsustain = on").unwrap();
+        let api = Api {
+            control: Arc::new(Control::new(vec![piano])),
+            ..api()
+        };
+        let mut receiver = receiver(&api.midi_state, &api.control);
+        // Held by the damper pedal, then a press waiting for the next sixteenth
+        receiver.receive(&[0x90, 60, 0x40, 0xB0, 64, 127, 0x80, 60, 0, 0xFA, 0xF8, 0x90, 62, 0x40]);
+        assert_eq!(body(&api.handle(&request("GET", "/api/notes", &[]))), "[60]");
+
+        api.handle(&request("POST", "/api/release", &[]));
+        assert_eq!(body(&api.handle(&request("GET", "/api/notes", &[]))), "[]");
+        // Neither comes back on the grid nor when the pedal goes up
+        receiver.receive(&[0xF8, 0xF8, 0xF8, 0xF8, 0xF8, 0xF8, 0xB0, 64, 0]);
+        assert_eq!(body(&api.handle(&request("GET", "/api/notes", &[]))), "[]");
+    }
+
+    #[test]
+    fn reports_connections_and_status() {
+        let api = api();
+        api.control.set_midi(Some(String::from("Piano \"88\"")));
+        assert_eq!(
+            body(&api.handle(&request("GET", "/api/connections", &[]))),
+            r#"{"midi":"Piano \"88\"","controller":null,"gadget":null}"#
+        );
+        let status = body(&api.handle(&request("GET", "/api/status", &[])));
+        assert!(status.starts_with(r#"{"notes":[],"controls":{},"buttons":[],"sticks":{"left":null,"right":null},"profile":{"active":"default""#));
+        assert!(status.contains(r#""stats":{"events":0,"dropped":0,"min_ms":null"#));
+        assert!(status.contains(r#""clock":{"playing":false,"bpm":null,"position":0,"bar":1,"beat":1}"#));
+    }
+
+    #[test]
+    fn exports_metrics() {
+        let api = api();
+        hold_middle_c(&api);
+        api.control.set_controller(Some(String::from("/dev/hidraw0")));
+        let response = api.handle(&request("GET", "/metrics", &[]));
+        assert_eq!(response.content_type, "text/plain; version=0.0.4; charset=utf-8");
+        let text = body(&response);
+        assert!(text.contains("# TYPE midi_to_switch_midi_messages_total counter\n"));
+        assert!(text.contains("midi_to_switch_held_notes 1\n"));
+        assert!(text.contains("midi_to_switch_connected{device=\"controller\"} 1\n"));
+        assert!(text.contains("midi_to_switch_connected{device=\"midi\"} 0\n"));
+        assert!(text.contains("midi_to_switch_dropped_events_total 0\n"));
+    }
+
+    #[test]
+    fn reports_controls_and_sticks() {
+        let api = api();
+        api.handle(&put("/api/profiles/wheel", "cc 1 = LeftStickX"));
+        api.handle(&request("POST", "/api/profile", &[("name", "wheel")]));
+        api.midi_state.update(|_, _| Some(vec![control(1, 0)]));
+        assert_eq!(body(&api.handle(&request("GET", "/api/notes", &[]))), "[]");
+        assert_eq!(body(&api.handle(&request("GET", "/api/controls", &[]))), r#"{"1":0}"#);
+        assert_eq!(
+            body(&api.handle(&request("GET", "/api/sticks", &[]))),
+            r#"{"left":{"x":0,"y":2048},"right":null}"#
+        );
+    }
+
+    #[test]
+    fn edits_profiles() {
+        let api = api();
+        let response = api.handle(&request("GET", "/api/profiles/kart", &[]));
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response), "name = kart\nnote C = A\n");
+        assert_eq!(api.handle(&request("GET", "/api/profiles/piano", &[])).status, 404);
+
+        // The name in the path wins over the one in the text
+        let response = api.handle(&put("/api/profiles/kart", "name = other\nnote D = B\n"));
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response), r#"{"profile":{"active":"default","available":["default","kart"],"octave":0},"saved":false}"#);
+        assert_eq!(body(&api.handle(&request("GET", "/api/profiles/kart", &[]))), "name = kart\nnote D = B\n");
+
+        api.handle(&put("/api/profiles/piano", "note C = X"));
+        assert_eq!(api.control.profile_names(), vec!["default", "kart", "piano"]);
+
+        assert_eq!(api.handle(&put("/api/profiles/piano", "note C = Start")).status, 400);
+        assert_eq!(api.handle(&put("/api/profiles/..%2Fetc", "note C = X")).status, 400);
+        // Names with spaces come escaped from the editor
+        assert_eq!(api.handle(&put("/api/profiles/My%20Kart", "note C = X")).status, 200);
+        assert_eq!(body(&api.handle(&request("GET", "/api/profiles/My%20Kart", &[]))), "name = My Kart\nnote C = X\n");
+        assert_eq!(api.handle(&put("/api/profiles/", "note C = X")).status, 400);
+    }
+
+    #[test]
+    fn saves_profiles_to_directory() {
+        let dir = std::env::temp_dir().join(format!("midi_to_switch_profiles_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let api = Api {
+            profile_dir: Some(dir.clone()),
+            ..api()
+        };
+        let response = api.handle(&put("/api/profiles/kart", "note C = A # accelerate"));
+        assert!(body(&response).ends_with(r#""saved":true}"#));
+        assert_eq!(fs::read_to_string(dir.join("kart.profile")).unwrap(), "name = kart\nnote C = A\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn learns_profile() {
+        let api = api();
+        assert_eq!(api.handle(&request("GET", "/api/learn", &[])).status, 404);
+        assert_eq!(api.handle(&request("POST", "/api/learn", &[("name", "../kart")])).status, 400);
+        let response = api.handle(&request("POST", "/api/learn", &[("name", "piano")]));
+        assert_eq!(
+            body(&response),
+            r#"{"name":"piano","step":1,"steps":26,"target":"Y","finished":false,"assigned":{},"conflict":null}"#
+        );
+
+        api.control.learn(&note_on(60, 100));
+        api.control.learn(&note_on(60, 100));
+        api.handle(&request("POST", "/api/learn/skip", &[]));
+        let response = api.handle(&request("GET", "/api/learn", &[]));
+        assert_eq!(
+            body(&response),
+            r#"{"name":"piano","step":3,"steps":26,"target":"B","finished":false,"assigned":{"note 60":"Y"},"conflict":null}"#
+        );
+
+        let response = api.handle(&request("POST", "/api/learn/finish", &[]));
+        assert_eq!(body(&response), r#"{"profile":{"active":"default","available":["default","kart","piano"],"octave":0},"saved":false}"#);
+        assert_eq!(body(&api.handle(&request("GET", "/api/profiles/piano", &[]))), "name = piano\nnote 60 = Y\n");
+        assert_eq!(api.handle(&request("POST", "/api/learn/finish", &[])).status, 404);
+        assert_eq!(api.handle(&request("POST", "/api/learn/cancel", &[])).status, 404);
+    }
+
+    #[test]
+    fn serves_editor() {
+        let response = api().handle(&request("GET", "/", &[]));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_type, "text/html; charset=utf-8");
+        assert!(body(&response).contains("/api/profiles/"));
+    }
+
+    #[test]
+    fn rejects_unknown_routes() {
+        let api = api();
+        assert_eq!(api.handle(&request("GET", "/index.html", &[])).status, 404);
+        assert_eq!(api.handle(&request("DELETE", "/api/status", &[])).status, 405);
+        assert_eq!(api.handle(&request("GET", "/api/release", &[])).status, 405);
+    }
+}
@@ -0,0 +1,154 @@
+use log::error;
+use std::fs::File;
+use std::io::{Error, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// LINKTYPE_USER0, packets are the raw HID reports / MIDI messages without any header
+pub const LINKTYPE_USER0: u16 = 147;
+
+/// Interfaces written to the section header, in this order
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Interface {
+    /// USB gadget, the console side
+    Gadget = 0,
+    /// hidraw, the physical controller side
+    Controller = 1,
+    /// Raw MIDI input
+    Midi = 2,
+}
+
+impl Interface {
+    pub const ALL: [Interface; 3] = [Interface::Gadget, Interface::Controller, Interface::Midi];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interface::Gadget => "hidg0",
+            // The hidraw node is discovered and may change when the controller is plugged again
+            Interface::Controller => "controller",
+            Interface::Midi => "midi",
+        }
+    }
+}
+
+/// Direction as seen from midi_to_switch, stored in the epb_flags option
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Direction {
+    /// Read from the device
+    Inbound = 1,
+    /// Written to the device
+    Outbound = 2,
+}
+
+/// pcapng writer shared between the gadget, controller and MIDI threads
+///
+/// Every packet is written and flushed as a complete block, so a capture stays
+/// readable in Wireshark even if the service is killed.
+pub struct Capture {
+    fp: Mutex<File>,
+}
+
+impl Capture {
+    pub fn new(path: &str) -> Result<Capture, Error> {
+        let mut fp = File::create(path)?;
+
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is not known up front
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        push_option(&mut shb, OPT_END, &[]);
+        fp.write_all(&block(BLOCK_SECTION_HEADER, &shb))?;
+
+        for interface in Interface::ALL.iter() {
+            let mut idb = Vec::new();
+            idb.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+            idb.extend_from_slice(&0u16.to_le_bytes());
+            // No snap length limit
+            idb.extend_from_slice(&0u32.to_le_bytes());
+            push_option(&mut idb, OPT_IF_NAME, interface.name().as_bytes());
+            push_option(&mut idb, OPT_END, &[]);
+            fp.write_all(&block(BLOCK_INTERFACE_DESCRIPTION, &idb))?;
+        }
+        fp.flush()?;
+
+        Ok(Capture { fp: Mutex::new(fp) })
+    }
+
+    /// Appends one packet with the current time, `comment` shows up in Wireshark's packet comments
+    pub fn record(&self, interface: Interface, direction: Direction, data: &[u8], comment: Option<&str>) -> Result<(), Error> {
+        // Default timestamp resolution is microseconds
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        let mut epb = Vec::new();
+        epb.extend_from_slice(&(interface as u32).to_le_bytes());
+        epb.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(data);
+        pad(&mut epb);
+        if let Some(comment) = comment {
+            push_option(&mut epb, OPT_COMMENT, comment.as_bytes());
+        }
+        push_option(&mut epb, OPT_EPB_FLAGS, &(direction as u32).to_le_bytes());
+        push_option(&mut epb, OPT_END, &[]);
+
+        let mut fp = match self.fp.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        fp.write_all(&block(BLOCK_ENHANCED_PACKET, &epb))?;
+        fp.flush()
+    }
+}
+
+/// Records a packet when capturing is enabled
+///
+/// Failures are only logged, a full disk must not stop the relay
+pub fn record(capture: &Option<Arc<Capture>>, interface: Interface, direction: Direction, data: &[u8], comment: Option<&str>) {
+    if let Some(capture) = capture {
+        if let Err(error) = capture.record(interface, direction, data, comment) {
+            error!("Unable to capture packet: {}", error);
+        }
+    }
+}
+
+/// Wraps a block body with its type and the leading and trailing total length
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let total_length = (body.len() + 12) as u32;
+    let mut data = Vec::with_capacity(total_length as usize);
+    data.extend_from_slice(&block_type.to_le_bytes());
+    data.extend_from_slice(&total_length.to_le_bytes());
+    data.extend_from_slice(body);
+    data.extend_from_slice(&total_length.to_le_bytes());
+    data
+}
+
+fn push_option(data: &mut Vec<u8>, code: u16, value: &[u8]) {
+    data.extend_from_slice(&code.to_le_bytes());
+    data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    data.extend_from_slice(value);
+    pad(data);
+}
+
+/// Blocks and options are aligned to 32 bits
+fn pad(data: &mut Vec<u8>) {
+    while !data.len().is_multiple_of(4) {
+        data.push(0);
+    }
+}
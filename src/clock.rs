@@ -0,0 +1,278 @@
+use crate::midi::MidiMessageData;
+use crate::midi_parser::MidiMessage;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// MIDI clock pulses per quarter note
+pub const PULSES_PER_QUARTER: u64 = 24;
+
+/// Pulses of a whole note, quantization grids divide it
+pub const PULSES_PER_WHOLE: u32 = 96;
+
+/// Song Position counts MIDI beats, sixteenth notes of 6 pulses
+const PULSES_PER_SONG_BEAT: u64 = 6;
+
+/// Pulse intervals averaged for the tempo, one quarter note
+const TEMPO_WINDOW: usize = 24;
+
+/// Without a pulse for this long the clock counts as gone, slower than 2.5 BPM
+const CLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Transport and tempo of a clock master such as a sequencer or drum machine
+///
+/// Follows Timing Clock (F8), Start (FA), Continue (FB), Stop (FC) and Song Position (F2),
+/// System Reset (FF) stops it.
+/// Pulses count while playing, the tempo is measured whenever pulses arrive.
+#[derive(Debug, Default, Clone)]
+pub struct MidiClock {
+    playing: bool,
+    /// Position of the next pulse, in pulses since the start of the song
+    next_pulse: u64,
+    last_pulse: Option<Instant>,
+    intervals: VecDeque<Duration>,
+}
+
+/// What a message did to the transport
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ClockEvent {
+    /// Pulse while playing, with its position since the start of the song
+    Pulse(u64),
+    /// Start or Continue
+    Playing,
+    Stopped,
+}
+
+/// Clock state for the API and the dashboard
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ClockSnapshot {
+    pub playing: bool,
+    /// Pulses since the start of the song
+    pub position: u64,
+    /// None without a running clock
+    pub bpm: Option<f64>,
+}
+
+impl ClockSnapshot {
+    /// Bar and beat in 4/4, both counted from 1
+    pub fn bar_beat(&self) -> (u64, u64) {
+        let beats = self.position / PULSES_PER_QUARTER;
+        (beats / 4 + 1, beats % 4 + 1)
+    }
+}
+
+impl MidiClock {
+    pub fn new() -> MidiClock {
+        MidiClock::default()
+    }
+
+    /// Applies a message that arrived `at`, None for messages that are not about the clock
+    pub fn handle(&mut self, message: &MidiMessage, at: Instant) -> Option<ClockEvent> {
+        match message {
+            MidiMessage::TimingClock => {
+                if let Some(last) = self.last_pulse {
+                    let interval = at.saturating_duration_since(last);
+                    if interval > CLOCK_TIMEOUT {
+                        self.intervals.clear();
+                    } else {
+                        if self.intervals.len() == TEMPO_WINDOW {
+                            self.intervals.pop_front();
+                        }
+                        self.intervals.push_back(interval);
+                    }
+                }
+                self.last_pulse = Some(at);
+                if !self.playing {
+                    return None;
+                }
+                let position = self.next_pulse;
+                self.next_pulse += 1;
+                Some(ClockEvent::Pulse(position))
+            }
+            // The first pulse after Start is the first beat of the song
+            MidiMessage::Start => {
+                self.next_pulse = 0;
+                self.playing = true;
+                Some(ClockEvent::Playing)
+            }
+            MidiMessage::Continue => {
+                self.playing = true;
+                Some(ClockEvent::Playing)
+            }
+            MidiMessage::Stop | MidiMessage::Reset => {
+                self.playing = false;
+                Some(ClockEvent::Stopped)
+            }
+            MidiMessage::SongPosition(beats) => {
+                self.next_pulse = *beats as u64 * PULSES_PER_SONG_BEAT;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn snapshot(&self, now: Instant) -> ClockSnapshot {
+        let running = self
+            .last_pulse
+            .is_some_and(|last| now.saturating_duration_since(last) <= CLOCK_TIMEOUT);
+        let bpm = match self.intervals.len() {
+            0 => None,
+            _ if !running => None,
+            count => {
+                let pulse = self.intervals.iter().sum::<Duration>().as_secs_f64() / count as f64;
+                Some(60.0 / (pulse * PULSES_PER_QUARTER as f64))
+            }
+        };
+        ClockSnapshot {
+            playing: self.playing,
+            position: self.next_pulse,
+            bpm,
+        }
+    }
+}
+
+/// Holds notes back until the next step of a grid of clock pulses
+///
+/// Presses and releases wait in arrival order, controllers pass at once.
+/// A release never leaves in the same step as its press, so even a short note
+/// is held for one step.
+#[derive(Debug, Default)]
+pub struct Quantizer {
+    pending: Vec<MidiMessageData>,
+}
+
+impl Quantizer {
+    /// Entry of an arriving message, `grid` is the step in pulses while quantizing
+    pub fn push(&mut self, midi_data: MidiMessageData, grid: Option<u32>, out: &mut Vec<MidiMessageData>) {
+        if midi_data.releases_all_notes() {
+            self.pending.clear();
+        }
+        let note = midi_data.is_note() || midi_data.should_remove_midi_message();
+        match grid {
+            Some(_) if note => self.pending.push(midi_data),
+            _ => {
+                if grid.is_none() {
+                    out.append(&mut self.pending);
+                }
+                out.push(midi_data);
+            }
+        }
+    }
+
+    /// Releases waiting notes on a pulse of the grid, and all of them once quantizing stops
+    pub fn clock(&mut self, event: ClockEvent, grid: Option<u32>, out: &mut Vec<MidiMessageData>) {
+        match (event, grid) {
+            (ClockEvent::Pulse(position), Some(grid)) if position.is_multiple_of(grid as u64) => self.step(out),
+            (ClockEvent::Pulse(_), Some(_)) | (ClockEvent::Playing, _) => {}
+            _ => out.append(&mut self.pending),
+        }
+    }
+
+    fn step(&mut self, out: &mut Vec<MidiMessageData>) {
+        let mut pressed = Vec::new();
+        let mut waiting: Vec<MidiMessageData> = Vec::new();
+        for midi_data in self.pending.drain(..) {
+            let note = midi_data.data_byte1;
+            let released_early = midi_data.should_remove_midi_message() && pressed.contains(&note);
+            if released_early || waiting.iter().any(|entry| entry.data_byte1 == note) {
+                waiting.push(midi_data);
+                continue;
+            }
+            if midi_data.should_add_midi_message() {
+                pressed.push(note);
+            }
+            out.push(midi_data);
+        }
+        self.pending = waiting;
+    }
+
+    /// Drops waiting notes, for inputs that went away
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{control, note_on};
+
+    #[test]
+    fn follows_transport_and_song_position() {
+        let mut clock = MidiClock::new();
+        let start = Instant::now();
+        assert_eq!(clock.handle(&MidiMessage::TimingClock, start), None);
+        assert_eq!(clock.handle(&MidiMessage::Start, start), Some(ClockEvent::Playing));
+        assert_eq!(clock.handle(&MidiMessage::TimingClock, start), Some(ClockEvent::Pulse(0)));
+        assert_eq!(clock.handle(&MidiMessage::TimingClock, start), Some(ClockEvent::Pulse(1)));
+        assert_eq!(clock.handle(&MidiMessage::Stop, start), Some(ClockEvent::Stopped));
+        assert_eq!(clock.handle(&MidiMessage::TimingClock, start), None);
+
+        // Song position 16 is the second bar
+        assert_eq!(clock.handle(&MidiMessage::SongPosition(16), start), None);
+        assert_eq!(clock.handle(&MidiMessage::Continue, start), Some(ClockEvent::Playing));
+        assert_eq!(clock.handle(&MidiMessage::TimingClock, start), Some(ClockEvent::Pulse(96)));
+        let snapshot = clock.snapshot(start);
+        assert!(snapshot.playing);
+        assert_eq!(snapshot.position, 97);
+        assert_eq!(snapshot.bar_beat(), (2, 1));
+        assert_eq!(clock.handle(&MidiMessage::NoteOn { channel: 0, note: 60, velocity: 1 }, start), None);
+    }
+
+    #[test]
+    fn measures_tempo_from_pulses() {
+        let mut clock = MidiClock::new();
+        let start = Instant::now();
+        assert_eq!(clock.snapshot(start).bpm, None);
+        // 120 BPM is a pulse every 20.83 ms
+        let pulse = Duration::from_secs_f64(0.5 / 24.0);
+        for i in 0..30 {
+            clock.handle(&MidiMessage::TimingClock, start + pulse * i);
+        }
+        let bpm = clock.snapshot(start + pulse * 30).bpm.unwrap();
+        assert!((bpm - 120.0).abs() < 0.01, "{}", bpm);
+
+        // A stopped clock has no tempo and starts over
+        assert_eq!(clock.snapshot(start + Duration::from_secs(5)).bpm, None);
+        clock.handle(&MidiMessage::TimingClock, start + Duration::from_secs(5));
+        clock.handle(&MidiMessage::TimingClock, start + Duration::from_secs(5) + pulse * 2);
+        let bpm = clock.snapshot(start + Duration::from_secs(5)).bpm.unwrap();
+        assert!((bpm - 60.0).abs() < 0.01, "{}", bpm);
+    }
+
+    #[test]
+    fn quantizer_releases_notes_on_the_grid() {
+        let mut quantizer = Quantizer::default();
+        let mut out = Vec::new();
+        quantizer.push(note_on(60, 100), Some(6), &mut out);
+        quantizer.push(control(1, 10), Some(6), &mut out);
+        assert_eq!(out, vec![control(1, 10)]);
+
+        out.clear();
+        quantizer.clock(ClockEvent::Pulse(5), Some(6), &mut out);
+        assert!(out.is_empty());
+        // The short note is held for one step
+        quantizer.push(note_on(60, 0), Some(6), &mut out);
+        quantizer.push(note_on(62, 100), Some(6), &mut out);
+        quantizer.clock(ClockEvent::Pulse(6), Some(6), &mut out);
+        assert_eq!(out, vec![note_on(60, 100), note_on(62, 100)]);
+
+        out.clear();
+        quantizer.clock(ClockEvent::Pulse(12), Some(6), &mut out);
+        assert_eq!(out, vec![note_on(60, 0)]);
+
+        // Without a grid everything passes, waiting notes first
+        out.clear();
+        quantizer.push(note_on(64, 100), Some(6), &mut out);
+        quantizer.push(note_on(65, 100), None, &mut out);
+        assert_eq!(out, vec![note_on(64, 100), note_on(65, 100)]);
+
+        out.clear();
+        quantizer.push(note_on(67, 100), Some(6), &mut out);
+        quantizer.clock(ClockEvent::Stopped, None, &mut out);
+        assert_eq!(out, vec![note_on(67, 100)]);
+    }
+}
@@ -0,0 +1,353 @@
+use crate::rtp_midi::DEFAULT_PORT;
+use crate::timing::FrameTiming;
+use crate::watchdog::SENSING_TIMEOUT;
+use log::LevelFilter;
+use std::error::Error;
+use std::time::Duration;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Backend {
+    /// Relay between the Switch (USB gadget) and the physical controller
+    Gadget,
+    /// Local virtual gamepad, no Switch or controller required
+    Uinput,
+}
+
+/// Where MIDI bytes are read from
+#[derive(Debug, PartialEq, Clone)]
+pub enum MidiSource {
+    /// ALSA sequencer port through midir
+    Alsa,
+    /// Serial TTY wired to a DIN socket, set to 31250 baud
+    Serial(String),
+    /// ALSA rawmidi device file such as /dev/snd/midiC1D0
+    RawMidi(String),
+    /// ALSA UMP device file such as /dev/snd/umpC1D0, MIDI 2.0 at full resolution
+    Ump(String),
+    /// RTP-MIDI session participant on this control port and the next one
+    RtpMidi(u16),
+}
+
+/// Printed with a command line error
+pub const USAGE: &str = "Usage: midi_to_switch [options]
+  --backend gadget|uinput          relay to a Switch or drive a local virtual gamepad
+  --midi-input <input>             alsa, serial:<path>, rawmidi:<path>, ump:<path> or rtpmidi[:<port>]
+  --controller <path>              hidraw node of the controller, discovered when not set
+  --log-level <level>              off, error, warn, info, debug or trace
+  --capture <file>                 write relayed packets to a pcapng file
+  --stats-interval <seconds>       log latency statistics, 0 disables it
+  --http <[address:]port>          serve the HTTP API
+  --osc <[address:]port>           listen for OSC messages
+  --profile <file>                 load a mapping profile, may be repeated
+  --profile-dir <dir>              load and save profiles in a directory
+  --learn <file>                   write a profile in a learn session instead of relaying
+  --tui                            show the terminal dashboard
+  --min-press <reports>            hold every press for at least that many reports
+  --min-gap <reports>              keep a button released that many reports before a press
+  --max-hold <seconds>             release notes held longer, 0 disables it
+  --sensing-timeout <ms>           release notes when Active Sensing stops, 0 ignores it";
+
+/// Runtime options taken from the command line
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub backend: Backend,
+    pub midi_source: MidiSource,
+    pub log_level: LevelFilter,
+    /// hidraw node of the physical controller, discovered through sysfs when not set
+    pub controller_path: Option<String>,
+    /// pcapng file receiving every relayed packet
+    pub capture_path: Option<String>,
+    /// How often latency statistics are logged, None disables periodic logging
+    pub stats_interval: Option<Duration>,
+    /// Address of the HTTP API, disabled when not set
+    pub http_address: Option<String>,
+    /// UDP address receiving OSC messages, disabled when not set
+    pub osc_address: Option<String>,
+    /// Mapping profiles, the first one is active at start
+    pub profile_paths: Vec<String>,
+    /// Directory of `*.profile` files, loaded after `profile_paths` and receiving profiles saved in the editor
+    pub profile_dir: Option<String>,
+    /// Runs a learn session on the terminal writing this profile instead of relaying
+    pub learn_path: Option<String>,
+    /// Shows the terminal dashboard instead of log lines
+    pub tui: bool,
+    /// Minimum press and gap in input reports, presses follow the report rate as they come without it
+    pub frame_timing: Option<FrameTiming>,
+    /// Notes held longer are released by the watchdog, None keeps them until their Note Off
+    pub max_hold: Option<Duration>,
+    /// Silence after Active Sensing that releases all notes, None ignores Active Sensing
+    pub sensing_timeout: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            backend: Backend::Gadget,
+            midi_source: MidiSource::Alsa,
+            log_level: LevelFilter::Info,
+            controller_path: None,
+            capture_path: None,
+            stats_interval: Some(Duration::from_secs(60)),
+            http_address: None,
+            osc_address: None,
+            profile_paths: Vec::new(),
+            profile_dir: None,
+            learn_path: None,
+            tui: false,
+            frame_timing: None,
+            max_hold: None,
+            sensing_timeout: Some(SENSING_TIMEOUT),
+        }
+    }
+}
+
+impl Config {
+    /// Parses the arguments following the program name
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, Box<dyn Error>> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--backend" => {
+                    config.backend = match next_value(&mut args, &arg)?.as_str() {
+                        "gadget" => Backend::Gadget,
+                        "uinput" => Backend::Uinput,
+                        other => return Err(format!("Unknown backend {:?}, expected gadget or uinput", other).into()),
+                    }
+                }
+                "--midi-input" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.midi_source = match value.split_once(':') {
+                        _ if value == "alsa" => Some(MidiSource::Alsa),
+                        _ if value == "rtpmidi" => Some(MidiSource::RtpMidi(DEFAULT_PORT)),
+                        Some(("serial", path)) if !path.is_empty() => Some(MidiSource::Serial(path.to_string())),
+                        Some(("rawmidi", path)) if !path.is_empty() => Some(MidiSource::RawMidi(path.to_string())),
+                        Some(("ump", path)) if !path.is_empty() => Some(MidiSource::Ump(path.to_string())),
+                        Some(("rtpmidi", port)) => port.parse().ok().map(MidiSource::RtpMidi),
+                        _ => None,
+                    }
+                    .ok_or_else(|| {
+                        format!(
+                            "Unknown MIDI input {:?}, expected alsa, serial:<path>, rawmidi:<path>, ump:<path> or rtpmidi[:<port>]",
+                            value
+                        )
+                    })?;
+                }
+                "--log-level" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.log_level = value
+                        .parse()
+                        .map_err(|_| format!("Unknown log level {:?}", value))?;
+                }
+                "--controller" => config.controller_path = Some(next_value(&mut args, &arg)?),
+                "--capture" => config.capture_path = Some(next_value(&mut args, &arg)?),
+                "--stats-interval" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let seconds: u64 = value
+                        .parse()
+                        .map_err(|_| format!("Invalid stats interval {:?}, expected seconds", value))?;
+                    config.stats_interval = if seconds == 0 { None } else { Some(Duration::from_secs(seconds)) };
+                }
+                "--http" => config.http_address = Some(listen_address(next_value(&mut args, &arg)?)),
+                "--osc" => config.osc_address = Some(listen_address(next_value(&mut args, &arg)?)),
+                "--profile" => config.profile_paths.push(next_value(&mut args, &arg)?),
+                "--profile-dir" => config.profile_dir = Some(next_value(&mut args, &arg)?),
+                "--learn" => config.learn_path = Some(next_value(&mut args, &arg)?),
+                "--tui" => config.tui = true,
+                "--min-press" => {
+                    let reports = reports(next_value(&mut args, &arg)?)?;
+                    config.frame_timing.get_or_insert_with(FrameTiming::default).min_press = reports;
+                }
+                "--min-gap" => {
+                    let reports = reports(next_value(&mut args, &arg)?)?;
+                    config.frame_timing.get_or_insert_with(FrameTiming::default).min_gap = reports;
+                }
+                "--max-hold" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let seconds: u64 = value
+                        .parse()
+                        .map_err(|_| format!("Invalid maximum hold {:?}, expected seconds", value))?;
+                    config.max_hold = if seconds == 0 { None } else { Some(Duration::from_secs(seconds)) };
+                }
+                "--sensing-timeout" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let milliseconds: u64 = value
+                        .parse()
+                        .map_err(|_| format!("Invalid sensing timeout {:?}, expected milliseconds", value))?;
+                    config.sensing_timeout = if milliseconds == 0 { None } else { Some(Duration::from_millis(milliseconds)) };
+                }
+                _ => return Err(format!("Unknown argument {:?}", arg).into()),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// A bare port only listens on localhost
+fn listen_address(value: String) -> String {
+    match value.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => value,
+    }
+}
+
+/// Number of input reports, a press or a gap lasts at least one
+fn reports(value: String) -> Result<u32, Box<dyn Error>> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|reports| *reports >= 1)
+        .ok_or_else(|| format!("Invalid report count {:?}, expected a number from 1", value).into())
+}
+
+fn next_value<I: Iterator<Item = String>>(args: &mut I, name: &str) -> Result<String, Box<dyn Error>> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {}", name).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, Box<dyn Error>> {
+        Config::from_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn defaults_to_gadget_backend() {
+        assert_eq!(parse(&[]).unwrap().backend, Backend::Gadget);
+    }
+
+    #[test]
+    fn parses_backend() {
+        assert_eq!(parse(&["--backend", "uinput"]).unwrap().backend, Backend::Uinput);
+        assert!(parse(&["--backend", "bluetooth"]).is_err());
+        assert!(parse(&["--backend"]).is_err());
+    }
+
+    #[test]
+    fn parses_midi_input() {
+        assert_eq!(parse(&[]).unwrap().midi_source, MidiSource::Alsa);
+        assert_eq!(parse(&["--midi-input", "alsa"]).unwrap().midi_source, MidiSource::Alsa);
+        assert_eq!(
+            parse(&["--midi-input", "serial:/dev/ttyAMA0"]).unwrap().midi_source,
+            MidiSource::Serial("/dev/ttyAMA0".to_string())
+        );
+        assert_eq!(
+            parse(&["--midi-input", "rawmidi:/dev/snd/midiC1D0"]).unwrap().midi_source,
+            MidiSource::RawMidi("/dev/snd/midiC1D0".to_string())
+        );
+        assert_eq!(
+            parse(&["--midi-input", "ump:/dev/snd/umpC1D0"]).unwrap().midi_source,
+            MidiSource::Ump("/dev/snd/umpC1D0".to_string())
+        );
+        assert_eq!(parse(&["--midi-input", "rtpmidi"]).unwrap().midi_source, MidiSource::RtpMidi(5004));
+        assert_eq!(parse(&["--midi-input", "rtpmidi:5008"]).unwrap().midi_source, MidiSource::RtpMidi(5008));
+        assert!(parse(&["--midi-input", "serial:"]).is_err());
+        assert!(parse(&["--midi-input", "rtpmidi:applemidi"]).is_err());
+        assert!(parse(&["--midi-input", "/dev/ttyAMA0"]).is_err());
+    }
+
+    #[test]
+    fn parses_log_level() {
+        assert_eq!(parse(&[]).unwrap().log_level, LevelFilter::Info);
+        assert_eq!(parse(&["--log-level", "debug"]).unwrap().log_level, LevelFilter::Debug);
+        assert_eq!(parse(&["--log-level", "trace"]).unwrap().log_level, LevelFilter::Trace);
+        assert!(parse(&["--log-level", "loud"]).is_err());
+    }
+
+    #[test]
+    fn parses_capture_path() {
+        assert_eq!(parse(&[]).unwrap().capture_path, None);
+        let config = parse(&["--capture", "/tmp/session.pcapng"]).unwrap();
+        assert_eq!(config.capture_path, Some("/tmp/session.pcapng".to_string()));
+    }
+
+    #[test]
+    fn parses_controller_path() {
+        assert_eq!(parse(&[]).unwrap().controller_path, None);
+        let config = parse(&["--controller", "/dev/hidraw3"]).unwrap();
+        assert_eq!(config.controller_path, Some("/dev/hidraw3".to_string()));
+    }
+
+    #[test]
+    fn parses_stats_interval() {
+        assert_eq!(parse(&[]).unwrap().stats_interval, Some(Duration::from_secs(60)));
+        assert_eq!(parse(&["--stats-interval", "5"]).unwrap().stats_interval, Some(Duration::from_secs(5)));
+        assert_eq!(parse(&["--stats-interval", "0"]).unwrap().stats_interval, None);
+        assert!(parse(&["--stats-interval", "often"]).is_err());
+    }
+
+    #[test]
+    fn parses_http_address() {
+        assert_eq!(parse(&[]).unwrap().http_address, None);
+        assert_eq!(parse(&["--http", "8080"]).unwrap().http_address, Some("127.0.0.1:8080".to_string()));
+        assert_eq!(parse(&["--http", "0.0.0.0:80"]).unwrap().http_address, Some("0.0.0.0:80".to_string()));
+    }
+
+    #[test]
+    fn parses_osc_address() {
+        assert_eq!(parse(&[]).unwrap().osc_address, None);
+        assert_eq!(parse(&["--osc", "9000"]).unwrap().osc_address, Some("127.0.0.1:9000".to_string()));
+        assert_eq!(parse(&["--osc", "0.0.0.0:9000"]).unwrap().osc_address, Some("0.0.0.0:9000".to_string()));
+    }
+
+    #[test]
+    fn collects_profiles() {
+        let config = parse(&["--profile", "kart.profile", "--profile", "piano.profile"]).unwrap();
+        assert_eq!(config.profile_paths, vec!["kart.profile", "piano.profile"]);
+    }
+
+    #[test]
+    fn parses_profile_dir() {
+        assert_eq!(parse(&[]).unwrap().profile_dir, None);
+        let config = parse(&["--profile-dir", "/etc/midi_to_switch"]).unwrap();
+        assert_eq!(config.profile_dir, Some("/etc/midi_to_switch".to_string()));
+    }
+
+    #[test]
+    fn parses_learn_path() {
+        assert_eq!(parse(&[]).unwrap().learn_path, None);
+        assert_eq!(parse(&["--learn", "kart.profile"]).unwrap().learn_path, Some("kart.profile".to_string()));
+        assert!(parse(&["--learn"]).is_err());
+    }
+
+    #[test]
+    fn parses_tui_flag() {
+        assert!(!parse(&[]).unwrap().tui);
+        assert!(parse(&["--tui", "--backend", "uinput"]).unwrap().tui);
+    }
+
+    #[test]
+    fn parses_frame_timing() {
+        assert_eq!(parse(&[]).unwrap().frame_timing, None);
+        assert_eq!(
+            parse(&["--min-press", "2"]).unwrap().frame_timing,
+            Some(FrameTiming { min_press: 2, min_gap: 1 })
+        );
+        assert_eq!(
+            parse(&["--min-gap", "3", "--min-press", "2"]).unwrap().frame_timing,
+            Some(FrameTiming { min_press: 2, min_gap: 3 })
+        );
+        assert!(parse(&["--min-press", "0"]).is_err());
+        assert!(parse(&["--min-gap", "-1"]).is_err());
+    }
+
+    #[test]
+    fn parses_watchdog() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.max_hold, None);
+        assert_eq!(config.sensing_timeout, Some(Duration::from_millis(300)));
+        let config = parse(&["--max-hold", "30", "--sensing-timeout", "0"]).unwrap();
+        assert_eq!(config.max_hold, Some(Duration::from_secs(30)));
+        assert_eq!(config.sensing_timeout, None);
+        assert_eq!(parse(&["--sensing-timeout", "500"]).unwrap().sensing_timeout, Some(Duration::from_millis(500)));
+        assert!(parse(&["--max-hold", "forever"]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_arguments() {
+        assert!(parse(&["--verbose"]).is_err());
+    }
+}
@@ -0,0 +1,602 @@
+use crate::clock::{ClockEvent, ClockSnapshot, MidiClock};
+use crate::learn::{Learn, Outcome};
+use crate::midi::MidiMessageData;
+use crate::midi_parser::MidiMessage;
+use crate::nscontroller::{Button, InputReport};
+use crate::osc::{OscMessage, OscState};
+use crate::profile::{Overlay, Profile, Target, MAX_OCTAVE_SHIFT};
+use crate::timing::FrameTiming;
+use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Note presses kept for the next report, bounded while no report thread takes them
+const MAX_PRESSES: usize = 128;
+
+/// Device currently used for each part of the relay, None while disconnected
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Connections {
+    /// Name of the MIDI input port
+    pub midi: Option<String>,
+    /// hidraw node of the physical controller
+    pub controller: Option<String>,
+    /// USB gadget device towards the console
+    pub gadget: Option<String>,
+}
+
+/// Runtime state that workers read and the HTTP API changes
+///
+/// Holds the loaded profiles, the active one, buttons pressed through the API,
+/// a running learn session, the MIDI clock and what the threads are connected to.
+pub struct Control {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    profiles: Vec<Arc<Profile>>,
+    active: usize,
+    /// Buttons pressed through the API and when they are released again
+    injected: Vec<(Button, Instant)>,
+    /// API presses and their duration waiting for the next step while quantizing
+    waiting: Vec<(Button, Duration)>,
+    osc: OscState,
+    /// Octaves the keyboard is shifted by, back to 0 with another profile
+    octave: i8,
+    /// Controllers mapped to an octave shift that are at 64 or more
+    shift_controls: Vec<u8>,
+    /// Shift each held note was pressed with, so shifting does not move held keys
+    note_octaves: Vec<(u8, i8)>,
+    learn: Option<Learn>,
+    clock: MidiClock,
+    /// Arrival of the latest Active Sensing, None until an input sends it
+    sensing: Option<Instant>,
+    /// Notes the watchdog released, for the receivers to forget
+    released: Vec<u8>,
+    /// Set when everything was released, the receivers drop the notes waiting for the clock too
+    released_all: bool,
+    /// Notes only a pedal holds, released when another profile is selected
+    pedal_held: Vec<u8>,
+    /// Stretches presses to report frames when set
+    frame_timing: Option<FrameTiming>,
+    /// Notes pressed since the last report, so short ones are not missed
+    presses: Vec<MidiMessageData>,
+    connections: Connections,
+}
+
+impl Control {
+    /// The first profile is active, without profiles the default mapping is used
+    pub fn new(mut profiles: Vec<Profile>) -> Control {
+        if profiles.is_empty() {
+            profiles.push(Profile::default());
+        }
+        Control {
+            inner: Mutex::new(Inner {
+                profiles: profiles.into_iter().map(Arc::new).collect(),
+                active: 0,
+                injected: Vec::new(),
+                waiting: Vec::new(),
+                osc: OscState::default(),
+                octave: 0,
+                shift_controls: Vec::new(),
+                note_octaves: Vec::new(),
+                learn: None,
+                clock: MidiClock::new(),
+                sensing: None,
+                released: Vec::new(),
+                released_all: false,
+                pedal_held: Vec::new(),
+                frame_timing: None,
+                presses: Vec::new(),
+                connections: Connections::default(),
+            }),
+        }
+    }
+
+    pub fn active_profile(&self) -> Arc<Profile> {
+        let inner = self.lock();
+        inner.profiles[inner.active].clone()
+    }
+
+    pub fn profile_names(&self) -> Vec<String> {
+        self.lock().profiles.iter().map(|profile| profile.name.clone()).collect()
+    }
+
+    pub fn profile(&self, name: &str) -> Option<Arc<Profile>> {
+        self.lock().profiles.iter().find(|profile| profile.name == name).cloned()
+    }
+
+    /// Replaces the profile with the same name or adds it, an active profile stays active
+    pub fn save_profile(&self, profile: Profile) {
+        let mut inner = self.lock();
+        match inner.profiles.iter().position(|existing| existing.name == profile.name) {
+            Some(position) => inner.profiles[position] = Arc::new(profile),
+            None => inner.profiles.push(Arc::new(profile)),
+        }
+    }
+
+    /// Makes `name` the active profile, returns the notes a pedal held.
+    /// The receivers forget them, the caller takes them out of the MIDI state
+    pub fn select_profile(&self, name: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut inner = self.lock();
+        let position = inner
+            .profiles
+            .iter()
+            .position(|profile| profile.name == name)
+            .ok_or_else(|| format!("Unknown profile {:?}", name))?;
+        inner.active = position;
+        inner.octave = 0;
+        let held = std::mem::take(&mut inner.pedal_held);
+        for note in held.iter() {
+            if !inner.released.contains(note) {
+                inner.released.push(*note);
+            }
+        }
+        Ok(held)
+    }
+
+    /// Notes a receiver's pedals hold after their keys came up
+    pub fn set_pedal_held(&self, notes: Vec<u8>) {
+        self.lock().pedal_held = notes;
+    }
+
+    /// Holds `button` for `duration`, used to test the path to the console without MIDI.
+    /// While the active profile quantizes the press waits for the next step like a note
+    pub fn inject(&self, button: Button, duration: Duration) {
+        let mut inner = self.lock();
+        if inner.profiles[inner.active].quantize.is_some() && inner.clock.is_playing() {
+            inner.waiting.retain(|(held, _)| *held != button);
+            inner.waiting.push((button, duration));
+            return;
+        }
+        hold(&mut inner, button, Instant::now() + duration);
+    }
+
+    /// Releases every button pressed through the API or OSC
+    pub fn release_injected(&self) {
+        let mut inner = self.lock();
+        inner.injected.clear();
+        inner.waiting.clear();
+        inner.osc = OscState::default();
+    }
+
+    /// Applies an OSC message through the active profile, false when no address matches
+    pub fn osc_message(&self, message: &OscMessage) -> bool {
+        let mut inner = self.lock();
+        let profile = inner.profiles[inner.active].clone();
+        inner.osc.apply(message, &profile)
+    }
+
+    /// Follows the clock, waiting API presses start on a step of the grid or once the clock stops
+    pub fn clock_message(&self, message: &MidiMessage, at: Instant) -> Option<ClockEvent> {
+        let mut inner = self.lock();
+        let event = inner.clock.handle(message, at);
+        let step = match (event, inner.profiles[inner.active].quantize) {
+            (Some(ClockEvent::Pulse(position)), Some(grid)) => position.is_multiple_of(grid as u64),
+            (None, _) | (Some(ClockEvent::Playing), _) => false,
+            _ => true,
+        };
+        if step {
+            for (button, duration) in std::mem::take(&mut inner.waiting) {
+                hold(&mut inner, button, at + duration);
+            }
+        }
+        event
+    }
+
+    pub fn clock(&self) -> ClockSnapshot {
+        self.lock().clock.snapshot(Instant::now())
+    }
+
+    pub fn active_sensing(&self, at: Instant) {
+        self.lock().sensing = Some(at);
+    }
+
+    /// True once when Active Sensing stopped for longer than `timeout`,
+    /// inputs that never sent it are not watched
+    pub fn sensing_lost(&self, timeout: Duration, now: Instant) -> bool {
+        let mut inner = self.lock();
+        let lost = inner
+            .sensing
+            .is_some_and(|last| now.saturating_duration_since(last) > timeout);
+        if lost {
+            inner.sensing = None;
+        }
+        lost
+    }
+
+    /// Notes released without their Note Off, so the receivers stop holding them
+    pub fn release_notes(&self, notes: &[u8]) {
+        let mut inner = self.lock();
+        for note in notes {
+            if !inner.released.contains(note) {
+                inner.released.push(*note);
+            }
+        }
+    }
+
+    /// Like `release_notes` for every held note, also drops what waits for the clock
+    pub fn release_all_notes(&self, notes: &[u8]) {
+        self.release_notes(notes);
+        self.lock().released_all = true;
+    }
+
+    pub fn take_released_notes(&self) -> Vec<u8> {
+        std::mem::take(&mut self.lock().released)
+    }
+
+    /// True once after `release_all_notes`
+    pub fn take_released_all(&self) -> bool {
+        std::mem::take(&mut self.lock().released_all)
+    }
+
+    /// Grid in clock pulses that notes wait for, only while the clock plays
+    /// and the active profile quantizes
+    pub fn quantize(&self) -> Option<u32> {
+        let inner = self.lock();
+        inner.profiles[inner.active].quantize.filter(|_| inner.clock.is_playing())
+    }
+
+    pub fn set_frame_timing(&self, timing: Option<FrameTiming>) {
+        self.lock().frame_timing = timing;
+    }
+
+    pub fn frame_timing(&self) -> Option<FrameTiming> {
+        self.lock().frame_timing
+    }
+
+    /// Remembers the octave shift of the note presses among state entries and,
+    /// with frame timing, the presses themselves for the next report
+    pub fn record_presses(&self, entries: &[MidiMessageData]) {
+        let mut inner = self.lock();
+        let octave = inner.octave;
+        for midi_data in entries.iter().filter(|midi_data| midi_data.is_note() || midi_data.should_remove_midi_message()) {
+            let note = midi_data.data_byte1;
+            inner.note_octaves.retain(|(held, _)| *held != note);
+            if midi_data.should_add_midi_message() {
+                inner.note_octaves.push((note, octave));
+            }
+        }
+        if inner.frame_timing.is_none() {
+            return;
+        }
+        for midi_data in entries.iter().filter(|midi_data| midi_data.should_add_midi_message()) {
+            if inner.presses.len() < MAX_PRESSES {
+                inner.presses.push(midi_data.clone());
+            }
+        }
+    }
+
+    /// Buttons of the notes pressed since the previous call, through the active profile.
+    /// None during a learn session, like the overlay
+    pub fn take_presses(&self) -> Option<InputReport> {
+        let mut inner = self.lock();
+        let presses = std::mem::take(&mut inner.presses);
+        if inner.learn.is_some() {
+            return None;
+        }
+        let octave_of = |midi_data: &MidiMessageData| held_octave(&inner.note_octaves, midi_data).unwrap_or(inner.octave);
+        inner.profiles[inner.active].overlay(&presses, octave_of).buttons
+    }
+
+    /// Whether the active profile lets the piano pedals hold notes
+    pub fn sustain(&self) -> bool {
+        let inner = self.lock();
+        inner.profiles[inner.active].sustain
+    }
+
+    pub fn octave(&self) -> i8 {
+        self.lock().octave
+    }
+
+    /// Moves the keyboard when a note or controller mapped to `OctaveUp` or `OctaveDown`
+    /// goes down, returns the new shift when it changed
+    pub fn shift_octave(&self, midi_data: &MidiMessageData) -> Option<i8> {
+        let mut inner = self.lock();
+        let step = match inner.profiles[inner.active].target_for(midi_data, inner.octave) {
+            Some(Target::Octave(step)) => step,
+            _ => return None,
+        };
+        let pressed = if midi_data.is_control_change() {
+            let controller = midi_data.data_byte1;
+            let down = midi_data.data_byte2 >= 64;
+            let was_down = inner.shift_controls.contains(&controller);
+            inner.shift_controls.retain(|existing| *existing != controller);
+            if down {
+                inner.shift_controls.push(controller);
+            }
+            down && !was_down
+        } else {
+            midi_data.should_add_midi_message()
+        };
+        let octave = (inner.octave + step).clamp(-MAX_OCTAVE_SHIFT, MAX_OCTAVE_SHIFT);
+        if !pressed || octave == inner.octave {
+            return None;
+        }
+        inner.octave = octave;
+        Some(octave)
+    }
+
+    /// Starts a learn session, replacing a running one
+    pub fn start_learning(&self, name: &str) {
+        self.lock().learn = Some(Learn::new(name));
+    }
+
+    /// Current state of the learn session
+    pub fn learning(&self) -> Option<Learn> {
+        self.lock().learn.clone()
+    }
+
+    /// Hands a received MIDI message to the learn session, None when not learning
+    pub fn learn(&self, midi_data: &MidiMessageData) -> Option<Outcome> {
+        self.lock().learn.as_mut().map(|learn| learn.capture(midi_data))
+    }
+
+    /// Leaves the current target of the learn session unassigned
+    pub fn skip_learning(&self) -> Option<Learn> {
+        let mut inner = self.lock();
+        let learn = inner.learn.as_mut()?;
+        learn.skip();
+        Some(learn.clone())
+    }
+
+    /// Ends the learn session and adds its profile, None when not learning
+    pub fn finish_learning(&self) -> Option<Profile> {
+        let learn = self.lock().learn.take()?;
+        let profile = learn.profile();
+        self.save_profile(profile.clone());
+        Some(profile)
+    }
+
+    pub fn cancel_learning(&self) -> bool {
+        self.lock().learn.take().is_some()
+    }
+
+    /// Input from the MIDI state and OSC notes through the active profile plus the
+    /// injected presses and the OSC buttons and sticks, None when nothing is active
+    /// so the controller input passes through. While learning notes do not reach the console.
+    pub fn overlay(&self, messages: &[MidiMessageData]) -> Option<Overlay> {
+        let now = Instant::now();
+        let (profile, injected, osc, octave, note_octaves) = {
+            let mut inner = self.lock();
+            inner.injected.retain(|(_, until)| *until > now);
+            let injected: Vec<Button> = inner.injected.iter().map(|(button, _)| button.clone()).collect();
+            let profile = match inner.learn {
+                Some(_) => None,
+                None => Some(inner.profiles[inner.active].clone()),
+            };
+            (profile, injected, inner.osc.clone(), inner.octave, inner.note_octaves.clone())
+        };
+        let octave_of = |midi_data: &MidiMessageData| held_octave(&note_octaves, midi_data).unwrap_or(octave);
+        let mut overlay = match profile {
+            Some(profile) if !osc.notes().is_empty() => profile.overlay(&[messages, osc.notes()].concat(), octave_of),
+            Some(profile) => profile.overlay(messages, octave_of),
+            None => Overlay::default(),
+        };
+        for button in injected.iter() {
+            overlay.press(button);
+        }
+        osc.apply_to(&mut overlay);
+        if overlay.is_empty() {
+            None
+        } else {
+            Some(overlay)
+        }
+    }
+
+    pub fn connections(&self) -> Connections {
+        self.lock().connections.clone()
+    }
+
+    pub fn set_midi(&self, port: Option<String>) {
+        self.lock().connections.midi = port;
+    }
+
+    pub fn set_controller(&self, path: Option<String>) {
+        self.lock().connections.controller = path;
+    }
+
+    pub fn set_gadget(&self, path: Option<String>) {
+        self.lock().connections.gadget = path;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+fn hold(inner: &mut Inner, button: Button, until: Instant) {
+    inner.injected.retain(|(held, _)| *held != button);
+    inner.injected.push((button, until));
+}
+
+/// Octave shift a held note was pressed with
+fn held_octave(note_octaves: &[(u8, i8)], midi_data: &MidiMessageData) -> Option<i8> {
+    if !midi_data.is_note() {
+        return None;
+    }
+    note_octaves
+        .iter()
+        .find(|(note, _)| *note == midi_data.data_byte1)
+        .map(|(_, octave)| *octave)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osc::Argument;
+    use crate::report::StickPosition;
+    use crate::test_support::{self, note_off, note_on};
+
+    #[test]
+    fn uses_default_profile_without_profiles() {
+        let control = Control::new(Vec::new());
+        assert_eq!(control.profile_names(), vec!["default"]);
+        assert_eq!(control.active_profile().name, "default");
+    }
+
+    #[test]
+    fn selects_profile_by_name() {
+        let kart = Profile::parse("kart", "note C = A").unwrap();
+        let control = Control::new(vec![Profile::default(), kart]);
+        control.select_profile("kart").unwrap();
+        assert_eq!(control.active_profile().name, "kart");
+        assert!(control.overlay(&[note_on(60, 0x40)]).unwrap().buttons.unwrap().is_pressed(&Button::A));
+        assert!(control.select_profile("piano").is_err());
+        assert_eq!(control.active_profile().name, "kart");
+    }
+
+    #[test]
+    fn overlay_is_none_without_input() {
+        let control = Control::new(Vec::new());
+        assert_eq!(control.overlay(&[]), None);
+    }
+
+    #[test]
+    fn injected_buttons_expire_and_release() {
+        let control = Control::new(Vec::new());
+        control.inject(Button::Home, Duration::from_secs(60));
+        let buttons = control.overlay(&[note_on(60, 0x40)]).unwrap().buttons.unwrap();
+        assert!(buttons.is_pressed(&Button::Home));
+        assert!(buttons.is_pressed(&Button::Y));
+
+        control.release_injected();
+        assert_eq!(control.overlay(&[]), None);
+
+        control.inject(Button::A, Duration::ZERO);
+        assert_eq!(control.overlay(&[]), None);
+    }
+
+    #[test]
+    fn injected_presses_wait_for_the_grid_while_quantizing() {
+        let kart = Profile::parse("kart", "quantize = 1/16").unwrap();
+        let control = Control::new(vec![kart]);
+        let now = Instant::now();
+        control.clock_message(&MidiMessage::Start, now);
+        control.clock_message(&MidiMessage::TimingClock, now);
+        control.inject(Button::A, Duration::from_secs(60));
+        assert_eq!(control.overlay(&[]), None);
+        for _ in 0..5 {
+            control.clock_message(&MidiMessage::TimingClock, now);
+        }
+        assert_eq!(control.overlay(&[]), None);
+        control.clock_message(&MidiMessage::TimingClock, now);
+        assert!(control.overlay(&[]).unwrap().buttons.unwrap().is_pressed(&Button::A));
+
+        // Stopping the clock lets waiting presses through
+        control.clock_message(&MidiMessage::TimingClock, now);
+        control.inject(Button::B, Duration::from_secs(60));
+        assert!(!control.overlay(&[]).unwrap().buttons.unwrap().is_pressed(&Button::B));
+        control.clock_message(&MidiMessage::Stop, now);
+        assert!(control.overlay(&[]).unwrap().buttons.unwrap().is_pressed(&Button::B));
+    }
+
+    #[test]
+    fn keeps_presses_for_the_next_report_with_frame_timing() {
+        let control = Control::new(Vec::new());
+        control.record_presses(&[note_on(60, 0x40)]);
+        assert_eq!(control.take_presses(), None);
+
+        control.set_frame_timing(Some(FrameTiming::default()));
+        let release = note_off(60);
+        control.record_presses(&[note_on(60, 0x40), release]);
+        assert!(control.take_presses().unwrap().is_pressed(&Button::Y));
+        assert_eq!(control.take_presses(), None);
+    }
+
+    #[test]
+    fn learning_mutes_recorded_presses() {
+        let control = Control::new(Vec::new());
+        control.set_frame_timing(Some(FrameTiming::default()));
+        control.start_learning("kart");
+        control.record_presses(&[note_on(60, 0x40)]);
+        assert_eq!(control.take_presses(), None);
+
+        // Presses during the session are not sent afterwards either
+        control.cancel_learning();
+        assert_eq!(control.take_presses(), None);
+    }
+
+    #[test]
+    fn octave_sources_shift_the_keyboard() {
+        let split = Profile::parse("split", "note 21 = OctaveUp\ncc 20 = OctaveDown\nnote 60 = A").unwrap();
+        let control = Control::new(vec![split, Profile::default()]);
+        let controller = |value: u8| test_support::control(20, value);
+        assert_eq!(control.shift_octave(&note_on(21, 0x40)), Some(1));
+        assert_eq!(control.shift_octave(&note_on(60, 0x40)), None);
+        // 48 plays as 60 now
+        assert!(control.overlay(&[note_on(48, 0x40)]).unwrap().buttons.unwrap().is_pressed(&Button::A));
+
+        // Controllers shift once each time they go down
+        assert_eq!(control.shift_octave(&controller(127)), Some(0));
+        assert_eq!(control.shift_octave(&controller(100)), None);
+        assert_eq!(control.shift_octave(&controller(0)), None);
+        assert_eq!(control.shift_octave(&controller(64)), Some(-1));
+        for _ in 0..20 {
+            control.shift_octave(&controller(0));
+            control.shift_octave(&controller(127));
+        }
+        assert_eq!(control.octave(), -MAX_OCTAVE_SHIFT);
+
+        control.select_profile("default").unwrap();
+        assert_eq!(control.octave(), 0);
+    }
+
+    #[test]
+    fn held_notes_keep_their_octave() {
+        let split = Profile::parse("split", "note 21 = OctaveUp\nnote 60 = A\nnote 72 = B").unwrap();
+        let control = Control::new(vec![split]);
+        control.record_presses(&[note_on(60, 0x40)]);
+        assert_eq!(control.shift_octave(&note_on(21, 0x40)), Some(1));
+        // 60 stays on A while held, a new press of 60 plays as 72
+        let overlay = control.overlay(&[note_on(60, 0x40)]).unwrap();
+        assert!(overlay.buttons.unwrap().is_pressed(&Button::A));
+        control.record_presses(&[note_off(60)]);
+        control.record_presses(&[note_on(60, 0x40)]);
+        let buttons = control.overlay(&[note_on(60, 0x40)]).unwrap().buttons.unwrap();
+        assert!(buttons.is_pressed(&Button::B));
+        assert!(!buttons.is_pressed(&Button::A));
+    }
+
+    #[test]
+    fn osc_input_joins_midi_until_released() {
+        let control = Control::new(Vec::new());
+        let message = |address: &str, arguments: &[Argument]| OscMessage {
+            address: address.to_string(),
+            arguments: arguments.to_vec(),
+        };
+        assert!(control.osc_message(&message("/note", &[Argument::Int(66), Argument::Int(100)])));
+        assert!(control.osc_message(&message("/button/A", &[Argument::Int(1)])));
+        assert!(control.osc_message(&message("/stick/right", &[Argument::Float(1.0), Argument::Float(0.0)])));
+        assert!(!control.osc_message(&message("/lights", &[])));
+
+        let overlay = control.overlay(&[note_on(60, 0x40)]).unwrap();
+        let buttons = overlay.buttons.unwrap();
+        assert!(buttons.is_pressed(&Button::Y));
+        assert!(buttons.is_pressed(&Button::L));
+        assert!(buttons.is_pressed(&Button::A));
+        assert_eq!(overlay.right_stick, Some(StickPosition { x: StickPosition::MAX, y: 0x800 }));
+
+        control.release_injected();
+        assert_eq!(control.overlay(&[]), None);
+    }
+
+    #[test]
+    fn learning_mutes_midi_and_adds_profile() {
+        let control = Control::new(Vec::new());
+        assert_eq!(control.learn(&note_on(60, 0x40)), None);
+        control.start_learning("kart");
+        assert_eq!(control.overlay(&[note_on(60, 0x40)]), None);
+        assert!(matches!(control.learn(&note_on(60, 0x40)), Some(Outcome::Assigned(..))));
+        assert_eq!(control.skip_learning().unwrap().assigned().len(), 1);
+
+        let profile = control.finish_learning().unwrap();
+        assert_eq!(profile.to_text(), "name = kart\nnote 60 = Y\n");
+        assert_eq!(control.learning(), None);
+        assert_eq!(control.profile_names(), vec!["default", "kart"]);
+        assert!(control.overlay(&[note_on(60, 0x40)]).is_some());
+        assert!(!control.cancel_learning());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::activity::ActivitySnapshot;
+use crate::clock::ClockSnapshot;
+use crate::control::Connections;
+use crate::midi_parser::MidiMessage;
+use crate::nscontroller::{Button, Pitch};
+use crate::report::ProControllerReport;
+use crate::stats::StatsSnapshot;
+use std::fmt::Write;
+use std::time::Instant;
+
+/// Moves the cursor to the top left, every frame overwrites the previous one
+const HOME: &str = "\x1b[H";
+/// Clears the rest of the line or, at the end, the rest of the screen
+const CLEAR_LINE: &str = "\x1b[K";
+const CLEAR_BELOW: &str = "\x1b[J";
+const REVERSE: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
+
+/// Messages per second since the previous frame
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Throughput {
+    pub midi: f64,
+    pub to_console: f64,
+    pub to_controller: f64,
+}
+
+/// Everything one frame of the dashboard shows
+pub struct Frame<'a> {
+    pub now: Instant,
+    pub profile: &'a str,
+    pub connections: &'a Connections,
+    pub activity: &'a ActivitySnapshot,
+    pub throughput: &'a Throughput,
+    pub stats: &'a StatsSnapshot,
+    pub clock: &'a ClockSnapshot,
+}
+
+/// Renders a frame as terminal output, starting at the top left
+pub fn render(frame: &Frame) -> String {
+    let mut out = String::from(HOME);
+    let mut line = |text: String| {
+        let _ = writeln!(out, "{}{}", text, CLEAR_LINE);
+    };
+    let device = |device: &Option<String>| device.clone().unwrap_or_else(|| String::from("-"));
+
+    line(format!(
+        "midi_to_switch  profile {}  midi {}  controller {}  gadget {}",
+        frame.profile,
+        device(&frame.connections.midi),
+        device(&frame.connections.controller),
+        device(&frame.connections.gadget)
+    ));
+    line(format!(
+        "throughput  midi {:.1}/s  to console {:.1}/s  to controller {:.1}/s",
+        frame.throughput.midi, frame.throughput.to_console, frame.throughput.to_controller
+    ));
+    line(format!("{}", frame.stats));
+    let (bar, beat) = frame.clock.bar_beat();
+    line(format!(
+        "clock  {}  {}  bar {} beat {}",
+        if frame.clock.playing { "playing" } else { "stopped" },
+        match frame.clock.bpm {
+            Some(bpm) => format!("{:.1} BPM", bpm),
+            None => String::from("no tempo"),
+        },
+        bar,
+        beat
+    ));
+    line(String::new());
+
+    line(String::from("Held notes"));
+    let mut any_held = false;
+    for (channel, held) in frame.activity.held.iter().enumerate() {
+        if *held == 0 {
+            continue;
+        }
+        any_held = true;
+        let notes: Vec<String> = (0..128u8).filter(|note| held & (1u128 << note) != 0).map(note_name).collect();
+        line(format!("  ch {:<2}  {}", channel + 1, notes.join(" ")));
+    }
+    if !any_held {
+        line(String::from("  -"));
+    }
+    line(String::new());
+
+    match frame.activity.report.as_deref().map(ProControllerReport::parse) {
+        Some(Ok(report)) => {
+            let buttons: Vec<String> = Button::ALL
+                .iter()
+                .map(|button| match report.buttons.is_pressed(button) {
+                    true => format!("{}{}{}", REVERSE, button.name(), RESET),
+                    false => button.name().to_string(),
+                })
+                .collect();
+            line(format!("Buttons  {}", buttons.join(" ")));
+            line(format!(
+                "Sticks   left {}/{}  right {}/{}",
+                report.left_stick.x, report.left_stick.y, report.right_stick.x, report.right_stick.y
+            ));
+        }
+        Some(Err(_)) | None => {
+            line(String::from("Buttons  no report sent yet"));
+            line(String::from("Sticks   -"));
+        }
+    }
+    line(String::new());
+
+    line(String::from("MIDI events"));
+    for (at, message) in frame.activity.midi.iter().rev() {
+        line(format!("  {:>7}  {}", age(frame.now, *at), describe_midi(message)));
+    }
+    line(String::new());
+
+    line(String::from("Console"));
+    for (at, packet) in frame.activity.console.iter().rev() {
+        line(format!("  {:>7}  {}", age(frame.now, *at), packet));
+    }
+    line(String::new());
+
+    line(String::from("Log"));
+    for log_line in frame.activity.log.iter().rev() {
+        line(format!("  {}", log_line));
+    }
+
+    out.push_str(CLEAR_BELOW);
+    out
+}
+
+/// Scientific pitch notation, middle C (60) is C4
+fn note_name(note: u8) -> String {
+    format!("{}{}", Pitch::from_note(note).name(), note as i32 / 12 - 1)
+}
+
+fn age(now: Instant, at: Instant) -> String {
+    format!("-{:.2}s", now.saturating_duration_since(at).as_secs_f64())
+}
+
+/// Bytes, channel and meaning of a message, long system exclusive is cut off
+fn describe_midi(message: &MidiMessage) -> String {
+    let encoded = message.to_bytes();
+    let mut bytes: Vec<String> = encoded.iter().take(3).map(|byte| format!("{:02X}", byte)).collect();
+    if encoded.len() > 3 {
+        bytes.push(String::from(".."));
+    }
+    let channel = match message.channel() {
+        Some(channel) => format!("ch {:<2}", channel + 1),
+        None => String::from("     "),
+    };
+    let meaning = match message {
+        MidiMessage::NoteOff { note, velocity, .. } => format!("NoteOff {} {}", note_name(*note & 0x7F), velocity),
+        MidiMessage::NoteOn { note, velocity, .. } => format!("NoteOn {} {}", note_name(*note & 0x7F), velocity),
+        MidiMessage::PolyphonicPressure { note, pressure, .. } => {
+            format!("PolyphonicPressure {} {}", note_name(*note & 0x7F), pressure)
+        }
+        MidiMessage::ControlChange { controller, value, .. } => format!("ControlChange cc {} = {}", controller, value),
+        MidiMessage::ProgramChange { program, .. } => format!("ProgramChange {}", program),
+        MidiMessage::ChannelPressure { pressure, .. } => format!("ChannelPressure {}", pressure),
+        MidiMessage::PitchBend { value, .. } => format!("PitchBend {}", value),
+        MidiMessage::SystemExclusive(data) => format!("SystemExclusive {} bytes", data.len()),
+        other => format!("{:?}", other),
+    };
+    format!("{:<9} {} {}", bytes.join(" "), channel, meaning)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::Activity;
+    use crate::stats::LatencyStats;
+
+    fn frame_text(activity: &ActivitySnapshot) -> String {
+        let frame = Frame {
+            now: Instant::now(),
+            profile: "Kart",
+            connections: &Connections {
+                midi: Some(String::from("Piano")),
+                controller: None,
+                gadget: Some(String::from("/dev/hidg0")),
+            },
+            activity,
+            throughput: &Throughput {
+                midi: 2.0,
+                to_console: 125.0,
+                to_controller: 0.5,
+            },
+            stats: &LatencyStats::new().snapshot(),
+            clock: &ClockSnapshot {
+                playing: true,
+                position: 100,
+                bpm: Some(128.0),
+            },
+        };
+        render(&frame)
+    }
+
+    #[test]
+    fn renders_connections_and_throughput() {
+        let text = frame_text(&Activity::new().snapshot());
+        assert!(text.starts_with("\x1b[Hmidi_to_switch  profile Kart  midi Piano  controller -  gadget /dev/hidg0\x1b[K\n"));
+        assert!(text.contains("throughput  midi 2.0/s  to console 125.0/s  to controller 0.5/s"));
+        assert!(text.contains("clock  playing  128.0 BPM  bar 2 beat 1"));
+        assert!(text.contains("Held notes\x1b[K\n  -\x1b[K\n"));
+        assert!(text.contains("Buttons  no report sent yet"));
+        assert!(text.ends_with("\x1b[J"));
+    }
+
+    #[test]
+    fn renders_notes_events_and_report() {
+        let activity = Activity::new();
+        activity.set_enabled(true);
+        activity.midi_message(&MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 });
+        activity.midi_message(&MidiMessage::NoteOn { channel: 9, note: 36, velocity: 90 });
+        activity.midi_message(&MidiMessage::ControlChange { channel: 0, controller: 1, value: 64 });
+        activity.midi_message(&MidiMessage::SystemExclusive(vec![0x7E, 0x7F, 0x06, 0x01]));
+        activity.midi_message(&MidiMessage::Start);
+        activity.console_packet(&"subcommand SetPlayerLights on=0001 flashing=0000 counter=3");
+        let mut report = ProControllerReport::neutral(0);
+        report.buttons.press_one(&Button::ZR).unwrap();
+        activity.input_report(&report.to_bytes());
+
+        let text = frame_text(&activity.snapshot());
+        assert!(text.contains("  ch 1   C4\x1b[K\n  ch 10  C2\x1b[K\n"));
+        assert!(text.contains("B0 01 40  ch 1  ControlChange cc 1 = 64"));
+        assert!(text.contains("90 3C 64  ch 1  NoteOn C4 100"));
+        assert!(text.contains("F0 7E 7F ..       SystemExclusive 4 bytes"));
+        assert!(text.contains("FA              Start"));
+        assert!(text.contains("subcommand SetPlayerLights"));
+        assert!(text.contains("R \x1b[7mZR\x1b[0m Minus"));
+        assert!(text.contains("Sticks   left 2048/2048  right 2048/2048"));
+    }
+}
@@ -21,6 +21,7 @@ impl DeviceFile {
     pub fn write(&mut self, data: Vec<u8>) -> Result<(), Error> {
         if let Err(e) = self.fp.write_all(data.as_ref()) {
             error!("Unable to write to {:?}: {}", self.fp, e);
+            return Err(e);
         }
         Ok(())
     }
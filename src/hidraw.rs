@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const NINTENDO_VENDOR_ID: u32 = 0x057E;
+
+/// Supported controllers in order of preference
+pub const NINTENDO_PRODUCTS: [(u32, &str); 4] = [
+    (0x2009, "Pro Controller"),
+    (0x200E, "Joy-Con Charging Grip"),
+    (0x2006, "Joy-Con (L)"),
+    (0x2007, "Joy-Con (R)"),
+];
+
+/// One /sys/class/hidraw entry
+#[derive(Debug, PartialEq, Clone)]
+pub struct HidrawDevice {
+    /// Node name, e.g. hidraw0
+    pub node: String,
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub name: String,
+}
+
+impl HidrawDevice {
+    /// Parses the uevent file of the HID device behind a hidraw node
+    /// ```text
+    /// HID_ID=0003:0000057E:00002009
+    /// HID_NAME=Nintendo Co., Ltd. Pro Controller
+    /// ```
+    pub fn from_uevent(node: &str, uevent: &str) -> Option<HidrawDevice> {
+        let mut ids = None;
+        let mut name = String::new();
+        for line in uevent.lines() {
+            if let Some(value) = line.strip_prefix("HID_ID=") {
+                let parts: Vec<&str> = value.split(':').collect();
+                if parts.len() == 3 {
+                    let vendor_id = u32::from_str_radix(parts[1], 16).ok()?;
+                    let product_id = u32::from_str_radix(parts[2], 16).ok()?;
+                    ids = Some((vendor_id, product_id));
+                }
+            } else if let Some(value) = line.strip_prefix("HID_NAME=") {
+                name = value.to_string();
+            }
+        }
+        let (vendor_id, product_id) = ids?;
+        Some(HidrawDevice {
+            node: node.to_string(),
+            vendor_id,
+            product_id,
+            name,
+        })
+    }
+
+    /// Position in NINTENDO_PRODUCTS, None for anything else
+    pub fn preference(&self) -> Option<usize> {
+        if self.vendor_id != NINTENDO_VENDOR_ID {
+            return None;
+        }
+        NINTENDO_PRODUCTS
+            .iter()
+            .position(|(product_id, _)| *product_id == self.product_id)
+    }
+}
+
+/// Lists every hidraw node found below `sysfs_root` (normally /sys), sorted by node name
+pub fn scan(sysfs_root: &Path) -> Result<Vec<HidrawDevice>, Box<dyn Error>> {
+    let class_dir = sysfs_root.join("class/hidraw");
+    let entries = fs::read_dir(&class_dir).map_err(|e| format!("Unable to read {}: {}", class_dir.display(), e))?;
+
+    let mut devices = Vec::new();
+    for entry in entries.flatten() {
+        let node = entry.file_name().to_string_lossy().to_string();
+        let uevent = match fs::read_to_string(entry.path().join("device/uevent")) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        if let Some(device) = HidrawDevice::from_uevent(&node, &uevent) {
+            devices.push(device);
+        }
+    }
+    devices.sort_by(|a, b| a.node.cmp(&b.node));
+    Ok(devices)
+}
+
+/// Picks the hidraw node of the preferred Nintendo controller and returns its path below `dev_root`
+///
+/// When nothing matches, the error lists every candidate that was found
+pub fn find_controller(sysfs_root: &Path, dev_root: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let devices = scan(sysfs_root)?;
+    let best = devices
+        .iter()
+        .filter_map(|device| device.preference().map(|preference| (preference, device)))
+        .min_by_key(|(preference, _)| *preference);
+
+    match best {
+        Some((_, device)) => Ok(dev_root.join(&device.node)),
+        None => {
+            let candidates: Vec<String> = devices
+                .iter()
+                .map(|device| format!("{} ({:04X}:{:04X} {})", device.node, device.vendor_id, device.product_id, device.name))
+                .collect();
+            Err(format!(
+                "No Nintendo controller found in {}, candidates: {}",
+                sysfs_root.join("class/hidraw").display(),
+                if candidates.is_empty() { String::from("none") } else { candidates.join(", ") }
+            )
+            .into())
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// Largest request body accepted, profiles are small text files
+const MAX_BODY: usize = 64 * 1024;
+
+/// Minimal HTTP/1.1 request, enough for the local control API
+#[derive(Debug, PartialEq, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads one request, the connection is closed after the response
+    pub fn read<R: Read>(stream: R) -> Result<Request, Box<dyn Error>> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next().ok_or("Empty request")?.to_string();
+        let target = parts.next().ok_or("Missing request target")?;
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), parse_query(query)),
+            None => (target.to_string(), HashMap::new()),
+        };
+
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().map_err(|_| "Invalid Content-Length")?;
+                }
+            }
+        }
+        if content_length > MAX_BODY {
+            return Err(format!("Request body of {} bytes is too large", content_length).into());
+        }
+
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body)?;
+        Ok(Request {
+            method,
+            path,
+            query,
+            body,
+        })
+    }
+}
+
+impl Response {
+    pub fn json(status: u16, body: String) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.into_bytes(),
+        }
+    }
+
+    pub fn text(status: u16, body: String) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into_bytes(),
+        }
+    }
+
+    pub fn html(body: &str) -> Response {
+        Response {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    /// Plain text in the Prometheus exposition format version
+    pub fn metrics(body: String) -> Response {
+        Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4; charset=utf-8",
+            body: body.into_bytes(),
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut stream: W) -> std::io::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Splits `a=1&b=2`, decoding %XX escapes and '+'
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (decode(key), decode(value)),
+            None => (decode(pair), String::new()),
+        })
+        .collect()
+}
+
+/// Decodes %XX escapes of a path segment, '+' stays as it is there
+pub fn decode_path(segment: &str) -> String {
+    percent_decode(segment, false)
+}
+
+fn decode(value: &str) -> String {
+    percent_decode(value, true)
+}
+
+fn percent_decode(value: &str, plus_is_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_is_space => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let escaped = std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_request_with_query_and_body() {
+        let raw = b"POST /api/press?button=A&duration_ms=200 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        let request = Request::read(&raw[..]).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/press");
+        assert_eq!(request.query.get("button"), Some(&"A".to_string()));
+        assert_eq!(request.query.get("duration_ms"), Some(&"200".to_string()));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn decodes_query_escapes() {
+        let query = parse_query("name=Mario%20Kart+8&flag&bad=%G1");
+        assert_eq!(query.get("name"), Some(&"Mario Kart 8".to_string()));
+        assert_eq!(query.get("flag"), Some(&String::new()));
+        assert_eq!(query.get("bad"), Some(&"%G1".to_string()));
+    }
+
+    #[test]
+    fn decodes_path_escapes() {
+        assert_eq!(decode_path("My%20Kart"), "My Kart");
+        assert_eq!(decode_path("a+b"), "a+b");
+    }
+
+    #[test]
+    fn rejects_empty_request() {
+        assert!(Request::read(&b""[..]).is_err());
+    }
+
+    #[test]
+    fn writes_response() {
+        let mut out = Vec::new();
+        Response::json(200, String::from("{}")).write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}"
+        );
+    }
+}
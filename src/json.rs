@@ -0,0 +1,86 @@
+use std::fmt::{Display, Write};
+
+/// Quoted and escaped JSON string
+pub fn string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// JSON array of already encoded values
+pub fn array<I: IntoIterator<Item = String>>(values: I) -> String {
+    format!("[{}]", values.into_iter().collect::<Vec<String>>().join(","))
+}
+
+/// String or null
+pub fn optional_string(value: &Option<String>) -> String {
+    match value {
+        Some(value) => string(value),
+        None => String::from("null"),
+    }
+}
+
+/// Builds a JSON object field by field, keeping the order
+#[derive(Default)]
+pub struct Object {
+    fields: Vec<String>,
+}
+
+impl Object {
+    pub fn new() -> Object {
+        Object::default()
+    }
+
+    /// Adds a value that is already JSON
+    pub fn raw(mut self, key: &str, value: String) -> Object {
+        self.fields.push(format!("{}:{}", string(key), value));
+        self
+    }
+
+    pub fn string(self, key: &str, value: &str) -> Object {
+        self.raw(key, string(value))
+    }
+
+    pub fn number<N: Display>(self, key: &str, value: N) -> Object {
+        self.raw(key, value.to_string())
+    }
+
+    pub fn build(self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn builds_objects_and_arrays() {
+        let json = Object::new()
+            .string("name", "Kart")
+            .number("notes", 3)
+            .raw("buttons", array(vec![string("A"), string("B")]))
+            .raw("port", optional_string(&None))
+            .build();
+        assert_eq!(json, r#"{"name":"Kart","notes":3,"buttons":["A","B"],"port":null}"#);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::midi::{MidiMessageData, MidiMessageTypes};
+use crate::nscontroller::Button;
+use crate::profile::{Axis, Profile, Target};
+use std::fmt;
+
+/// MIDI input a learned target is assigned to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Source {
+    Note(u8),
+    Control(u8),
+}
+
+impl fmt::Display for Source {
+    /// Same spelling as the left side of a profile line
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Note(note) => write!(f, "note {}", note),
+            Source::Control(control) => write!(f, "cc {}", control),
+        }
+    }
+}
+
+/// What a MIDI message did to a learn session
+#[derive(Debug, PartialEq, Clone)]
+pub enum Outcome {
+    /// Not a press, or the controller assigned last sending again while it is held
+    Ignored,
+    Assigned(Source, Target),
+    /// The source is already assigned to an earlier target, the step is asked again
+    Conflict(Source, Target),
+}
+
+/// Builds a profile by asking for every button and stick direction in turn
+///
+/// Each step is answered by the next note on or controller pushed past its
+/// middle. A controller keeps sending while it moves, so messages from the
+/// controller assigned last are ignored until it goes back below its middle
+/// instead of reported as conflicts. A note pressed again is a conflict.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Learn {
+    name: String,
+    steps: Vec<Target>,
+    position: usize,
+    assigned: Vec<(Source, Target)>,
+    /// Controller assigned last while it is still past its middle
+    held: Option<u8>,
+    conflict: Option<(Source, Target)>,
+}
+
+impl Learn {
+    pub fn new(name: &str) -> Learn {
+        let mut steps: Vec<Target> = Button::ALL.iter().cloned().map(Target::Button).collect();
+        for axis in Axis::ALL.iter() {
+            for positive in [false, true] {
+                steps.push(Target::HalfAxis { axis: *axis, positive });
+            }
+        }
+        Learn {
+            name: name.to_string(),
+            steps,
+            position: 0,
+            assigned: Vec::new(),
+            held: None,
+            conflict: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Target waiting for a source, None once every step is done
+    pub fn current(&self) -> Option<&Target> {
+        self.steps.get(self.position)
+    }
+
+    /// Number of the current step counting from 1, and the number of steps
+    pub fn progress(&self) -> (usize, usize) {
+        ((self.position + 1).min(self.steps.len()), self.steps.len())
+    }
+
+    pub fn assigned(&self) -> &[(Source, Target)] {
+        &self.assigned
+    }
+
+    /// Last rejected source and the target it already drives
+    pub fn conflict(&self) -> Option<&(Source, Target)> {
+        self.conflict.as_ref()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.steps.len()
+    }
+
+    pub fn capture(&mut self, midi_data: &MidiMessageData) -> Outcome {
+        if midi_data.is_control_change() && self.held == Some(midi_data.data_byte1) {
+            if midi_data.data_byte2 < 64 {
+                self.held = None;
+            }
+            return Outcome::Ignored;
+        }
+        let source = match midi_data.status_byte {
+            MidiMessageTypes::NoteOn if midi_data.data_byte2 > 0 => Source::Note(midi_data.data_byte1),
+            // Channel mode messages release notes and are never mapped
+            MidiMessageTypes::ControlChange if midi_data.is_channel_mode() => return Outcome::Ignored,
+            MidiMessageTypes::ControlChange if midi_data.data_byte2 >= 64 => Source::Control(midi_data.data_byte1),
+            _ => return Outcome::Ignored,
+        };
+        let target = match self.current() {
+            Some(target) => target.clone(),
+            None => return Outcome::Ignored,
+        };
+        if let Some((_, existing)) = self.assigned.iter().find(|(assigned, _)| *assigned == source) {
+            self.conflict = Some((source, existing.clone()));
+            return Outcome::Conflict(source, existing.clone());
+        }
+        self.assigned.push((source, target.clone()));
+        if let Source::Control(control) = source {
+            self.held = Some(control);
+        }
+        self.conflict = None;
+        self.position += 1;
+        Outcome::Assigned(source, target)
+    }
+
+    /// Leaves the current target unassigned
+    pub fn skip(&mut self) {
+        if !self.is_finished() {
+            self.position += 1;
+            self.conflict = None;
+        }
+    }
+
+    /// Profile with everything assigned so far
+    pub fn profile(&self) -> Profile {
+        let mut profile = Profile::new(&self.name);
+        for (source, target) in self.assigned.iter() {
+            match source {
+                Source::Note(note) => profile.map_note(*note, target.clone()),
+                Source::Control(control) => profile.map_control(*control, target.clone()),
+            }
+        }
+        profile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{control, note_off, note_on};
+
+    #[test]
+    fn asks_for_buttons_then_stick_directions() {
+        let mut learn = Learn::new("kart");
+        assert_eq!(learn.current(), Some(&Target::Button(Button::Y)));
+        assert_eq!(learn.progress(), (1, 26));
+        for _ in Button::ALL.iter() {
+            learn.skip();
+        }
+        assert_eq!(
+            learn.current(),
+            Some(&Target::HalfAxis {
+                axis: Axis::LeftStickX,
+                positive: false
+            })
+        );
+        for _ in 0..8 {
+            learn.skip();
+        }
+        assert!(learn.is_finished());
+        assert_eq!(learn.current(), None);
+        assert_eq!(learn.progress(), (26, 26));
+    }
+
+    #[test]
+    fn captures_notes_and_controllers() {
+        let mut learn = Learn::new("kart");
+        assert_eq!(learn.capture(&note_off(60)), Outcome::Ignored);
+        assert_eq!(learn.capture(&note_on(60, 0)), Outcome::Ignored);
+        assert_eq!(
+            learn.capture(&note_on(60, 100)),
+            Outcome::Assigned(Source::Note(60), Target::Button(Button::Y))
+        );
+        assert_eq!(learn.capture(&control(64, 10)), Outcome::Ignored);
+        assert_eq!(learn.capture(&control(123, 127)), Outcome::Ignored);
+        assert_eq!(
+            learn.capture(&control(64, 127)),
+            Outcome::Assigned(Source::Control(64), Target::Button(Button::X))
+        );
+        // The pedal keeps sending while it is held
+        assert_eq!(learn.capture(&control(64, 127)), Outcome::Ignored);
+        // Pressed again after its release it is a conflict
+        assert_eq!(learn.capture(&control(64, 0)), Outcome::Ignored);
+        assert_eq!(
+            learn.capture(&control(64, 127)),
+            Outcome::Conflict(Source::Control(64), Target::Button(Button::X))
+        );
+
+        let profile = learn.profile();
+        assert_eq!(profile.to_text(), "name = kart\nnote 60 = Y\ncc 64 = X\n");
+    }
+
+    #[test]
+    fn reports_conflicts_and_asks_again() {
+        let mut learn = Learn::new("kart");
+        learn.capture(&note_on(60, 100));
+        // The note just assigned is not swallowed
+        assert_eq!(
+            learn.capture(&note_on(60, 100)),
+            Outcome::Conflict(Source::Note(60), Target::Button(Button::Y))
+        );
+        learn.capture(&note_on(62, 100));
+        assert_eq!(
+            learn.capture(&note_on(60, 100)),
+            Outcome::Conflict(Source::Note(60), Target::Button(Button::Y))
+        );
+        assert_eq!(learn.conflict(), Some(&(Source::Note(60), Target::Button(Button::Y))));
+        assert_eq!(learn.current(), Some(&Target::Button(Button::B)));
+
+        learn.capture(&note_on(64, 100));
+        assert_eq!(learn.conflict(), None);
+        assert_eq!(learn.assigned().len(), 3);
+    }
+}
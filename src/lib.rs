@@ -1,8 +1,40 @@
+pub mod activity;
+pub mod api;
+pub mod capture;
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod dashboard;
 pub mod device_file;
+pub mod hidraw;
+pub mod http;
+pub mod json;
+pub mod learn;
 pub mod logging;
+pub mod metrics;
 pub mod midi;
+pub mod midi_device;
+pub mod midi_parser;
+pub mod nscontroller;
+pub mod osc;
+pub mod pedals;
+pub mod profile;
+pub mod protocol;
+pub mod report;
+pub mod rtp_midi;
+pub mod shutdown;
+pub mod state;
+pub mod stats;
+pub mod supervisor;
+#[cfg(test)]
+mod test_support;
+pub mod timing;
+pub mod uinput;
+pub mod ump;
+pub mod watchdog;
 
 // Re-export commonly used types for tests and downstream users
 pub use crate::device_file::DeviceFile;
 pub use crate::logging::init_logger;
 pub use crate::midi::{MidiMessageData, MidiMessageTypes};
+pub use crate::midi_parser::{MidiMessage, MidiParser};
@@ -1,16 +1,25 @@
-use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+use crate::activity::ACTIVITY;
+use log::{LevelFilter, Metadata, Record, SetLoggerError};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 struct SimpleLogger;
 static LOGGER: SimpleLogger = SimpleLogger;
+/// Set while the terminal dashboard owns stdout
+static TO_ACTIVITY: AtomicBool = AtomicBool::new(false);
 
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Debug
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            println!("{} - {}", record.level(), record.args());
+            let line = format!("{} - {}", record.level(), record.args());
+            if TO_ACTIVITY.load(Ordering::Relaxed) {
+                ACTIVITY.log_line(line);
+            } else {
+                println!("{}", line);
+            }
         }
     }
 
@@ -20,3 +29,8 @@ impl log::Log for SimpleLogger {
 pub fn init_logger(level: LevelFilter) -> Result<(), SetLoggerError> {
     log::set_logger(&LOGGER).map(|()| log::set_max_level(level))
 }
+
+/// Sends log lines to the dashboard instead of stdout, or back with `false`
+pub fn log_to_activity(enabled: bool) {
+    TO_ACTIVITY.store(enabled, Ordering::Relaxed);
+}
@@ -1,78 +1,416 @@
 extern crate core;
 
+use crate::activity::ACTIVITY;
+use crate::api::Api;
+use crate::capture::Capture;
+use crate::config::{Backend, Config, MidiSource, USAGE};
+use crate::control::Control;
 use crate::logging::init_logger;
-use crate::midi::{process_signals, MidiMessageData};
+use crate::metrics::METRICS;
+use crate::midi::{process_signals, MidiMessageData, MidiReceiver};
+use crate::midi_device::{process_device, DeviceKind};
+use crate::rtp_midi::process_rtp;
+use crate::profile::Profile;
+use crate::shutdown::StopToken;
+use crate::state::LatestState;
+use crate::stats::LatencyStats;
+use crate::supervisor::{Supervisor, Worker};
 use crate::threads::controller::start_controller;
 use crate::threads::gadget::start_gadget;
+use crate::threads::http::start_http;
+use crate::threads::osc::start_osc;
+use crate::threads::learn::start_learn;
+use crate::threads::stats::start_stats;
+use crate::threads::tui::start_tui;
+use crate::threads::uinput::start_uinput;
+use crate::threads::watchdog::start_watchdog;
 use core::time;
-use log::LevelFilter;
+use log::{error, info};
+use std::error::Error;
 use std::fs::OpenOptions;
-use std::process::Command;
+use std::net::{TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
 
+mod activity;
+mod api;
+mod capture;
+mod clock;
+mod config;
+mod control;
+mod dashboard;
 mod device_file;
+mod hidraw;
+mod http;
+mod json;
+mod learn;
 mod logging;
+mod metrics;
 mod midi;
+mod midi_device;
+mod midi_parser;
 mod nscontroller;
+mod osc;
+mod pedals;
+mod profile;
+mod protocol;
+mod report;
+mod rtp_midi;
+mod shutdown;
+mod state;
+mod stats;
+mod supervisor;
+#[cfg(test)]
+mod test_support;
+mod timing;
 mod threads {
     pub mod gadget;
     pub mod controller;
+    pub mod http;
+    pub mod learn;
+    pub mod osc;
+    pub mod stats;
+    pub mod tui;
+    pub mod uinput;
+    pub mod watchdog;
 }
+mod uinput;
+mod ump;
+mod watchdog;
 
-fn reconnect_controller() {
+fn reconnect_controller() -> Result<(), Box<dyn Error>> {
     // Disconnect gadget from USB OTG port
     // echo > /sys/kernel/config/usb_gadget/procon/UDC
     {
         let gadget_file = OpenOptions::new()
             .write(true)
-            .open("/sys/kernel/config/usb_gadget/procon/UDC")
-            .unwrap();
-        let mut command_disconnect = Command::new("echo").stdout(gadget_file).spawn().unwrap();
-        command_disconnect.wait().unwrap();
+            .open("/sys/kernel/config/usb_gadget/procon/UDC")?;
+        let mut command_disconnect = Command::new("echo").stdout(gadget_file).spawn()?;
+        command_disconnect.wait()?;
     }
     // Connect gadget to USB OTG port
     // ls /sys/class/udc > /sys/kernel/config/usb_gadget/procon/UDC
     {
         let gadget_file = OpenOptions::new()
             .write(true)
-            .open("/sys/kernel/config/usb_gadget/procon/UDC")
-            .unwrap();
+            .open("/sys/kernel/config/usb_gadget/procon/UDC")?;
         let mut command_connect = Command::new("ls")
             .arg("/sys/class/udc")
             .stdout(gadget_file)
-            .spawn()
-            .unwrap();
-        command_connect.wait().unwrap();
+            .spawn()?;
+        command_connect.wait()?;
     }
     let wait_ms = time::Duration::from_millis(500);
     thread::sleep(wait_ms);
+    Ok(())
 }
 
 fn main() {
-    init_logger(LevelFilter::Info).unwrap();
+    // The logger follows --log-level, so errors of the command line go to stderr directly
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            process::exit(1);
+        }
+    };
+    init_logger(config.log_level).unwrap();
+    shutdown::install_signal_handlers();
+    stats::install_signal_handler();
+
+    let capture = config
+        .capture_path
+        .as_ref()
+        .map(|path| match Capture::new(path) {
+            Ok(capture) => Arc::new(capture),
+            Err(error) => {
+                error!("Unable to open capture file {}: {}", path, error);
+                process::exit(1);
+            }
+        });
+
+    // latest set of held notes, shared so restarted workers keep seeing the same MIDI state
+    let midi_state: Arc<LatestState<Vec<MidiMessageData>>> = Arc::new(LatestState::new(Vec::new()));
+    let worker_midi_state = midi_state.clone();
+    let stats = Arc::new(LatencyStats::new());
+    let worker_stats = stats.clone();
+
+    let profile_dir = config.profile_dir.as_ref().map(PathBuf::from);
+    let profiles = match load_profiles(&config.profile_paths, profile_dir.as_deref()) {
+        Ok(profiles) => profiles,
+        Err(error) => {
+            error!("{}", error);
+            process::exit(1);
+        }
+    };
+    let control = Arc::new(Control::new(profiles));
+    info!("Using profile {}", control.active_profile().name);
+    control.set_frame_timing(config.frame_timing);
+    let worker_control = control.clone();
+
+    if let Some(learn_path) = &config.learn_path {
+        let receiver = MidiReceiver::new(midi_state, stats, control.clone(), capture);
+        process::exit(learn(control, PathBuf::from(learn_path), &config.midi_source, receiver));
+    }
+
+    // thread answering the local HTTP API
+    let http_thread = config.http_address.as_ref().map(|address| {
+        let listener = match TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!("Unable to serve HTTP on {}: {}", address, error);
+                process::exit(1);
+            }
+        };
+        let api = Api {
+            midi_state: midi_state.clone(),
+            control: control.clone(),
+            stats: stats.clone(),
+            profile_dir: profile_dir.clone(),
+        };
+        thread::Builder::new()
+            .name(String::from("http"))
+            .spawn(move || start_http(listener, api))
+            .unwrap()
+    });
+
+    // thread applying OSC messages next to the MIDI input
+    let osc_thread = config.osc_address.as_ref().map(|address| {
+        let socket = match UdpSocket::bind(address) {
+            Ok(socket) => socket,
+            Err(error) => {
+                error!("Unable to listen for OSC on {}: {}", address, error);
+                process::exit(1);
+            }
+        };
+        let control = control.clone();
+        thread::Builder::new()
+            .name(String::from("osc"))
+            .spawn(move || start_osc(socket, control))
+            .unwrap()
+    });
+
+    // thread drawing the terminal dashboard, log lines are shown inside it
+    let tui_thread = config.tui.then(|| {
+        logging::log_to_activity(true);
+        ACTIVITY.set_enabled(true);
+        let control = control.clone();
+        let stats = stats.clone();
+        thread::Builder::new()
+            .name(String::from("tui"))
+            .spawn(move || start_tui(control, stats).map_err(|e| e.to_string()))
+            .unwrap()
+    });
+
+    // thread logging the latency statistics
+    let stats_thread = {
+        let stats = stats.clone();
+        let interval = config.stats_interval;
+        thread::Builder::new()
+            .name(String::from("stats"))
+            .spawn(move || start_stats(stats, interval))
+            .unwrap()
+    };
+
+    // thread releasing stuck notes
+    let watchdog_thread = {
+        let midi_state = midi_state.clone();
+        let control = control.clone();
+        let max_hold = config.max_hold;
+        let sensing_timeout = config.sensing_timeout;
+        thread::Builder::new()
+            .name(String::from("watchdog"))
+            .spawn(move || start_watchdog(midi_state, control, max_hold, sensing_timeout))
+            .unwrap()
+    };
+
+    // thread restarting the workers when they fail
+    let supervisor_capture = capture.clone();
+    let midi_source = config.midi_source.clone();
+    let supervisor = thread::Builder::new()
+        .name(String::from("supervisor"))
+        .spawn(move || {
+            let result = match config.backend {
+                Backend::Gadget => Supervisor::new("relay").run(|stop| {
+                    start_relay(
+                        worker_midi_state.clone(),
+                        worker_stats.clone(),
+                        worker_control.clone(),
+                        config.controller_path.clone(),
+                        supervisor_capture.clone(),
+                        stop,
+                    )
+                }),
+                Backend::Uinput => Supervisor::new("uinput").run(|stop| start_uinput_worker(worker_midi_state.clone(), worker_stats.clone(), worker_control.clone(), stop)),
+            };
+            // Without workers there is no point in processing MIDI
+            shutdown::request();
+            result
+        })
+        .unwrap();
+
+    let mut exit_code = 0;
+    let receiver = MidiReceiver::new(midi_state, stats, control.clone(), capture);
+    if let Err(error) = process_midi(&midi_source, receiver, control) {
+        error!("MIDI processing failed: {}", error);
+        exit_code = 1;
+    }
+
+    // Whatever stopped MIDI processing, the workers have to release all buttons and stop too
+    shutdown::request();
+    match supervisor.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => {
+            error!("{}", error);
+            exit_code = 1;
+        }
+        Err(_) => {
+            error!("Supervisor panicked");
+            exit_code = 1;
+        }
+    }
+
+    if stats_thread.join().is_err() {
+        error!("Stats thread panicked");
+    }
+    if watchdog_thread.join().is_err() {
+        error!("Watchdog thread panicked");
+    }
+    if let Some(tui_thread) = tui_thread {
+        let result = tui_thread.join();
+        logging::log_to_activity(false);
+        ACTIVITY.set_enabled(false);
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => error!("Dashboard failed: {}", error),
+            Err(_) => error!("Dashboard thread panicked"),
+        }
+    }
+    if let Some(http_thread) = http_thread {
+        if http_thread.join().is_err() {
+            error!("HTTP thread panicked");
+        }
+    }
+    if let Some(osc_thread) = osc_thread {
+        if osc_thread.join().is_err() {
+            error!("OSC thread panicked");
+        }
+    }
+
+    info!("Stopped with exit code {}", exit_code);
+    process::exit(exit_code);
+}
+
+/// The `--profile` files followed by the profiles of `--profile-dir`
+fn load_profiles(paths: &[String], dir: Option<&Path>) -> Result<Vec<Profile>, Box<dyn Error>> {
+    let mut profiles = paths
+        .iter()
+        .map(|path| Profile::load(Path::new(path)))
+        .collect::<Result<Vec<Profile>, Box<dyn Error>>>()?;
+    if let Some(dir) = dir {
+        profiles.extend(Profile::load_dir(dir)?);
+    }
+    Ok(profiles)
+}
+
+/// Reads the configured MIDI input until a shutdown is requested
+fn process_midi(source: &MidiSource, receiver: MidiReceiver, control: Arc<Control>) -> Result<(), Box<dyn Error>> {
+    match source {
+        MidiSource::Alsa => process_signals(1, receiver, control),
+        MidiSource::Serial(path) => process_device(Path::new(path), DeviceKind::Serial, receiver, control),
+        MidiSource::RawMidi(path) => process_device(Path::new(path), DeviceKind::RawMidi, receiver, control),
+        MidiSource::Ump(path) => process_device(Path::new(path), DeviceKind::Ump, receiver, control),
+        MidiSource::RtpMidi(port) => process_rtp(*port, receiver, control),
+    }
+}
+
+/// Terminal learn session, MIDI is processed but nothing is relayed
+fn learn(control: Arc<Control>, path: PathBuf, source: &MidiSource, receiver: MidiReceiver) -> i32 {
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    control.start_learning(&name);
+
+    // thread prompting for every target, MIDI processing stops once it is done
+    let learn_control = control.clone();
+    let learn_thread = thread::Builder::new()
+        .name(String::from("learn"))
+        .spawn(move || {
+            let result = start_learn(learn_control, path);
+            shutdown::request();
+            result.map_err(|e| e.to_string())
+        })
+        .unwrap();
+
+    let mut exit_code = 0;
+    if let Err(error) = process_midi(source, receiver, control) {
+        error!("MIDI processing failed: {}", error);
+        exit_code = 1;
+    }
+    shutdown::request();
+    match learn_thread.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => {
+            error!("{}", error);
+            exit_code = 1;
+        }
+        Err(_) => {
+            error!("Learn thread panicked");
+            exit_code = 1;
+        }
+    }
+    exit_code
+}
+
+fn start_relay(
+    midi_state: Arc<LatestState<Vec<MidiMessageData>>>,
+    stats: Arc<LatencyStats>,
+    control: Arc<Control>,
+    controller_path: Option<String>,
+    capture: Option<Arc<Capture>>,
+    stop: StopToken,
+) -> Result<Vec<Worker>, String> {
     // reconnect controller for host to send
     // init packets to the game controller
-    reconnect_controller();
+    reconnect_controller().map_err(|e| format!("Unable to reconnect USB gadget: {}", e))?;
+    METRICS.reconnect(metrics::Device::Gadget);
 
     // channels to control communication between gamepads
     let (tx_controller, rx_controller): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
     let (tx_gadget, rx_gadget): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
 
-    // channel to receive MidiMessageData
-    let (tx_midi, rx_midi): (Sender<Vec<MidiMessageData>>, Receiver<Vec<MidiMessageData>>) = mpsc::channel();
-
     // thread to process usb gadget data via gadgetfs
-    thread::Builder::new()
+    let gadget_capture = capture.clone();
+    let gadget_control = control.clone();
+    let gadget_stop = stop.clone();
+    let gadget = thread::Builder::new()
         .name(String::from("gadget"))
-        .spawn(move || start_gadget(tx_controller.clone(), rx_gadget, rx_midi).unwrap())
-        .unwrap();
+        .spawn(move || start_gadget(tx_controller, rx_gadget, midi_state, stats, gadget_control, gadget_capture, gadget_stop).map_err(|e| e.to_string()))
+        .map_err(|e| e.to_string())?;
     // thread to process usb controller
-    thread::Builder::new()
+    let controller = thread::Builder::new()
         .name(String::from("controller"))
-        .spawn(move || start_controller(tx_gadget.clone(), rx_controller).unwrap())
-        .unwrap();
+        .spawn(move || start_controller(tx_gadget, rx_controller, controller_path, capture, control, stop).map_err(|e| e.to_string()))
+        .map_err(|e| e.to_string())?;
+
+    Ok(vec![gadget, controller])
+}
 
-    process_signals(1, tx_midi).unwrap();
+fn start_uinput_worker(
+    midi_state: Arc<LatestState<Vec<MidiMessageData>>>,
+    stats: Arc<LatencyStats>,
+    control: Arc<Control>,
+    stop: StopToken,
+) -> Result<Vec<Worker>, String> {
+    // thread to feed a local virtual gamepad instead of the Switch
+    let uinput = thread::Builder::new()
+        .name(String::from("uinput"))
+        .spawn(move || start_uinput(midi_state, stats, control, stop).map_err(|e| e.to_string()))
+        .map_err(|e| e.to_string())?;
+    Ok(vec![uinput])
 }
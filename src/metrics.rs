@@ -0,0 +1,303 @@
+use crate::midi_parser::MidiMessage;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Prefix of every exported metric
+pub const PREFIX: &str = "midi_to_switch";
+
+/// Upper bounds of the latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.002, 0.004, 0.008, 0.016, 0.032, 0.064, 0.128, 0.256];
+
+const MIDI_TYPES: [&str; 11] = [
+    "note_off",
+    "note_on",
+    "polyphonic_pressure",
+    "control_change",
+    "program_change",
+    "channel_pressure",
+    "pitch_bend",
+    "system_exclusive",
+    "system_common",
+    "realtime",
+    "invalid",
+];
+
+/// Counters of the whole service
+///
+/// Like the registries of the usual Prometheus clients the counters are global,
+/// so every trace point can count without another handle threaded through it.
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReportDirection {
+    ToConsole = 0,
+    ToController = 1,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Device {
+    Gadget = 0,
+    Controller = 1,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operation {
+    Read = 0,
+    Write = 1,
+}
+
+pub struct Metrics {
+    midi_messages: [AtomicU64; MIDI_TYPES.len()],
+    reports: [AtomicU64; 2],
+    reports_modified: AtomicU64,
+    /// Indexed by device * 2 + operation
+    device_errors: [AtomicU64; 4],
+    reconnects: [AtomicU64; 2],
+    handshakes: AtomicU64,
+    /// Not cumulative, rendering adds them up
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            midi_messages: [const { AtomicU64::new(0) }; MIDI_TYPES.len()],
+            reports: [const { AtomicU64::new(0) }; 2],
+            reports_modified: AtomicU64::new(0),
+            device_errors: [const { AtomicU64::new(0) }; 4],
+            reconnects: [const { AtomicU64::new(0) }; 2],
+            handshakes: AtomicU64::new(0),
+            latency_buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            latency_sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// MIDI message received, None when it could not be decoded
+    pub fn midi_message(&self, message: Option<&MidiMessage>) {
+        let index = match message {
+            Some(MidiMessage::NoteOff { .. }) => 0,
+            Some(MidiMessage::NoteOn { .. }) => 1,
+            Some(MidiMessage::PolyphonicPressure { .. }) => 2,
+            Some(MidiMessage::ControlChange { .. }) => 3,
+            Some(MidiMessage::ProgramChange { .. }) => 4,
+            Some(MidiMessage::ChannelPressure { .. }) => 5,
+            Some(MidiMessage::PitchBend { .. }) => 6,
+            Some(MidiMessage::SystemExclusive(_)) => 7,
+            Some(message) if message.is_realtime() => 9,
+            Some(_) => 8,
+            None => 10,
+        };
+        self.midi_messages[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report_forwarded(&self, direction: ReportDirection) {
+        self.reports[direction as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report_modified(&self) {
+        self.reports_modified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn device_error(&self, device: Device, operation: Operation) {
+        self.device_errors[device as usize * 2 + operation as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnect(&self, device: Device) {
+        self.reconnects[device as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The console finished the USB handshake with the relay
+    pub fn handshake_completed(&self) {
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_latency(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn midi_messages_total(&self) -> u64 {
+        self.midi_messages.iter().map(|counter| counter.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn reports_forwarded_total(&self, direction: ReportDirection) -> u64 {
+        self.reports[direction as usize].load(Ordering::Relaxed)
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self, out: &mut String) {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+
+        let midi: Vec<(String, String)> = MIDI_TYPES
+            .iter()
+            .zip(self.midi_messages.iter())
+            .map(|(kind, counter)| (format!("type=\"{}\"", kind), load(counter)))
+            .collect();
+        family(out, "midi_messages_total", "counter", "MIDI messages received by type", &midi);
+
+        family(
+            out,
+            "reports_forwarded_total",
+            "counter",
+            "Reports relayed between console and controller",
+            &[
+                (String::from("direction=\"to_console\""), load(&self.reports[0])),
+                (String::from("direction=\"to_controller\""), load(&self.reports[1])),
+            ],
+        );
+        family(
+            out,
+            "reports_modified_total",
+            "counter",
+            "Input reports changed by MIDI or the API",
+            &[(String::new(), load(&self.reports_modified))],
+        );
+
+        let mut errors = Vec::new();
+        for (device_index, device) in ["gadget", "controller"].iter().enumerate() {
+            for (operation_index, operation) in ["read", "write"].iter().enumerate() {
+                errors.push((
+                    format!("device=\"{}\",operation=\"{}\"", device, operation),
+                    load(&self.device_errors[device_index * 2 + operation_index]),
+                ));
+            }
+        }
+        family(out, "device_errors_total", "counter", "Failed device reads and writes", &errors);
+
+        family(
+            out,
+            "reconnects_total",
+            "counter",
+            "Gadget reconnects to the console and controller re-attaches",
+            &[
+                (String::from("device=\"gadget\""), load(&self.reconnects[0])),
+                (String::from("device=\"controller\""), load(&self.reconnects[1])),
+            ],
+        );
+        family(
+            out,
+            "handshakes_total",
+            "counter",
+            "USB handshakes completed by the console",
+            &[(String::new(), load(&self.handshakes))],
+        );
+
+        let mut latency = Vec::new();
+        let mut cumulative = 0;
+        for (bound, counter) in LATENCY_BUCKETS.iter().zip(self.latency_buckets.iter()) {
+            cumulative += counter.load(Ordering::Relaxed);
+            latency.push((format!("le=\"{}\"", bound), cumulative.to_string()));
+        }
+        cumulative += self.latency_buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        latency.push((String::from("le=\"+Inf\""), cumulative.to_string()));
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+        let name = format!("{}_latency_seconds", PREFIX);
+        let _ = writeln!(out, "# HELP {} Time from a MIDI event to the first report carrying it", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (labels, value) in latency {
+            let _ = writeln!(out, "{}_bucket{{{}}} {}", name, labels, value);
+        }
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+/// Writes one metric family, `samples` are (labels, value) pairs, labels may be empty
+pub fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, String)]) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+        } else {
+            let _ = writeln!(out, "{}_{}{{{}}} {}", PREFIX, name, labels, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(metrics: &Metrics) -> String {
+        let mut out = String::new();
+        metrics.render(&mut out);
+        out
+    }
+
+    #[test]
+    fn counts_midi_messages_by_type() {
+        let metrics = Metrics::new();
+        let note_on = MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 };
+        metrics.midi_message(Some(&note_on));
+        metrics.midi_message(Some(&note_on));
+        metrics.midi_message(Some(&MidiMessage::PitchBend { channel: 0, value: 0x2000 }));
+        metrics.midi_message(Some(&MidiMessage::TimingClock));
+        metrics.midi_message(Some(&MidiMessage::SongSelect(1)));
+        metrics.midi_message(None);
+        assert_eq!(metrics.midi_messages_total(), 6);
+
+        let out = rendered(&metrics);
+        assert!(out.contains("# TYPE midi_to_switch_midi_messages_total counter\n"));
+        assert!(out.contains("midi_to_switch_midi_messages_total{type=\"note_on\"} 2\n"));
+        assert!(out.contains("midi_to_switch_midi_messages_total{type=\"pitch_bend\"} 1\n"));
+        assert!(out.contains("midi_to_switch_midi_messages_total{type=\"realtime\"} 1\n"));
+        assert!(out.contains("midi_to_switch_midi_messages_total{type=\"system_common\"} 1\n"));
+        assert!(out.contains("midi_to_switch_midi_messages_total{type=\"system_exclusive\"} 0\n"));
+        assert!(out.contains("midi_to_switch_midi_messages_total{type=\"invalid\"} 1\n"));
+        assert!(out.contains("midi_to_switch_midi_messages_total{type=\"note_off\"} 0\n"));
+    }
+
+    #[test]
+    fn counts_reports_errors_and_reconnects() {
+        let metrics = Metrics::new();
+        metrics.report_forwarded(ReportDirection::ToController);
+        metrics.report_modified();
+        metrics.device_error(Device::Controller, Operation::Write);
+        metrics.reconnect(Device::Controller);
+        metrics.handshake_completed();
+
+        assert_eq!(metrics.reports_forwarded_total(ReportDirection::ToController), 1);
+        let out = rendered(&metrics);
+        assert!(out.contains("midi_to_switch_reports_forwarded_total{direction=\"to_console\"} 0\n"));
+        assert!(out.contains("midi_to_switch_reports_forwarded_total{direction=\"to_controller\"} 1\n"));
+        assert!(out.contains("midi_to_switch_reports_modified_total 1\n"));
+        assert!(out.contains("midi_to_switch_device_errors_total{device=\"controller\",operation=\"write\"} 1\n"));
+        assert!(out.contains("midi_to_switch_device_errors_total{device=\"gadget\",operation=\"read\"} 0\n"));
+        assert!(out.contains("midi_to_switch_reconnects_total{device=\"controller\"} 1\n"));
+        assert!(out.contains("midi_to_switch_handshakes_total 1\n"));
+    }
+
+    #[test]
+    fn latency_histogram_is_cumulative() {
+        let metrics = Metrics::new();
+        metrics.observe_latency(Duration::from_micros(300));
+        metrics.observe_latency(Duration::from_millis(3));
+        metrics.observe_latency(Duration::from_secs(1));
+
+        let out = rendered(&metrics);
+        assert!(out.contains("# TYPE midi_to_switch_latency_seconds histogram\n"));
+        assert!(out.contains("midi_to_switch_latency_seconds_bucket{le=\"0.0005\"} 1\n"));
+        assert!(out.contains("midi_to_switch_latency_seconds_bucket{le=\"0.002\"} 1\n"));
+        assert!(out.contains("midi_to_switch_latency_seconds_bucket{le=\"0.004\"} 2\n"));
+        assert!(out.contains("midi_to_switch_latency_seconds_bucket{le=\"0.256\"} 2\n"));
+        assert!(out.contains("midi_to_switch_latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("midi_to_switch_latency_seconds_sum 1.0033\n"));
+        assert!(out.contains("midi_to_switch_latency_seconds_count 3\n"));
+    }
+}
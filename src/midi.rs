@@ -1,16 +1,35 @@
 use std::convert::TryFrom;
 use std::error::Error;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::Sender;
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 
 use midir::{Ignore, MidiInput};
 
-/// This thread has infinite loop in the end to process midi forever
-pub fn process_signals(position: usize, tx: Sender<Vec<MidiMessageData>>) -> Result<(), Box<dyn Error>> {
+use crate::activity::ACTIVITY;
+use crate::capture::{self, Capture, Direction, Interface};
+use crate::clock::Quantizer;
+use crate::control::Control;
+use crate::learn::Outcome;
+use crate::midi_parser::{MidiMessage, MidiParser};
+use crate::metrics::METRICS;
+use crate::pedals::Pedals;
+use crate::shutdown;
+use crate::state::LatestState;
+use crate::stats::LatencyStats;
+use crate::ump::{self, UmpMessage, UmpParser};
+
+/// Channel mode controller that silences a synthesizer at once, releases every note here
+pub const ALL_SOUND_OFF: u8 = 120;
+/// Channel mode controller that puts every controller back to its default
+pub const RESET_ALL_CONTROLLERS: u8 = 121;
+/// Channel mode controller releasing every note, Omni and Mono/Poly changes after it do too
+pub const ALL_NOTES_OFF: u8 = 123;
+
+/// This thread processes midi until a shutdown is requested
+pub fn process_signals(position: usize, mut receiver: MidiReceiver, control: Arc<Control>) -> Result<(), Box<dyn Error>> {
     let mut midi_in = MidiInput::new("midir reading input")?;
     midi_in.ignore(Ignore::None);
 
@@ -39,85 +58,252 @@ pub fn process_signals(position: usize, tx: Sender<Vec<MidiMessageData>>) -> Res
     let in_port_name = midi_in.port_name(in_port)?;
     info!("Connecting to {}", in_port_name);
 
-    // Make the persistent note-on message list shared and thread-safe so it can be
-    // inspected or modified from multiple places in future refactors.
-    let midi_note_on_messages: Arc<Mutex<Vec<MidiMessageData>>> = Arc::new(Mutex::new(Vec::new()));
-    let state_for_callback = midi_note_on_messages.clone();
-
-    // _conn_in needs to be a named parameter, because it needs to be kept alive until the end of the scope
-    let _conn_in = midi_in.connect(
+    // conn_in needs to be a named parameter, because it needs to be kept alive until shutdown
+    let conn_in = midi_in.connect(
         in_port,
         "midir-read-input",
-        move |_, message: &[u8], _| {
-            // Grab a local copy of the persistent state, run the pure processing
-            // logic (process_callback) and then store the updated state back into
-            // the mutex. This keeps the mutex held only for the clone/replace
-            // and leaves the processing and sends inside process_callback to run
-            // without holding the lock for the whole time.
-            let persistent = {
-                match state_for_callback.lock() {
-                    Ok(guard) => guard.clone(),
-                    Err(poisoned) => poisoned.into_inner().clone(),
+        move |_, message: &[u8], _| receiver.receive(message),
+        (),
+    )?;
+
+    control.set_midi(Some(in_port_name.clone()));
+
+    while !shutdown::requested() {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    control.set_midi(None);
+
+    info!("Closing connection to {}", in_port_name);
+    conn_in.close();
+    Ok(())
+}
+
+
+/// Turns raw MIDI bytes from any input into the shared MIDI state
+///
+/// Every input (midir, serial, rawmidi) owns one, so running status and
+/// unfinished messages are kept per input. Clock messages drive the shared
+/// MIDI clock, notes wait for its grid while the active profile quantizes.
+/// Piano pedals hold released notes when the profile asks for it.
+/// A System Reset releases all notes and controllers, Active Sensing feeds the watchdog.
+pub struct MidiReceiver {
+    parser: MidiParser,
+    ump_parser: UmpParser,
+    quantizer: Quantizer,
+    pedals: Pedals,
+    state: Arc<LatestState<Vec<MidiMessageData>>>,
+    stats: Arc<LatencyStats>,
+    control: Arc<Control>,
+    capture: Option<Arc<Capture>>,
+}
+
+impl MidiReceiver {
+    pub fn new(
+        state: Arc<LatestState<Vec<MidiMessageData>>>,
+        stats: Arc<LatencyStats>,
+        control: Arc<Control>,
+        capture: Option<Arc<Capture>>,
+    ) -> MidiReceiver {
+        MidiReceiver {
+            parser: MidiParser::new(),
+            ump_parser: UmpParser::new(),
+            quantizer: Quantizer::default(),
+            pedals: Pedals::default(),
+            state,
+            stats,
+            control,
+            capture,
+        }
+    }
+
+    /// Bytes as they arrived, any number of messages or a part of one
+    pub fn receive(&mut self, bytes: &[u8]) {
+        let arrived = Instant::now();
+        self.forget_released();
+        capture::record(&self.capture, Interface::Midi, Direction::Inbound, bytes, None);
+        let messages = decode(&mut self.parser, bytes);
+        let mut data = Vec::new();
+        for message in messages.iter() {
+            let entry = self.observe(message);
+            self.schedule(Some(message), entry, arrived, &mut data);
+        }
+        self.update(&data, arrived);
+    }
+
+    /// Universal MIDI Packet words as they arrived, a packet may continue in the next call
+    ///
+    /// MIDI 2.0 messages reach the state at full resolution, the dashboard,
+    /// the metrics and learn sessions see them downconverted to MIDI 1.0.
+    pub fn receive_ump(&mut self, words: &[u32]) {
+        let arrived = Instant::now();
+        self.forget_released();
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        capture::record(&self.capture, Interface::Midi, Direction::Inbound, &bytes, None);
+        let mut data = Vec::new();
+        for message in self.ump_parser.parse(words) {
+            let midi1 = message.to_midi1();
+            if let Some(midi_message) = &midi1 {
+                METRICS.midi_message(Some(midi_message));
+            }
+            match message {
+                UmpMessage::Midi1(midi_message) => {
+                    let entry = self.observe(&midi_message);
+                    self.schedule(Some(&midi_message), entry, arrived, &mut data);
                 }
-            };
-
-            match process_callback(message, persistent, tx.clone()) {
-                Ok(value) => {
-                    // Replace the contents of the mutex with the updated state
-                    match state_for_callback.lock() {
-                        Ok(mut guard) => *guard = value,
-                        Err(poisoned) => {
-                            let mut guard = poisoned.into_inner();
-                            *guard = value;
-                        }
+                UmpMessage::Midi2 { message, .. } => {
+                    if let Some(midi_message) = &midi1 {
+                        self.observe(midi_message);
                     }
-                }
-                Err(error) => {
-                    error!("Error processing callback: {}", error);
+                    self.schedule(midi1.as_ref(), message.to_data(), arrived, &mut data);
                 }
             }
-        },
-        (),
-    )?;
+        }
+        self.update(&data, arrived);
+    }
 
-    loop {
-        thread::sleep(Duration::from_millis(1));
+    /// Notes the watchdog released are no longer down or held by a pedal
+    fn forget_released(&mut self) {
+        let released = self.control.take_released_notes();
+        if !released.is_empty() {
+            self.pedals.forget(&released);
+        }
+        if self.control.take_released_all() {
+            self.quantizer.clear();
+        }
+    }
+
+    /// Shows a message on the dashboard and hands it to a learn session,
+    /// returns its entry for the state
+    fn observe(&self, midi_message: &MidiMessage) -> Option<MidiMessageData> {
+        ACTIVITY.midi_message(midi_message);
+        let midi_data = MidiMessageData::from_message(midi_message)?;
+        match self.control.learn(&midi_data) {
+            Some(Outcome::Assigned(source, target)) => info!("Learned {} = {}", source, target.name()),
+            Some(Outcome::Conflict(source, target)) => {
+                warn!("{} already drives {}, try another one", source, target.name())
+            }
+            _ => {}
+        }
+        if let Some(octave) = self.control.shift_octave(&midi_data) {
+            info!("Keyboard shifted by {:+} octaves", octave);
+        }
+        Some(midi_data)
+    }
+
+    /// Adds the state entry of a message to `data`, held back while quantizing.
+    /// A clock pulse on the grid adds the notes that waited for it.
+    fn schedule(&mut self, message: Option<&MidiMessage>, entry: Option<MidiMessageData>, arrived: Instant, data: &mut Vec<MidiMessageData>) {
+        if let Some(event) = message.and_then(|message| self.control.clock_message(message, arrived)) {
+            self.quantizer.clock(event, self.control.quantize(), data);
+        }
+        let entries = match message {
+            Some(MidiMessage::ActiveSensing) => {
+                self.control.active_sensing(arrived);
+                Vec::new()
+            }
+            // Back to power-up state, as if every channel got both channel mode messages
+            Some(MidiMessage::Reset) => {
+                info!("MIDI reset, releasing all notes and controllers");
+                vec![
+                    MidiMessageData::midi1(MidiMessageTypes::ControlChange, ALL_NOTES_OFF, 0),
+                    MidiMessageData::midi1(MidiMessageTypes::ControlChange, RESET_ALL_CONTROLLERS, 0),
+                ]
+            }
+            _ => entry.into_iter().collect(),
+        };
+        let sustain = self.control.sustain();
+        let grid = self.control.quantize();
+        for midi_data in entries {
+            let mut pedaled = Vec::new();
+            self.pedals.apply(midi_data, sustain, &mut pedaled);
+            for midi_data in pedaled {
+                self.quantizer.push(midi_data, grid, data);
+            }
+        }
+    }
+
+    fn update(&self, data: &[MidiMessageData], arrived: Instant) {
+        // The shared state always holds the complete set of held notes. Other inputs,
+        // releases through the HTTP API and the watchdog write it too, so the entries
+        // are applied to the current state in one atomic update. The event is
+        // registered before the state lock is released, so no report can carry the
+        // generation before the statistics know about it
+        self.control.record_presses(data);
+        self.control.set_pedal_held(self.pedals.held());
+        self.state.update(|held, generation| {
+            let updated = process_messages(data, held)?;
+            self.stats.midi_event(generation, arrived);
+            Some(updated)
+        });
+    }
+
+    /// Forgets held notes and any unfinished message, for inputs that went away
+    pub fn release_all(&mut self) {
+        self.parser = MidiParser::new();
+        self.quantizer.clear();
+        self.pedals.clear();
+        self.control.set_pedal_held(Vec::new());
+        self.state.update(|held, _| (!held.is_empty()).then(Vec::new));
     }
 }
 
+/// Decodes the bytes of one callback, counting every message and logging malformed input
+///
+/// The parser keeps running status and unfinished messages between callbacks.
+pub(crate) fn decode(parser: &mut MidiParser, bytes: &[u8]) -> Vec<MidiMessage> {
+    let mut messages = Vec::new();
+    for parsed in parser.parse(bytes) {
+        match parsed {
+            Ok(midi_message) => {
+                METRICS.midi_message(Some(&midi_message));
+                messages.push(midi_message);
+            }
+            Err(error) => {
+                METRICS.midi_message(None);
+                error!("Invalid MIDI input {:02X?}: {}", bytes, error);
+            }
+        }
+    }
+    messages
+}
 
-/// Processes a single incoming MIDI message, updates the current message state,
-/// and forwards the updated state to the given channel.
+/// Applies decoded MIDI messages to the current message state.
 ///
 /// # Parameters
-/// - `message`: A slice containing exactly three bytes of MIDI data conforming to MIDI standards
-///   The slice is expected to have length ≥ 3; the first three bytes are used.
+/// - `messages`: Entries of one callback, only notes, controllers and per-note pitch bend change the state
 /// - `current_messages`: The current collection of active `MidiMessageData` entries.
-/// - `tx`: A `Sender` over which the updated list of MIDI messages is sent.
 ///
 /// # Returns
-/// On success, returns the updated vector of `MidiMessageData` that represents
-/// the current active MIDI state after applying the given `message`.
-///
-/// # Errors
-/// Returns an error if:
-/// - `message` does not have the correct number of bytes
-/// - The raw `message` bytes cannot be converted into a valid `MidiMessageData`
-///   instance (as determined by `MidiMessageData::new`), or
-/// - Sending the updated MIDI message list on `tx` fails (for either of the
-///   two send attempts).
-pub(crate) fn process_callback(message: &[u8], current_messages: Vec<MidiMessageData>, tx: Sender<Vec<MidiMessageData>>) -> Result<Vec<MidiMessageData>, Box<dyn Error>> {
-    if message.len() < 3 {
-        return Err(format!("MIDI message too short: expected at least 3 bytes, got {}", message.len()).into());
-    }
-
-    let mut return_messages = current_messages;
-    let midi_data = MidiMessageData::new(message[0], message[1], message[2])?;
+/// The updated vector of `MidiMessageData` that represents the current active
+/// MIDI state after applying the given `messages`, None when nothing changed.
+/// Replacing the whole state means a later release always
+/// supersedes the press, no matter when the gadget thread looks.
+pub(crate) fn process_messages(messages: &[MidiMessageData], current_messages: &[MidiMessageData]) -> Option<Vec<MidiMessageData>> {
+    let mut return_messages = current_messages.to_vec();
+    for midi_data in messages.iter() {
+        apply(midi_data, &mut return_messages);
+    }
+    (return_messages != current_messages).then_some(return_messages)
+}
+
+/// Updates held notes, controller values and per-note pitch bends with one channel voice message
+fn apply(midi_data: &MidiMessageData, return_messages: &mut Vec<MidiMessageData>) {
+    if midi_data.is_channel_mode() {
+        // Channel mode messages are commands, they are never kept as controller values
+        if midi_data.releases_all_notes() {
+            return_messages.retain(|x| x.is_control_change());
+        } else if midi_data.data_byte1 == RESET_ALL_CONTROLLERS {
+            return_messages.retain(|x| !x.is_control_change());
+        }
+        return;
+    }
+
     if midi_data.should_add_midi_message() {
         // Only add if note does not already exist
         if !return_messages
             .iter()
-            .any(|x| x.data_byte1 == midi_data.data_byte1)
+            .any(|x| x.is_note() && x.data_byte1 == midi_data.data_byte1)
         {
             return_messages.push(midi_data.clone());
         }
@@ -126,15 +312,29 @@ pub(crate) fn process_callback(message: &[u8], current_messages: Vec<MidiMessage
     if midi_data.should_remove_midi_message() {
         // Currently all MIDI channels will be "squished" in the
         // output to controller, so no need to filter by channel
+        // The bend of the note ends with it
         trace!("removing <- {:#04X?}", midi_data.data_byte1);
-        return_messages.retain(|x| x.data_byte1 != midi_data.data_byte1);
+        return_messages.retain(|x| x.is_control_change() || x.data_byte1 != midi_data.data_byte1);
     }
 
-    // Send twice to ensure Gadget thread picks up the message
-    tx.send(return_messages.clone()).map_err(|e| -> Box<dyn Error> { format!("failed to send MIDI messages (1st send): {e}").into() })?;
-    tx.send(return_messages.clone()).map_err(|e| -> Box<dyn Error> { format!("failed to send MIDI messages (2nd send): {e}").into() })?;
+    if midi_data.is_control_change() {
+        // Controllers keep their latest value, e.g. for a stick mapped to the mod wheel
+        match return_messages
+            .iter_mut()
+            .find(|x| x.is_control_change() && x.data_byte1 == midi_data.data_byte1)
+        {
+            Some(existing) => *existing = midi_data.clone(),
+            None => return_messages.push(midi_data.clone()),
+        }
+    }
 
-    Ok(return_messages)
+    if midi_data.status_byte == MidiMessageTypes::PerNotePitchBend {
+        // Only held notes bend, the entry goes away with the note
+        if return_messages.iter().any(|x| x.is_note() && x.data_byte1 == midi_data.data_byte1) {
+            return_messages.retain(|x| x.status_byte != MidiMessageTypes::PerNotePitchBend || x.data_byte1 != midi_data.data_byte1);
+            return_messages.push(midi_data.clone());
+        }
+    }
 }
 
 // Structure to store MIDI data packet
@@ -154,11 +354,14 @@ pub(crate) fn process_callback(message: &[u8], current_messages: Vec<MidiMessage
 // Pitch Bend                    Ex      MSB                 LSB
 // ```
 
-#[derive(Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct MidiMessageData {
     pub status_byte: MidiMessageTypes,
     pub data_byte1: u8,
     pub data_byte2: u8,
+    /// `data_byte2` at 32 bit, MIDI 2.0 sources fill in the full resolution,
+    /// MIDI 1.0 values are upscaled so 64 is exactly the center
+    pub value: u32,
 }
 
 impl MidiMessageData {
@@ -167,11 +370,24 @@ impl MidiMessageData {
             Ok(v) => v,
             Err(_) => return Err("Incorrect MidiMessageType".into()),
         };
-        Ok(MidiMessageData {
-            status_byte: midi_type,
-            data_byte1: byte1,
-            data_byte2: byte2,
-        })
+        Ok(MidiMessageData::midi1(midi_type, byte1, byte2))
+    }
+
+    /// Entry with a 7 bit MIDI 1.0 value
+    pub fn midi1(status_byte: MidiMessageTypes, data_byte1: u8, data_byte2: u8) -> MidiMessageData {
+        MidiMessageData {
+            status_byte,
+            data_byte1,
+            data_byte2,
+            value: ump::scale_up(data_byte2 as u32 & 0x7F, 7, 32),
+        }
+    }
+
+    /// Channel voice messages without their channel, all channels share one state
+    pub fn from_message(message: &MidiMessage) -> Option<MidiMessageData> {
+        message.channel()?;
+        let bytes = message.to_bytes();
+        MidiMessageData::new(bytes[0], bytes[1], bytes.get(2).copied().unwrap_or(0)).ok()
     }
 
     /// status is NoteOn AND velocity (data_byte2) IS NOT 0
@@ -181,6 +397,27 @@ impl MidiMessageData {
             && self.data_byte2 != 0x00u8
     }
 
+    /// Status is NoteOn, the state only keeps notes while they are held
+    pub fn is_note(&self) -> bool {
+        self.status_byte == MidiMessageTypes::NoteOn
+    }
+
+    /// Status is ControlChange, the state keeps the latest value of every controller
+    /// next to the held notes
+    pub fn is_control_change(&self) -> bool {
+        self.status_byte == MidiMessageTypes::ControlChange
+    }
+
+    /// Controllers 120 to 127 are channel mode messages instead of controller values
+    pub fn is_channel_mode(&self) -> bool {
+        self.is_control_change() && self.data_byte1 >= ALL_SOUND_OFF
+    }
+
+    /// All Sound Off, All Notes Off and the Omni and Mono/Poly changes that imply it
+    pub fn releases_all_notes(&self) -> bool {
+        self.is_channel_mode() && (self.data_byte1 == ALL_SOUND_OFF || self.data_byte1 >= ALL_NOTES_OFF)
+    }
+
     /// Status is NoteOff OR Status is NoteOn and 
     /// velocity (data_byte2) is 0
     /// (0 is equivalent to NoteOff per MIDI standard)
@@ -199,6 +436,8 @@ pub enum MidiMessageTypes {
     ProgramChange = 0xC,
     ChannelPressure = 0xD,
     PitchBend = 0xE,
+    /// MIDI 2.0 only, never parsed from MIDI 1.0 bytes
+    PerNotePitchBend = 0x6,
 }
 
 impl TryFrom<u8> for MidiMessageTypes {
@@ -225,7 +464,13 @@ impl TryFrom<u8> for MidiMessageTypes {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::mpsc;
+    use crate::test_support;
+
+    /// Receiver with the default profile writing a state that starts with `entries`
+    fn receiver(entries: Vec<MidiMessageData>) -> (MidiReceiver, Arc<LatestState<Vec<MidiMessageData>>>) {
+        let state = test_support::midi_state(entries);
+        (test_support::receiver(&state, &Arc::new(Control::new(Vec::new()))), state)
+    }
 
     #[test]
     fn should_add_midi_message_cases() {
@@ -251,101 +496,224 @@ mod tests {
     }
 
     #[test]
-    fn process_callback_adds_message_and_sends() {
-        let (tx, rx) = mpsc::channel();
-        let persistent: Vec<MidiMessageData> = Vec::new();
+    fn process_callback_adds_message_and_publishes() {
+        let (mut receiver, state) = receiver(Vec::new());
         let msg = [(MidiMessageTypes::NoteOn as u8) << 4, 0x3C, 0x40];
 
-        let res = process_callback(&msg, persistent, tx).expect("callback failed");
-        // returned state should contain the note
-        assert_eq!(res.len(), 1);
-        assert_eq!(res[0].data_byte1, 0x3C);
-
-        // two sends were made; both should contain one element with the same note
-        let first = rx.recv().expect("no first send");
-        let second = rx.recv().expect("no second send");
-        assert_eq!(first.len(), 1);
-        assert_eq!(second.len(), 1);
-        assert_eq!(first[0].data_byte1, 0x3C);
+        receiver.receive(&msg);
+        // exactly one publish with the note
+        let (published, generation) = state.get();
+        assert_eq!(generation, 1);
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].data_byte1, 0x3C);
     }
 
     #[test]
     fn process_callback_does_not_add_duplicate_messages() {
-        let (tx, rx) = mpsc::channel();
-        // persistent already contains the note
+        // the state already contains the note
         let existing = MidiMessageData::new((MidiMessageTypes::NoteOn as u8) << 4, 0x3C, 0x40).unwrap();
-        let persistent = vec![existing.clone()];
+        let (mut receiver, state) = receiver(vec![existing]);
         let msg = [(MidiMessageTypes::NoteOn as u8) << 4, 0x3C, 0x40];
 
-        let res = process_callback(&msg, persistent, tx).expect("callback failed");
-        // should not duplicate
-        assert_eq!(res.len(), 1);
-        let first = rx.recv().expect("no first send");
-        let second = rx.recv().expect("no second send");
-        assert_eq!(first.len(), 1);
-        assert_eq!(second.len(), 1);
+        receiver.receive(&msg);
+        // should not duplicate, and nothing changed so nothing is published
+        let (held, generation) = state.get();
+        assert_eq!(held.len(), 1);
+        assert_eq!(generation, 0);
     }
 
     #[test]
     fn process_callback_remove_not_present_no_error() {
-        let (tx, rx) = mpsc::channel();
-        let persistent: Vec<MidiMessageData> = Vec::new();
+        let (mut receiver, state) = receiver(Vec::new());
         let msg = [(MidiMessageTypes::NoteOff as u8) << 4, 0x3C, 0x00];
 
-        let res = process_callback(&msg, persistent, tx).expect("callback failed");
-        assert!(res.is_empty());
-        // two sends of empty vectors
-        let first = rx.recv().expect("no first send");
-        let second = rx.recv().expect("no second send");
-        assert!(first.is_empty());
-        assert!(second.is_empty());
+        receiver.receive(&msg);
+        assert_eq!(state.get(), (Vec::new(), 0));
     }
 
     #[test]
     fn process_callback_remove_present() {
-        let (tx, rx) = mpsc::channel();
         let existing = MidiMessageData::new((MidiMessageTypes::NoteOn as u8) << 4, 0x3C, 0x40).unwrap();
-        let persistent = vec![existing];
+        let (mut receiver, state) = receiver(vec![existing]);
         let msg = [(MidiMessageTypes::NoteOff as u8) << 4, 0x3C, 0x00];
 
-        let res = process_callback(&msg, persistent, tx).expect("callback failed");
-        assert!(res.is_empty());
-        let first = rx.recv().expect("no first send");
-        let second = rx.recv().expect("no second send");
-        assert!(first.is_empty());
-        assert!(second.is_empty());
+        receiver.receive(&msg);
+        let (published, generation) = state.get();
+        assert_eq!(generation, 1);
+        assert!(published.is_empty());
     }
 
     #[test]
     fn process_callback_persistence_across_iterations() {
+        let (mut receiver, state) = receiver(Vec::new());
         // First call: add note
-        let (tx1, rx1) = mpsc::channel();
-        let persistent: Vec<MidiMessageData> = Vec::new();
         let add_msg = [(MidiMessageTypes::NoteOn as u8) << 4, 0x3C, 0x40];
-        let res1 = process_callback(&add_msg, persistent, tx1).expect("callback failed");
-        assert_eq!(res1.len(), 1);
-        // drain sends
-        let _ = rx1.recv().unwrap();
-        let _ = rx1.recv().unwrap();
-
-        // Second call: no relevant midi message (ControlChange) but state should persist
-        let (tx2, rx2) = mpsc::channel();
+        receiver.receive(&add_msg);
+        assert_eq!(state.get().0.len(), 1);
+
+        // Second call: a controller value (ControlChange) is added but the note should persist
         let heartbeat = [(MidiMessageTypes::ControlChange as u8) << 4, 0x01, 0x7F];
-        let res2 = process_callback(&heartbeat, res1.clone(), tx2).expect("callback failed");
-        // res2 should still contain the previously added note
-        assert_eq!(res2.len(), 1);
-        let first = rx2.recv().expect("no first send");
-        assert_eq!(first.len(), 1);
+        receiver.receive(&heartbeat);
+        let (held, _) = state.get();
+        assert_eq!(held.len(), 2);
+        assert!(held.iter().any(|x| !x.is_control_change() && x.data_byte1 == 0x3C));
+    }
+
+    #[test]
+    fn process_callback_keeps_latest_controller_value() {
+        let (mut receiver, state) = receiver(Vec::new());
+        let modulation = |value| [(MidiMessageTypes::ControlChange as u8) << 4, 0x01, value];
+        // Note 0x01 and controller 0x01 are independent
+        let note = [(MidiMessageTypes::NoteOn as u8) << 4, 0x01, 0x40];
+        let note_off = [(MidiMessageTypes::NoteOff as u8) << 4, 0x01, 0x00];
+
+        receiver.receive(&modulation(0x10));
+        receiver.receive(&note);
+        receiver.receive(&modulation(0x7F));
+        receiver.receive(&note_off);
+
+        let (held, _) = state.get();
+        assert_eq!(held.len(), 1);
+        assert!(held[0].is_control_change());
+        assert_eq!(held[0].data_byte2, 0x7F);
+
+        // Same value again changes nothing
+        receiver.receive(&modulation(0x7F));
+        assert_eq!(state.get().1, 4);
+    }
+
+    #[test]
+    fn process_callback_release_after_press_is_never_lost() {
+        let (mut receiver, state) = receiver(Vec::new());
+        let press = [(MidiMessageTypes::NoteOn as u8) << 4, 0x3C, 0x40];
+        let other = [(MidiMessageTypes::NoteOn as u8) << 4, 0x40, 0x40];
+        let release = [(MidiMessageTypes::NoteOff as u8) << 4, 0x3C, 0x00];
+
+        // Nobody reads in between, as when the gadget thread is between two reports
+        receiver.receive(&press);
+        receiver.receive(&other);
+        receiver.receive(&release);
+
+        let (published, generation) = state.get();
+        assert_eq!(generation, 3);
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].data_byte1, 0x40);
+    }
+
+    #[test]
+    fn receive_skips_malformed_bytes() {
+        let (mut receiver, state) = receiver(Vec::new());
+        // byte0 high nibble 0x0 is not a valid MidiMessageTypes, the receiver logs and skips the bytes
+        receiver.receive(&[0x00u8, 0x00u8, 0x00u8]);
+        assert_eq!(state.get(), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn decode_skips_malformed_bytes() {
+        let mut parser = MidiParser::new();
+        let messages = decode(&mut parser, &[0x3C, 0x90, 0x3C, 0x40, 0xF8, 0x3E, 0x40]);
+        assert_eq!(
+            messages,
+            vec![
+                MidiMessage::NoteOn { channel: 0, note: 0x3C, velocity: 0x40 },
+                MidiMessage::TimingClock,
+                MidiMessage::NoteOn { channel: 0, note: 0x3E, velocity: 0x40 },
+            ]
+        );
+    }
+
+    #[test]
+    fn process_messages_handles_all_message_lengths() {
+        let (mut receiver, state) = receiver(Vec::new());
+        // Running status, a two byte program change and real-time bytes in one callback
+        receiver.receive(&[0x91, 0x3C, 0x40, 0x3E, 0x40, 0xC1, 0x05, 0xF8, 0xFE]);
+        let (held, generation) = state.get();
+        assert_eq!(held.len(), 2);
+        assert_eq!(generation, 1);
+
+        receiver.receive(&[0x81, 0x3C, 0x00, 0xF0, 0x7E, 0xF7]);
+        let (held, _) = state.get();
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].data_byte1, 0x3E);
+    }
+
+    #[test]
+    fn from_message_drops_channel_and_system_messages() {
+        let pitch_bend = MidiMessageData::from_message(&MidiMessage::PitchBend { channel: 3, value: 0x2001 }).unwrap();
+        assert_eq!(pitch_bend.status_byte, MidiMessageTypes::PitchBend);
+        assert_eq!((pitch_bend.data_byte1, pitch_bend.data_byte2), (0x01, 0x40));
+        assert_eq!(MidiMessageData::from_message(&MidiMessage::TimingClock), None);
+    }
+
+    #[test]
+    fn quantized_notes_wait_for_the_clock() {
+        let kart = crate::profile::Profile::parse("kart", "quantize = 1/16\nnote C = A").unwrap();
+        let control = Arc::new(Control::new(vec![kart]));
+        let state = test_support::midi_state(Vec::new());
+        let mut receiver = test_support::receiver(&state, &control);
+
+        // Stopped, notes pass at once
+        receiver.receive(&[0x90, 0x3C, 0x40]);
+        assert_eq!(state.get().0.len(), 1);
+        receiver.receive(&[0x80, 0x3C, 0x00, 0xFA]);
+        assert!(state.get().0.is_empty());
+
+        // Playing, the press waits for the first pulse of the next sixteenth
+        receiver.receive(&[0xF8, 0x90, 0x3C, 0x40, 0xF8, 0xF8, 0xF8, 0xF8, 0xF8]);
+        assert!(state.get().0.is_empty());
+        receiver.receive(&[0xF8]);
+        assert_eq!(state.get().0.len(), 1);
+        assert_eq!(control.clock().position, 7);
+
+        // Stop lets waiting notes through
+        receiver.receive(&[0x80, 0x3C, 0x00]);
+        assert_eq!(state.get().0.len(), 1);
+        receiver.receive(&[0xFC]);
+        assert!(state.get().0.is_empty());
+    }
+
+    #[test]
+    fn channel_mode_messages_clear_the_state() {
+        let piano = crate::profile::Profile::parse("piano", "sustain = on\nnote C = A").unwrap();
+        let control = Arc::new(Control::new(vec![piano]));
+        let state = test_support::midi_state(Vec::new());
+        let mut receiver = test_support::receiver(&state, &control);
+
+        // All Notes Off releases notes, including the ones the damper pedal holds
+        receiver.receive(&[0xB0, 0x40, 0x7F, 0x90, 0x3C, 0x40, 0x3E, 0x40, 0x80, 0x3C, 0x00]);
+        assert_eq!(state.get().0.len(), 3);
+        receiver.receive(&[0xB1, ALL_NOTES_OFF, 0x00]);
+        assert_eq!(state.get().0, vec![test_support::control(0x40, 0x7F)]);
+
+        // Reset All Controllers drops controller values and puts the pedal up
+        receiver.receive(&[0x90, 0x3C, 0x40, 0x80, 0x3C, 0x00, 0xB0, 0x01, 0x20]);
+        assert_eq!(state.get().0.len(), 3);
+        receiver.receive(&[0xB0, RESET_ALL_CONTROLLERS, 0x00]);
+        assert!(state.get().0.is_empty());
+
+        // System Reset releases both
+        receiver.receive(&[0x90, 0x3C, 0x40, 0xB0, 0x01, 0x20, 0xFF]);
+        assert!(state.get().0.is_empty());
+        receiver.receive(&[0x9F, 0x3C, 0x40]);
+        assert_eq!(state.get().0.len(), 1);
+        receiver.receive(&[0xBF, ALL_SOUND_OFF, 0x00]);
+        assert!(state.get().0.is_empty());
     }
 
     #[test]
-    #[should_panic(expected = "Incorrect MidiMessageType")]
-    fn process_callback_malformed_data_panics() {
-        let (tx, _rx) = mpsc::channel();
-        let persistent: Vec<MidiMessageData> = Vec::new();
-        // byte0 high nibble 0x0 is not a valid MidiMessageTypes
-        let bad = [0x00u8, 0x00u8, 0x00u8];
-        // process_callback currently unwraps MidiMessageData::new(), so this will panic
-        process_callback(&bad, persistent, tx).unwrap();
+    fn notes_released_by_the_watchdog_are_forgotten() {
+        let piano = crate::profile::Profile::parse("piano", "sustain = on\nnote C = A").unwrap();
+        let control = Arc::new(Control::new(vec![piano]));
+        let state = test_support::midi_state(Vec::new());
+        let mut receiver = test_support::receiver(&state, &control);
+
+        // The Note Off of 60 got lost and the watchdog released it
+        receiver.receive(&[0x90, 0x3C, 0x40]);
+        state.update(|held, _| Some(crate::watchdog::release(held, &[0x3C])));
+        control.release_notes(&[0x3C]);
+
+        // The sostenuto pedal does not catch the released note
+        receiver.receive(&[0xB0, 0x42, 0x7F, 0x90, 0x3C, 0x40, 0x80, 0x3C, 0x00]);
+        assert_eq!(state.get().0, vec![test_support::control(0x42, 0x7F)]);
     }
 }
@@ -0,0 +1,242 @@
+use crate::control::Control;
+use crate::midi::MidiReceiver;
+use crate::shutdown;
+use libc::{O_NOCTTY, O_NONBLOCK};
+use log::info;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Bit rate of the MIDI 1.0 DIN current loop
+pub const MIDI_BAUD: u32 = 31250;
+
+/// Speed bits of c_cflag, missing from libc for most Linux targets
+const CBAUD: libc::tcflag_t = 0o010017;
+
+/// How long a read waits before checking for a shutdown again
+const POLL_TIMEOUT_MS: libc::c_int = 100;
+
+/// What a MIDI device file delivers
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DeviceKind {
+    /// Serial port wired to a MIDI DIN socket
+    Serial,
+    /// ALSA rawmidi device, the bytes of the MIDI cable
+    RawMidi,
+    /// ALSA UMP device such as /dev/snd/umpC1D0, native endian 32 bit words
+    Ump,
+}
+
+/// Reads a serial port or an ALSA rawmidi or UMP device file until a shutdown is requested
+///
+/// Serial ports are switched to raw mode at 31250 baud first, rawmidi files
+/// already deliver the bytes of the MIDI cable and are read as they are.
+pub fn process_device(path: &Path, kind: DeviceKind, mut receiver: MidiReceiver, control: Arc<Control>) -> Result<(), Box<dyn Error>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(O_NONBLOCK | O_NOCTTY)
+        .open(path)
+        .map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
+    if kind == DeviceKind::Serial {
+        configure_serial(&file).map_err(|e| format!("Unable to configure {}: {}", path.display(), e))?;
+    }
+
+    info!("Reading MIDI from {}", path.display());
+    control.set_midi(Some(path.display().to_string()));
+    let result = match kind {
+        DeviceKind::Ump => {
+            let mut pending = Vec::new();
+            read_stream(&file, &mut |bytes| receiver.receive_ump(&ump_words(&mut pending, bytes)), &shutdown::requested)
+        }
+        _ => read_stream(&file, &mut |bytes| receiver.receive(bytes), &shutdown::requested),
+    };
+    control.set_midi(None);
+
+    info!("Closing {}", path.display());
+    result
+}
+
+/// Raw 8N1 without flow control at the MIDI bit rate
+///
+/// 31250 is not one of the Bxxx constants, termios2 with BOTHER sets it directly.
+pub fn configure_serial(file: &File) -> Result<(), io::Error> {
+    let fd = file.as_raw_fd();
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { libc::cfmakeraw(&mut termios) };
+    termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+    termios.c_cflag &= !(libc::CSTOPB | libc::CRTSCTS);
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut termios2: libc::termios2 = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TCGETS2 as _, &mut termios2) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    termios2.c_cflag = (termios2.c_cflag & !CBAUD) | libc::BOTHER;
+    termios2.c_ispeed = MIDI_BAUD;
+    termios2.c_ospeed = MIDI_BAUD;
+    if unsafe { libc::ioctl(fd, libc::TCSETS2 as _, &termios2) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Complete words of `bytes` appended to the `pending` bytes of an earlier read, the rest stays pending
+pub fn ump_words(pending: &mut Vec<u8>, bytes: &[u8]) -> Vec<u32> {
+    pending.extend_from_slice(bytes);
+    let complete = pending.len() - pending.len() % 4;
+    let words = pending[..complete]
+        .chunks_exact(4)
+        .map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    pending.drain(..complete);
+    words
+}
+
+/// Hands everything read from a non-blocking `file` to `consume` until `stopped` returns true
+///
+/// The end of the file or a vanished device is an error, MIDI sources do not end by themselves.
+pub fn read_stream(mut file: &File, consume: &mut dyn FnMut(&[u8]), stopped: &dyn Fn() -> bool) -> Result<(), Box<dyn Error>> {
+    let mut buffer = [0u8; 256];
+    while !stopped() {
+        let mut poll_fd = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll_fd, 1, POLL_TIMEOUT_MS) } {
+            0 => continue,
+            ready if ready < 0 => {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error.into());
+            }
+            _ => {}
+        }
+
+        match file.read(&mut buffer) {
+            Ok(0) => return Err("MIDI input closed".into()),
+            Ok(length) => consume(&buffer[..length]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::MidiMessageTypes;
+    use crate::test_support::{midi_state, receiver};
+    use std::ffi::CStr;
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Master side of a new pseudo-terminal and the path of its slave
+    fn open_pty() -> (File, String) {
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | O_NOCTTY);
+            assert!(master >= 0, "posix_openpt: {}", io::Error::last_os_error());
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+            let mut name = [0 as libc::c_char; 64];
+            assert_eq!(libc::ptsname_r(master, name.as_mut_ptr(), name.len()), 0);
+            let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().to_string();
+            (File::from_raw_fd(master), path)
+        }
+    }
+
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn configures_serial_port_for_midi() {
+        let (_master, path) = open_pty();
+        let slave = OpenOptions::new().read(true).custom_flags(O_NONBLOCK | O_NOCTTY).open(&path).unwrap();
+        configure_serial(&slave).unwrap();
+
+        let mut termios2: libc::termios2 = unsafe { std::mem::zeroed() };
+        assert!(unsafe { libc::ioctl(slave.as_raw_fd(), libc::TCGETS2 as _, &mut termios2) } >= 0);
+        assert_eq!(termios2.c_ospeed, MIDI_BAUD);
+        assert_eq!(termios2.c_ispeed, MIDI_BAUD);
+        assert_eq!(termios2.c_cflag & libc::CSIZE, libc::CS8);
+        assert_eq!(termios2.c_lflag & (libc::ICANON | libc::ECHO), 0);
+    }
+
+    #[test]
+    fn reads_midi_from_serial_port() {
+        let (mut master, path) = open_pty();
+        let slave = OpenOptions::new().read(true).custom_flags(O_NONBLOCK | O_NOCTTY).open(&path).unwrap();
+        configure_serial(&slave).unwrap();
+
+        let state = midi_state(Vec::new());
+        let mut receiver = receiver(&state, &Arc::new(Control::new(Vec::new())));
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader_stop = stop.clone();
+        let reader = thread::spawn(move || {
+            read_stream(&slave, &mut |bytes| receiver.receive(bytes), &|| reader_stop.load(Ordering::SeqCst)).map_err(|e| e.to_string())
+        });
+
+        // Running status continues across writes, the clock byte interleaves
+        master.write_all(&[0x90, 0x3C, 0x40]).unwrap();
+        master.write_all(&[0x3E, 0xF8, 0x40]).unwrap();
+        wait_for(|| state.get().0.len() == 2);
+        master.write_all(&[0x3C, 0x00]).unwrap();
+        wait_for(|| state.get().0.len() == 1);
+        assert_eq!(state.get().0[0].status_byte, MidiMessageTypes::NoteOn);
+        assert_eq!(state.get().0[0].data_byte1, 0x3E);
+
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(reader.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn closed_input_is_an_error() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK) }, 0);
+        let (read_end, mut write_end) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let state = midi_state(Vec::new());
+        write_end.write_all(&[0xB0, 0x01, 0x7F]).unwrap();
+        drop(write_end);
+        let mut receiver = receiver(&state, &Arc::new(Control::new(Vec::new())));
+        let result = read_stream(&read_end, &mut |bytes| receiver.receive(bytes), &|| false);
+        assert!(result.is_err());
+        assert_eq!(state.get().0.len(), 1);
+    }
+
+    #[test]
+    fn reads_ump_words_split_across_reads() {
+        let mut pending = Vec::new();
+        let bytes: Vec<u8> = [0x40B0_0100u32, 0xFFFF_FFFF].iter().flat_map(|word| word.to_ne_bytes()).collect();
+        assert_eq!(ump_words(&mut pending, &bytes[..3]), Vec::<u32>::new());
+        assert_eq!(ump_words(&mut pending, &bytes[3..6]), vec![0x40B0_0100]);
+        assert_eq!(ump_words(&mut pending, &bytes[6..]), vec![0xFFFF_FFFF]);
+        assert!(pending.is_empty());
+
+        let state = midi_state(Vec::new());
+        let mut receiver = receiver(&state, &Arc::new(Control::new(Vec::new())));
+        receiver.receive_ump(&ump_words(&mut pending, &bytes));
+        assert_eq!(state.get().0[0].status_byte, MidiMessageTypes::ControlChange);
+        assert_eq!(state.get().0[0].value, u32::MAX);
+    }
+}
@@ -0,0 +1,513 @@
+use std::error::Error;
+use std::fmt;
+
+/// Longest system exclusive payload kept, longer ones are dropped with an error
+pub const MAX_SYSEX: usize = 4096;
+
+/// MIDI 1.0 message with its channel (0-15)
+///
+/// ```text
+/// Message                 Status   Data
+/// ----------------------  -------  ------------------------
+/// Note off                8n       note, velocity
+/// Note on                 9n       note, velocity
+/// Polyphonic pressure     An       note, pressure
+/// Control change          Bn       controller, value
+/// Program change          Cn       program
+/// Channel pressure        Dn       pressure
+/// Pitch bend              En       LSB, MSB
+/// System exclusive        F0       any number of bytes, ended by F7
+/// Time code quarter frame F1       type and value
+/// Song position           F2       LSB, MSB in sixteenth notes
+/// Song select             F3       song
+/// Tune request            F6       -
+/// Real-time               F8-FF    -, may appear between any two bytes
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyphonicPressure { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// 14 bit, 0x2000 is the center
+    PitchBend { channel: u8, value: u16 },
+    /// Payload between F0 and F7
+    SystemExclusive(Vec<u8>),
+    TimeCodeQuarterFrame(u8),
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+}
+
+impl MidiMessage {
+    /// Channel of channel voice messages
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::PolyphonicPressure { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+
+    pub fn is_realtime(&self) -> bool {
+        matches!(
+            self,
+            MidiMessage::TimingClock
+                | MidiMessage::Start
+                | MidiMessage::Continue
+                | MidiMessage::Stop
+                | MidiMessage::ActiveSensing
+                | MidiMessage::Reset
+        )
+    }
+
+    /// Complete encoding with its status byte, no running status
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let lsb = |value: u16| (value & 0x7F) as u8;
+        let msb = |value: u16| ((value >> 7) & 0x7F) as u8;
+        match self {
+            MidiMessage::NoteOff { channel, note, velocity } => vec![0x80 | channel, *note, *velocity],
+            MidiMessage::NoteOn { channel, note, velocity } => vec![0x90 | channel, *note, *velocity],
+            MidiMessage::PolyphonicPressure { channel, note, pressure } => vec![0xA0 | channel, *note, *pressure],
+            MidiMessage::ControlChange { channel, controller, value } => vec![0xB0 | channel, *controller, *value],
+            MidiMessage::ProgramChange { channel, program } => vec![0xC0 | channel, *program],
+            MidiMessage::ChannelPressure { channel, pressure } => vec![0xD0 | channel, *pressure],
+            MidiMessage::PitchBend { channel, value } => vec![0xE0 | channel, lsb(*value), msb(*value)],
+            MidiMessage::SystemExclusive(data) => {
+                let mut bytes = Vec::with_capacity(data.len() + 2);
+                bytes.push(0xF0);
+                bytes.extend_from_slice(data);
+                bytes.push(0xF7);
+                bytes
+            }
+            MidiMessage::TimeCodeQuarterFrame(value) => vec![0xF1, *value],
+            MidiMessage::SongPosition(position) => vec![0xF2, lsb(*position), msb(*position)],
+            MidiMessage::SongSelect(song) => vec![0xF3, *song],
+            MidiMessage::TuneRequest => vec![0xF6],
+            MidiMessage::TimingClock => vec![0xF8],
+            MidiMessage::Start => vec![0xFA],
+            MidiMessage::Continue => vec![0xFB],
+            MidiMessage::Stop => vec![0xFC],
+            MidiMessage::ActiveSensing => vec![0xFE],
+            MidiMessage::Reset => vec![0xFF],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseError {
+    /// Data byte without a status byte it belongs to
+    UnexpectedData(u8),
+    /// Status byte MIDI 1.0 leaves undefined (F4, F5, F9, FD)
+    UndefinedStatus(u8),
+    /// Another status byte arrived before the message of `status` was complete
+    Incomplete { status: u8, received: usize },
+    /// System exclusive interrupted by another status byte
+    UnterminatedSysEx,
+    /// F7 outside of system exclusive
+    UnexpectedEndOfExclusive,
+    SysExTooLong,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedData(byte) => write!(f, "Data byte {:#04X} without status", byte),
+            ParseError::UndefinedStatus(byte) => write!(f, "Undefined status byte {:#04X}", byte),
+            ParseError::Incomplete { status, received } => {
+                write!(f, "Message {:#04X} interrupted after {} data bytes", status, received)
+            }
+            ParseError::UnterminatedSysEx => write!(f, "System exclusive interrupted by a status byte"),
+            ParseError::UnexpectedEndOfExclusive => write!(f, "End of exclusive without system exclusive"),
+            ParseError::SysExTooLong => write!(f, "System exclusive longer than {} bytes", MAX_SYSEX),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Number of data bytes following a status byte
+pub(crate) fn data_length(status: u8) -> usize {
+    match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        _ => 0,
+    }
+}
+
+/// MIDI 1.0 byte stream parser
+///
+/// Bytes may arrive in any split, one callback can carry several messages or a
+/// part of one. Channel messages may omit their status byte while it stays the
+/// same (running status), system common messages and system exclusive cancel it.
+/// Real-time bytes are returned as soon as they arrive, even in the middle of
+/// another message, which then continues undisturbed.
+#[derive(Debug, Default)]
+pub struct MidiParser {
+    /// Running status or the system common message waiting for its data
+    status: Option<u8>,
+    /// A status byte arrived and its message is not complete yet
+    pending: bool,
+    data: [u8; 2],
+    received: usize,
+    sysex: Option<Vec<u8>>,
+    sysex_overflow: bool,
+}
+
+impl MidiParser {
+    pub fn new() -> MidiParser {
+        MidiParser::default()
+    }
+
+    /// Every message or error the bytes complete
+    pub fn parse(&mut self, bytes: &[u8]) -> Vec<Result<MidiMessage, ParseError>> {
+        let mut out = Vec::new();
+        for byte in bytes {
+            self.push(*byte, &mut out);
+        }
+        out
+    }
+
+    /// Feeds one byte, adds the messages and errors it completes to `out`
+    ///
+    /// A status byte interrupting a message adds the error first and then
+    /// the message it completes on its own, such as a Tune Request.
+    pub fn push(&mut self, byte: u8, out: &mut Vec<Result<MidiMessage, ParseError>>) {
+        // Real-time bytes interleave and End of Exclusive finishes a message
+        if matches!(byte, 0x80..=0xF6) {
+            if let Some(interrupted) = self.interrupt() {
+                out.push(Err(interrupted));
+            }
+        }
+        out.extend(self.complete(byte));
+    }
+
+    fn complete(&mut self, byte: u8) -> Option<Result<MidiMessage, ParseError>> {
+        if byte >= 0xF8 {
+            return Some(realtime(byte));
+        }
+        if byte == 0xF7 {
+            return Some(match self.sysex.take() {
+                Some(_) if self.sysex_overflow => Err(ParseError::SysExTooLong),
+                Some(data) => Ok(MidiMessage::SystemExclusive(data)),
+                None => Err(ParseError::UnexpectedEndOfExclusive),
+            });
+        }
+        if byte >= 0x80 {
+            return self.status_byte(byte);
+        }
+
+        if let Some(sysex) = self.sysex.as_mut() {
+            if sysex.len() < MAX_SYSEX {
+                sysex.push(byte);
+            } else {
+                self.sysex_overflow = true;
+            }
+            return None;
+        }
+        let status = match self.status {
+            Some(status) => status,
+            None => return Some(Err(ParseError::UnexpectedData(byte))),
+        };
+        self.data[self.received] = byte;
+        self.received += 1;
+        if self.received < data_length(status) {
+            self.pending = true;
+            return None;
+        }
+        self.received = 0;
+        self.pending = false;
+        if status >= 0xF0 {
+            self.status = None;
+        }
+        Some(Ok(decode(status, &self.data)))
+    }
+
+    /// Ends the message in progress for a new status byte, the error if it was unfinished
+    fn interrupt(&mut self) -> Option<ParseError> {
+        let interrupted = if self.sysex.take().is_some() {
+            Some(ParseError::UnterminatedSysEx)
+        } else if self.pending {
+            self.status.map(|status| ParseError::Incomplete {
+                status,
+                received: self.received,
+            })
+        } else {
+            None
+        };
+        self.received = 0;
+        self.pending = false;
+        self.status = None;
+        interrupted
+    }
+
+    fn status_byte(&mut self, byte: u8) -> Option<Result<MidiMessage, ParseError>> {
+        match byte {
+            0xF0 => {
+                self.sysex = Some(Vec::new());
+                self.sysex_overflow = false;
+                None
+            }
+            0xF4 | 0xF5 => Some(Err(ParseError::UndefinedStatus(byte))),
+            0xF6 => Some(Ok(MidiMessage::TuneRequest)),
+            _ => {
+                self.status = Some(byte);
+                self.pending = true;
+                None
+            }
+        }
+    }
+}
+
+fn realtime(byte: u8) -> Result<MidiMessage, ParseError> {
+    match byte {
+        0xF8 => Ok(MidiMessage::TimingClock),
+        0xFA => Ok(MidiMessage::Start),
+        0xFB => Ok(MidiMessage::Continue),
+        0xFC => Ok(MidiMessage::Stop),
+        0xFE => Ok(MidiMessage::ActiveSensing),
+        0xFF => Ok(MidiMessage::Reset),
+        _ => Err(ParseError::UndefinedStatus(byte)),
+    }
+}
+
+fn decode(status: u8, data: &[u8; 2]) -> MidiMessage {
+    let channel = status & 0x0F;
+    let fourteen_bit = data[0] as u16 | (data[1] as u16) << 7;
+    match status {
+        0x80..=0x8F => MidiMessage::NoteOff { channel, note: data[0], velocity: data[1] },
+        0x90..=0x9F => MidiMessage::NoteOn { channel, note: data[0], velocity: data[1] },
+        0xA0..=0xAF => MidiMessage::PolyphonicPressure { channel, note: data[0], pressure: data[1] },
+        0xB0..=0xBF => MidiMessage::ControlChange { channel, controller: data[0], value: data[1] },
+        0xC0..=0xCF => MidiMessage::ProgramChange { channel, program: data[0] },
+        0xD0..=0xDF => MidiMessage::ChannelPressure { channel, pressure: data[0] },
+        0xE0..=0xEF => MidiMessage::PitchBend { channel, value: fourteen_bit },
+        0xF1 => MidiMessage::TimeCodeQuarterFrame(data[0]),
+        0xF2 => MidiMessage::SongPosition(fourteen_bit),
+        _ => MidiMessage::SongSelect(data[0]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<Result<MidiMessage, ParseError>> {
+        MidiParser::new().parse(bytes)
+    }
+
+    fn note_on(channel: u8, note: u8, velocity: u8) -> Result<MidiMessage, ParseError> {
+        Ok(MidiMessage::NoteOn { channel, note, velocity })
+    }
+
+    #[test]
+    fn parses_every_channel_message() {
+        let cases = [
+            (vec![0x83, 60, 0], MidiMessage::NoteOff { channel: 3, note: 60, velocity: 0 }),
+            (vec![0x9F, 60, 100], MidiMessage::NoteOn { channel: 15, note: 60, velocity: 100 }),
+            (vec![0xA1, 61, 20], MidiMessage::PolyphonicPressure { channel: 1, note: 61, pressure: 20 }),
+            (vec![0xB2, 64, 127], MidiMessage::ControlChange { channel: 2, controller: 64, value: 127 }),
+            (vec![0xC4, 5], MidiMessage::ProgramChange { channel: 4, program: 5 }),
+            (vec![0xD5, 90], MidiMessage::ChannelPressure { channel: 5, pressure: 90 }),
+            (vec![0xE6, 0x00, 0x40], MidiMessage::PitchBend { channel: 6, value: 0x2000 }),
+            (vec![0xE6, 0x7F, 0x7F], MidiMessage::PitchBend { channel: 6, value: 0x3FFF }),
+        ];
+        for (bytes, message) in cases.iter() {
+            assert_eq!(parse(bytes), vec![Ok(message.clone())], "{:02X?}", bytes);
+            assert_eq!(message.to_bytes(), *bytes);
+            assert_eq!(message.channel(), Some(bytes[0] & 0x0F));
+        }
+    }
+
+    #[test]
+    fn parses_system_messages() {
+        let cases = [
+            (vec![0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7], MidiMessage::SystemExclusive(vec![0x7E, 0x7F, 0x06, 0x01])),
+            (vec![0xF0, 0xF7], MidiMessage::SystemExclusive(Vec::new())),
+            (vec![0xF1, 0x35], MidiMessage::TimeCodeQuarterFrame(0x35)),
+            (vec![0xF2, 0x10, 0x01], MidiMessage::SongPosition(0x90)),
+            (vec![0xF3, 0x07], MidiMessage::SongSelect(7)),
+            (vec![0xF6], MidiMessage::TuneRequest),
+            (vec![0xF8], MidiMessage::TimingClock),
+            (vec![0xFA], MidiMessage::Start),
+            (vec![0xFB], MidiMessage::Continue),
+            (vec![0xFC], MidiMessage::Stop),
+            (vec![0xFE], MidiMessage::ActiveSensing),
+            (vec![0xFF], MidiMessage::Reset),
+        ];
+        for (bytes, message) in cases.iter() {
+            assert_eq!(parse(bytes), vec![Ok(message.clone())], "{:02X?}", bytes);
+            assert_eq!(message.to_bytes(), *bytes);
+            assert_eq!(message.channel(), None);
+        }
+        assert!(MidiMessage::TimingClock.is_realtime());
+        assert!(!MidiMessage::TuneRequest.is_realtime());
+    }
+
+    #[test]
+    fn running_status_repeats_channel_messages() {
+        assert_eq!(
+            parse(&[0x90, 60, 100, 64, 100, 60, 0]),
+            vec![note_on(0, 60, 100), note_on(0, 64, 100), note_on(0, 60, 0)]
+        );
+        assert_eq!(
+            parse(&[0xC2, 1, 2]),
+            vec![
+                Ok(MidiMessage::ProgramChange { channel: 2, program: 1 }),
+                Ok(MidiMessage::ProgramChange { channel: 2, program: 2 })
+            ]
+        );
+    }
+
+    #[test]
+    fn running_status_survives_split_callbacks() {
+        let mut parser = MidiParser::new();
+        assert_eq!(parser.parse(&[0x90, 60]), vec![]);
+        assert_eq!(parser.parse(&[100]), vec![note_on(0, 60, 100)]);
+        assert_eq!(parser.parse(&[62, 100]), vec![note_on(0, 62, 100)]);
+    }
+
+    #[test]
+    fn system_messages_cancel_running_status() {
+        assert_eq!(
+            parse(&[0x90, 60, 100, 0xF3, 1, 62, 100]),
+            vec![
+                note_on(0, 60, 100),
+                Ok(MidiMessage::SongSelect(1)),
+                Err(ParseError::UnexpectedData(62)),
+                Err(ParseError::UnexpectedData(100))
+            ]
+        );
+        assert_eq!(
+            parse(&[0x90, 60, 100, 0xF0, 1, 0xF7, 62]),
+            vec![
+                note_on(0, 60, 100),
+                Ok(MidiMessage::SystemExclusive(vec![1])),
+                Err(ParseError::UnexpectedData(62))
+            ]
+        );
+    }
+
+    #[test]
+    fn realtime_bytes_interleave_without_disturbing() {
+        assert_eq!(
+            parse(&[0x90, 0xF8, 60, 0xFE, 100, 62, 0xFA, 100]),
+            vec![
+                Ok(MidiMessage::TimingClock),
+                Ok(MidiMessage::ActiveSensing),
+                note_on(0, 60, 100),
+                Ok(MidiMessage::Start),
+                note_on(0, 62, 100)
+            ]
+        );
+        assert_eq!(
+            parse(&[0xF0, 1, 0xF8, 2, 0xF7]),
+            vec![Ok(MidiMessage::TimingClock), Ok(MidiMessage::SystemExclusive(vec![1, 2]))]
+        );
+    }
+
+    #[test]
+    fn reports_data_without_status() {
+        assert_eq!(
+            parse(&[0x00, 0x00, 0x00]),
+            vec![
+                Err(ParseError::UnexpectedData(0)),
+                Err(ParseError::UnexpectedData(0)),
+                Err(ParseError::UnexpectedData(0))
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_interrupted_messages() {
+        assert_eq!(
+            parse(&[0x90, 60, 0x80, 60, 0]),
+            vec![
+                Err(ParseError::Incomplete { status: 0x90, received: 1 }),
+                Ok(MidiMessage::NoteOff { channel: 0, note: 60, velocity: 0 })
+            ]
+        );
+        assert_eq!(
+            parse(&[0xB0, 0xC0, 3]),
+            vec![
+                Err(ParseError::Incomplete { status: 0xB0, received: 0 }),
+                Ok(MidiMessage::ProgramChange { channel: 0, program: 3 })
+            ]
+        );
+        // The interrupting byte still completes its own message
+        assert_eq!(
+            parse(&[0xF2, 1, 0xF6]),
+            vec![Err(ParseError::Incomplete { status: 0xF2, received: 1 }), Ok(MidiMessage::TuneRequest)]
+        );
+        assert_eq!(
+            parse(&[0xF0, 1, 0xF4]),
+            vec![Err(ParseError::UnterminatedSysEx), Err(ParseError::UndefinedStatus(0xF4))]
+        );
+    }
+
+    #[test]
+    fn reports_broken_system_exclusive() {
+        assert_eq!(
+            parse(&[0xF0, 1, 2, 0x90, 60, 100]),
+            vec![Err(ParseError::UnterminatedSysEx), note_on(0, 60, 100)]
+        );
+        assert_eq!(parse(&[0xF7]), vec![Err(ParseError::UnexpectedEndOfExclusive)]);
+
+        let mut long = vec![0xF0];
+        long.extend(std::iter::repeat_n(0x01, MAX_SYSEX + 1));
+        long.push(0xF7);
+        long.extend_from_slice(&[0xF0, 0x02, 0xF7]);
+        assert_eq!(
+            parse(&long),
+            vec![Err(ParseError::SysExTooLong), Ok(MidiMessage::SystemExclusive(vec![2]))]
+        );
+    }
+
+    #[test]
+    fn reports_undefined_status() {
+        for byte in [0xF4, 0xF5, 0xF9, 0xFD] {
+            assert_eq!(parse(&[byte]), vec![Err(ParseError::UndefinedStatus(byte))]);
+        }
+        // Undefined system common bytes cancel running status, real-time ones do not
+        assert_eq!(
+            parse(&[0x90, 60, 100, 0xF9, 62, 100, 0xF4, 64]),
+            vec![
+                note_on(0, 60, 100),
+                Err(ParseError::UndefinedStatus(0xF9)),
+                note_on(0, 62, 100),
+                Err(ParseError::UndefinedStatus(0xF4)),
+                Err(ParseError::UnexpectedData(64))
+            ]
+        );
+    }
+
+    #[test]
+    fn recovers_after_errors() {
+        let mut parser = MidiParser::new();
+        parser.parse(&[0x42, 0xF7, 0xF0, 1, 0x90, 60]);
+        parser.parse(&[0x91, 60, 100]);
+        assert_eq!(parser.parse(&[0xB1, 1, 64]), vec![Ok(MidiMessage::ControlChange { channel: 1, controller: 1, value: 64 })]);
+    }
+
+    #[test]
+    fn errors_display() {
+        assert_eq!(ParseError::UnexpectedData(0x3C).to_string(), "Data byte 0x3C without status");
+        assert_eq!(
+            ParseError::Incomplete { status: 0x90, received: 1 }.to_string(),
+            "Message 0x90 interrupted after 1 data bytes"
+        );
+    }
+}
@@ -1,6 +1,5 @@
 use crate::midi::MidiMessageData;
 use lazy_static::lazy_static;
-use log::error;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 
@@ -28,6 +27,49 @@ pub enum Button {
     ZL,
 }
 
+impl Button {
+    pub const ALL: [Button; 18] = [
+        Button::Y, Button::X, Button::B,
+        Button::A, Button::R, Button::ZR,
+        Button::Minus, Button::Plus, Button::RightStick,
+        Button::LeftStick, Button::Home, Button::Capture,
+        Button::DpadDown, Button::DpadUp, Button::DpadRight,
+        Button::DpadLeft, Button::L, Button::ZL,
+    ];
+
+    /// Name used in profiles and the HTTP API
+    pub fn name(&self) -> &'static str {
+        match self {
+            Button::Y => "Y",
+            Button::X => "X",
+            Button::B => "B",
+            Button::A => "A",
+            Button::R => "R",
+            Button::ZR => "ZR",
+            Button::Minus => "Minus",
+            Button::Plus => "Plus",
+            Button::RightStick => "RightStick",
+            Button::LeftStick => "LeftStick",
+            Button::Home => "Home",
+            Button::Capture => "Capture",
+            Button::DpadDown => "DpadDown",
+            Button::DpadUp => "DpadUp",
+            Button::DpadRight => "DpadRight",
+            Button::DpadLeft => "DpadLeft",
+            Button::L => "L",
+            Button::ZL => "ZL",
+        }
+    }
+
+    /// Case insensitive inverse of `name`
+    pub fn from_name(name: &str) -> Option<Button> {
+        Button::ALL
+            .iter()
+            .find(|button| button.name().eq_ignore_ascii_case(name))
+            .cloned()
+    }
+}
+
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub enum Pitch {
     C,
@@ -121,20 +163,80 @@ lazy_static! {
 }
 
 impl Pitch {
-    pub fn from_midi(midi_data: &MidiMessageData) -> Result<Pitch, Box<dyn Error>> {
-        let note_number_mod = midi_data.data_byte1 % 12;
+    pub const ALL: [Pitch; 12] = [
+        Pitch::C, Pitch::CSharp, Pitch::D, Pitch::DSharp,
+        Pitch::E, Pitch::F, Pitch::FSharp, Pitch::G,
+        Pitch::GSharp, Pitch::A, Pitch::ASharp, Pitch::B,
+    ];
 
-        match INDEX_TO_PITCH.get(&note_number_mod) {
-            Some(pitch) => Ok(pitch.clone()),
-            None => Err(format!("Cannot find pitch for data byte {:?}", midi_data.data_byte1).into()),
+    /// Name used in profiles, sharps only
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pitch::C => "C",
+            Pitch::CSharp => "C#",
+            Pitch::D => "D",
+            Pitch::DSharp => "D#",
+            Pitch::E => "E",
+            Pitch::F => "F",
+            Pitch::FSharp => "F#",
+            Pitch::G => "G",
+            Pitch::GSharp => "G#",
+            Pitch::A => "A",
+            Pitch::ASharp => "A#",
+            Pitch::B => "B",
         }
     }
+
+    /// Parses note names like C, C# or Db
+    pub fn from_name(name: &str) -> Option<Pitch> {
+        let mut chars = name.chars();
+        let index: i8 = match chars.next()?.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return None,
+        };
+        let index = match chars.as_str() {
+            "" => index,
+            "#" => index + 1,
+            "b" => index - 1,
+            _ => return None,
+        };
+        INDEX_TO_PITCH.get(&(index.rem_euclid(12) as u8)).cloned()
+    }
+
+    /// Button the pitch is mapped to when no profile is loaded
+    pub fn default_button(&self) -> Option<Button> {
+        PITCH_TO_BUTTON.get(self).cloned()
+    }
+
+    /// Pitch of a MIDI note number in any octave
+    pub fn from_note(note: u8) -> Pitch {
+        Pitch::ALL[(note % 12) as usize].clone()
+    }
+
+    /// Pitch of a note message, kept for library users, the binary maps notes through profiles
+    #[allow(dead_code)]
+    pub fn from_midi(midi_data: &MidiMessageData) -> Result<Pitch, Box<dyn Error>> {
+        Ok(Pitch::from_note(midi_data.data_byte1))
+    }
 }
 
+#[derive(Debug, PartialEq, Clone)]
 pub struct InputReport {
     pub report: [u8; 3],
 }
 
+impl Default for InputReport {
+    fn default() -> Self {
+        InputReport::new()
+    }
+}
+
 /// Input report format
 ///  =========================================================================================================
 /// | Bytes/Bits |     7    |    6    |     5      |     4      |     3     |     2     |     1     |    0    |
@@ -175,31 +277,15 @@ impl InputReport {
         }
     }
 
-    pub fn from(midi_data: &MidiMessageData) -> InputReport {
-        let mut input_report = InputReport::new();
-        let pressed_pitch = match Pitch::from_midi(midi_data) {
+    pub fn is_pressed(&self, key: &Button) -> bool {
+        let position = match self.find_packet_position(key) {
             Ok(value) => value,
-            Err(_error) => {
-                error!(
-                    "Unable to find pitch for {:#04X?}",
-                    midi_data.data_byte1
-                );
-                return input_report;
-            }
+            Err(_) => return false,
         };
-        let pressed_button = match PITCH_TO_BUTTON.get(&pressed_pitch) {
-            Some(value) => value,
-            None => {
-                error!(
-                    "Unable to find corresponding button for {:#04X?}",
-                    pressed_pitch
-                );
-                return input_report;
-            }
-        };
-
-        input_report.press_one(pressed_button).unwrap();
-        input_report
+        match KEY_OFFSET.get(key) {
+            Some(offset) => self.report[position] & (1 << offset) != 0,
+            None => false,
+        }
     }
 
     fn find_packet_position(&self, key: &Button) -> Result<usize, Box<dyn Error>> {
@@ -219,11 +305,19 @@ impl InputReport {
         Ok(position)
     }
 
-    fn press_one(&mut self, key: &Button) -> Result<(), Box<dyn Error>> {
+    pub fn press_one(&mut self, key: &Button) -> Result<(), Box<dyn Error>> {
+        self.set(key, true)
+    }
+
+    pub fn set(&mut self, key: &Button, pressed: bool) -> Result<(), Box<dyn Error>> {
         let position = self.find_packet_position(key)?;
         match KEY_OFFSET.get(key) {
             Some(offset) => {
-                self.report[position] |= 1 << offset;
+                if pressed {
+                    self.report[position] |= 1 << offset;
+                } else {
+                    self.report[position] &= !(1 << offset);
+                }
             }
             None => return Err(format!("Cannot find offset for {:?}", key).into()),
         };
@@ -234,7 +328,6 @@ impl InputReport {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::midi::MidiMessageTypes;
 
     #[test]
     fn input_report_new_has_expected_default() {
@@ -244,10 +337,35 @@ mod tests {
 
     #[test]
     fn from_midi_message_uses_mapping() {
-        // data_byte1 0x06u8 maps to Button::L per MIDI_TO_INPUT
-        let midi = crate::midi::MidiMessageData { status_byte: MidiMessageTypes::NoteOn, data_byte1: 0x06u8, data_byte2: 0 };
-        let r = InputReport::from(&midi);
+        // data_byte1 0x06u8 maps to Button::L in the default mapping
+        let midi = crate::midi::MidiMessageData::midi1(crate::midi::MidiMessageTypes::NoteOn, 0x06u8, 0);
+        let button = Pitch::from_midi(&midi).unwrap().default_button().unwrap();
+        assert_eq!(button, Button::L);
+        let mut r = InputReport::new();
+        r.press_one(&button).unwrap();
         // L is in byte 2 offset 6
         assert_eq!(r.report, [0x00, 0x80, 0x40]);
     }
+
+    #[test]
+    fn button_names_round_trip() {
+        for button in Button::ALL.iter() {
+            assert_eq!(Button::from_name(button.name()), Some(button.clone()));
+        }
+        assert_eq!(Button::from_name("dpadup"), Some(Button::DpadUp));
+        assert_eq!(Button::from_name("Start"), None);
+    }
+
+    #[test]
+    fn parses_pitch_names() {
+        assert_eq!(Pitch::from_name("C"), Some(Pitch::C));
+        assert_eq!(Pitch::from_name("F#"), Some(Pitch::FSharp));
+        assert_eq!(Pitch::from_name("Db"), Some(Pitch::CSharp));
+        assert_eq!(Pitch::from_name("Cb"), Some(Pitch::B));
+        assert_eq!(Pitch::from_name("H"), None);
+        assert_eq!(Pitch::from_name("C##"), None);
+        for pitch in Pitch::ALL.iter() {
+            assert_eq!(Pitch::from_name(pitch.name()), Some(pitch.clone()));
+        }
+    }
 }
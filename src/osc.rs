@@ -0,0 +1,549 @@
+use crate::midi::{MidiMessageData, MidiMessageTypes};
+use crate::nscontroller::Button;
+use crate::profile::{Axis, Overlay, Profile, Target};
+use crate::report::StickPosition;
+use std::error::Error;
+use std::fmt;
+
+/// Addresses of the sticks and the axes they set
+const STICKS: [(&str, Axis, Axis); 2] = [
+    ("/stick/left", Axis::LeftStickX, Axis::LeftStickY),
+    ("/stick/right", Axis::RightStickX, Axis::RightStickY),
+];
+
+/// Nested bundles deeper than this are rejected
+const MAX_BUNDLE_DEPTH: usize = 8;
+
+/// One typed OSC argument
+#[derive(Debug, PartialEq, Clone)]
+pub enum Argument {
+    Int(i32),
+    Float(f32),
+    Long(i64),
+    Double(f64),
+    String(String),
+    Blob(Vec<u8>),
+    True,
+    False,
+    Nil,
+    Impulse,
+}
+
+impl Argument {
+    /// Numeric value, booleans count as 1 and 0
+    pub fn number(&self) -> Option<f32> {
+        match self {
+            Argument::Int(value) => Some(*value as f32),
+            Argument::Float(value) => Some(*value),
+            Argument::Long(value) => Some(*value as f32),
+            Argument::Double(value) => Some(*value as f32),
+            Argument::True => Some(1.0),
+            Argument::False => Some(0.0),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Argument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Argument::Int(value) => write!(f, "{}", value),
+            Argument::Float(value) => write!(f, "{}", value),
+            Argument::Long(value) => write!(f, "{}", value),
+            Argument::Double(value) => write!(f, "{}", value),
+            Argument::String(value) => write!(f, "{:?}", value),
+            Argument::Blob(bytes) => write!(f, "<{} bytes>", bytes.len()),
+            Argument::True => write!(f, "true"),
+            Argument::False => write!(f, "false"),
+            Argument::Nil => write!(f, "nil"),
+            Argument::Impulse => write!(f, "impulse"),
+        }
+    }
+}
+
+/// OSC message, the address may be a pattern matching several addresses
+#[derive(Debug, PartialEq, Clone)]
+pub struct OscMessage {
+    pub address: String,
+    pub arguments: Vec<Argument>,
+}
+
+impl OscMessage {
+    /// Numeric value of an argument, None when missing or not a number
+    pub fn number(&self, index: usize) -> Option<f32> {
+        self.arguments.get(index)?.number()
+    }
+}
+
+impl fmt::Display for OscMessage {
+    /// Written like the examples, `/stick/left 0.5 -1`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.address)?;
+        for argument in self.arguments.iter() {
+            write!(f, " {}", argument)?;
+        }
+        Ok(())
+    }
+}
+
+/// Messages of one UDP datagram, bundles are flattened and their time tags ignored
+///
+/// ```text
+/// message: address string | type tags string ",ifs" | arguments
+/// bundle:  "#bundle" | time tag u64 | size i32 | element | size i32 | element ..
+/// ```
+/// Strings are NUL terminated and padded to a multiple of 4 bytes, numbers are big endian.
+pub fn parse_packet(packet: &[u8]) -> Result<Vec<OscMessage>, Box<dyn Error>> {
+    let mut messages = Vec::new();
+    parse_element(packet, 0, &mut messages)?;
+    Ok(messages)
+}
+
+fn parse_element(packet: &[u8], depth: usize, messages: &mut Vec<OscMessage>) -> Result<(), Box<dyn Error>> {
+    let mut reader = Reader { packet, position: 0 };
+    if packet.starts_with(b"#bundle\0") {
+        if depth >= MAX_BUNDLE_DEPTH {
+            return Err("OSC bundles nested too deep".into());
+        }
+        reader.position = 16;
+        while reader.position < packet.len() {
+            let size = reader.int()?;
+            let element = reader.take(usize::try_from(size).map_err(|_| "Negative OSC bundle element size")?)?;
+            parse_element(element, depth + 1, messages)?;
+        }
+        return Ok(());
+    }
+
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        return Err(format!("OSC address {:?} does not start with /", address).into());
+    }
+    // Very old senders leave out the type tags, their messages carry no arguments
+    let tags = match reader.position < packet.len() {
+        true => reader.string()?,
+        false => String::from(","),
+    };
+    let tags = tags.strip_prefix(',').ok_or("OSC type tags do not start with ,")?;
+    let mut arguments = Vec::new();
+    for tag in tags.chars() {
+        arguments.push(match tag {
+            'i' => Argument::Int(reader.int()?),
+            'f' => Argument::Float(f32::from_bits(reader.int()? as u32)),
+            'h' => Argument::Long(reader.long()?),
+            'd' => Argument::Double(f64::from_bits(reader.long()? as u64)),
+            's' | 'S' => Argument::String(reader.string()?),
+            'b' => {
+                let size = usize::try_from(reader.int()?).map_err(|_| "Negative OSC blob size")?;
+                let blob = reader.take(size)?.to_vec();
+                reader.position += (4 - size % 4) % 4;
+                Argument::Blob(blob)
+            }
+            'T' => Argument::True,
+            'F' => Argument::False,
+            'N' => Argument::Nil,
+            'I' => Argument::Impulse,
+            other => return Err(format!("Unsupported OSC type tag {:?}", other).into()),
+        });
+    }
+    messages.push(OscMessage { address, arguments });
+    Ok(())
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let bytes = self
+            .packet
+            .get(self.position..self.position + length)
+            .ok_or("Truncated OSC packet")?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn int(&mut self) -> Result<i32, Box<dyn Error>> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn long(&mut self) -> Result<i64, Box<dyn Error>> {
+        let bytes = self.take(8)?;
+        Ok(i64::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]))
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn Error>> {
+        let rest = self.packet.get(self.position..).unwrap_or_default();
+        let length = rest.iter().position(|byte| *byte == 0).ok_or("Unterminated OSC string")?;
+        let text = String::from_utf8_lossy(&rest[..length]).to_string();
+        self.position += (length / 4 + 1) * 4;
+        Ok(text)
+    }
+}
+
+/// OSC 1.0 address pattern matching
+///
+/// `?` matches one character and `*` any number of them, both never match `/`.
+/// `[a-c]` matches one character of the list or range, `[!a-c]` one that is not.
+/// `{left,right}` matches any of the listed strings.
+pub fn pattern_matches(pattern: &str, address: &str) -> bool {
+    matches(pattern.as_bytes(), address.as_bytes())
+}
+
+fn matches(pattern: &[u8], address: &[u8]) -> bool {
+    match pattern.first() {
+        None => address.is_empty(),
+        Some(b'*') => (0..=address.len())
+            .take_while(|skipped| *skipped == 0 || address[skipped - 1] != b'/')
+            .any(|skipped| matches(&pattern[1..], &address[skipped..])),
+        Some(b'?') => address.first().is_some_and(|c| *c != b'/') && matches(&pattern[1..], &address[1..]),
+        Some(b'[') => {
+            let end = match pattern.iter().position(|c| *c == b']') {
+                Some(end) => end,
+                None => return address.first() == Some(&b'[') && matches(&pattern[1..], &address[1..]),
+            };
+            let (negated, class) = match pattern[1..end].strip_prefix(b"!") {
+                Some(class) => (true, class),
+                None => (false, &pattern[1..end]),
+            };
+            match address.first() {
+                Some(c) if *c != b'/' => in_class(class, *c) != negated && matches(&pattern[end + 1..], &address[1..]),
+                _ => false,
+            }
+        }
+        Some(b'{') => {
+            let end = match pattern.iter().position(|c| *c == b'}') {
+                Some(end) => end,
+                None => return address.first() == Some(&b'{') && matches(&pattern[1..], &address[1..]),
+            };
+            pattern[1..end]
+                .split(|c| *c == b',')
+                .any(|choice| address.starts_with(choice) && matches(&pattern[end + 1..], &address[choice.len()..]))
+        }
+        Some(c) => address.first() == Some(c) && matches(&pattern[1..], &address[1..]),
+    }
+}
+
+fn in_class(class: &[u8], c: u8) -> bool {
+    let mut position = 0;
+    while position < class.len() {
+        if position + 2 < class.len() && class[position + 1] == b'-' {
+            if (class[position]..=class[position + 2]).contains(&c) {
+                return true;
+            }
+            position += 3;
+        } else {
+            if class[position] == c {
+                return true;
+            }
+            position += 1;
+        }
+    }
+    false
+}
+
+/// Buttons, sticks and notes held through OSC
+///
+/// Built in are `/button/<name> 1|0`, `/stick/left x y` and `/stick/right x y`
+/// with both axes from -1 to 1 (Y grows upwards), `/note <number> <velocity>`
+/// and `/release`. The `osc` lines of the active profile add more addresses.
+/// Notes go through the profile like MIDI notes, everything else overrides
+/// the controller input until it is released again.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct OscState {
+    notes: Vec<MidiMessageData>,
+    buttons: Vec<Button>,
+    /// Stick axes that are off center, from -1 to 1
+    axes: Vec<(Axis, f32)>,
+}
+
+impl OscState {
+    /// Applies a message to every address its pattern matches, false when none does
+    ///
+    /// Profile addresses may be patterns themselves. A message with a NaN or
+    /// infinite argument is ignored, clamping would turn it into a full deflection.
+    pub fn apply(&mut self, message: &OscMessage, profile: &Profile) -> bool {
+        if message.arguments.iter().any(|argument| argument.number().is_some_and(|value| !value.is_finite())) {
+            return false;
+        }
+        let address = message.address.as_str();
+        let value = message.number(0).unwrap_or(1.0);
+        let mut matched = false;
+
+        for (mapped, target) in profile.osc_mappings() {
+            if pattern_matches(address, mapped) || pattern_matches(mapped, address) {
+                self.set_target(target, value);
+                matched = true;
+            }
+        }
+        for button in Button::ALL.iter() {
+            if pattern_matches(address, &format!("/button/{}", button.name())) {
+                self.set_target(&Target::Button(button.clone()), value);
+                matched = true;
+            }
+        }
+        for (stick, x_axis, y_axis) in STICKS.iter() {
+            if let (true, Some(x), Some(y)) = (pattern_matches(address, stick), message.number(0), message.number(1)) {
+                self.set_axis(*x_axis, x);
+                self.set_axis(*y_axis, y);
+                matched = true;
+            }
+        }
+        if let (true, Some(note)) = (pattern_matches(address, "/note"), message.number(0)) {
+            if (0.0..128.0).contains(&note) {
+                let velocity = message.number(1).unwrap_or(127.0).clamp(0.0, 127.0) as u8;
+                self.notes.retain(|held| held.data_byte1 != note as u8);
+                if velocity > 0 {
+                    self.notes.push(MidiMessageData::midi1(MidiMessageTypes::NoteOn, note as u8, velocity));
+                }
+                matched = true;
+            }
+        }
+        if pattern_matches(address, "/release") {
+            *self = OscState::default();
+            matched = true;
+        }
+        matched
+    }
+
+    /// Held notes, the profile maps them together with the MIDI ones
+    pub fn notes(&self) -> &[MidiMessageData] {
+        &self.notes
+    }
+
+    /// Presses the held buttons and moves the sticks of the off center axes
+    pub fn apply_to(&self, overlay: &mut Overlay) {
+        for button in self.buttons.iter() {
+            overlay.press(button);
+        }
+        let position = |value: f32| {
+            let center = StickPosition::CENTER.x as f32;
+            let range = if value < 0.0 { center } else { StickPosition::MAX as f32 - center };
+            (center + value * range).round() as u16
+        };
+        for (stick, (_, x_axis, y_axis)) in [&mut overlay.left_stick, &mut overlay.right_stick].into_iter().zip(STICKS.iter()) {
+            for (axis, value) in self.axes.iter() {
+                if axis == x_axis {
+                    stick.get_or_insert(StickPosition::CENTER).x = position(*value);
+                } else if axis == y_axis {
+                    stick.get_or_insert(StickPosition::CENTER).y = position(*value);
+                }
+            }
+        }
+    }
+
+    fn set_target(&mut self, target: &Target, value: f32) {
+        match target {
+            Target::Button(button) => {
+                self.buttons.retain(|held| held != button);
+                if value >= 0.5 {
+                    self.buttons.push(button.clone());
+                }
+            }
+            Target::Axis(axis) => self.set_axis(*axis, value),
+            Target::HalfAxis { axis, positive } => {
+                let value = value.clamp(0.0, 1.0);
+                self.set_axis(*axis, if *positive { value } else { -value })
+            }
+            // Profiles do not map OSC addresses to octave shifts
+            Target::Octave(_) => {}
+        }
+    }
+
+    /// A centered axis is released, the controller input passes through again
+    fn set_axis(&mut self, axis: Axis, value: f32) {
+        self.axes.retain(|(held, _)| *held != axis);
+        let value = value.clamp(-1.0, 1.0);
+        if value != 0.0 {
+            self.axes.push((axis, value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn padded(bytes: &mut Vec<u8>, text: &str) {
+        bytes.extend_from_slice(text.as_bytes());
+        bytes.push(0);
+        while !bytes.len().is_multiple_of(4) {
+            bytes.push(0);
+        }
+    }
+
+    /// Encodes a message with int, float and string arguments
+    fn encode(address: &str, arguments: &[Argument]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        padded(&mut bytes, address);
+        let mut tags = String::from(",");
+        let mut data = Vec::new();
+        for argument in arguments {
+            match argument {
+                Argument::Int(value) => {
+                    tags.push('i');
+                    data.extend_from_slice(&value.to_be_bytes());
+                }
+                Argument::Float(value) => {
+                    tags.push('f');
+                    data.extend_from_slice(&value.to_be_bytes());
+                }
+                Argument::String(value) => {
+                    tags.push('s');
+                    padded(&mut data, value);
+                }
+                Argument::True => tags.push('T'),
+                other => panic!("not supported in tests: {:?}", other),
+            }
+        }
+        padded(&mut bytes, &tags);
+        bytes.extend(data);
+        bytes
+    }
+
+    fn message(address: &str, arguments: &[Argument]) -> OscMessage {
+        OscMessage {
+            address: address.to_string(),
+            arguments: arguments.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_messages() {
+        let packet = encode("/stick/left", &[Argument::Float(0.5), Argument::Int(-1), Argument::String(String::from("kart"))]);
+        assert_eq!(
+            parse_packet(&packet).unwrap(),
+            vec![message("/stick/left", &[Argument::Float(0.5), Argument::Int(-1), Argument::String(String::from("kart"))])]
+        );
+        assert_eq!(parse_packet(&encode("/release", &[])).unwrap(), vec![message("/release", &[])]);
+        // Without type tags
+        assert_eq!(parse_packet(b"/release\0\0\0\0").unwrap(), vec![message("/release", &[])]);
+        assert_eq!(
+            parse_packet(&encode("/button/A", &[Argument::True])).unwrap()[0].to_string(),
+            "/button/A true"
+        );
+    }
+
+    #[test]
+    fn flattens_bundles() {
+        let mut inner = Vec::new();
+        padded(&mut inner, "#bundle");
+        inner.extend_from_slice(&1u64.to_be_bytes());
+        let note = encode("/note", &[Argument::Int(60), Argument::Int(127)]);
+        inner.extend_from_slice(&(note.len() as i32).to_be_bytes());
+        inner.extend(note);
+
+        let mut bundle = Vec::new();
+        padded(&mut bundle, "#bundle");
+        bundle.extend_from_slice(&1u64.to_be_bytes());
+        let button = encode("/button/A", &[Argument::Int(1)]);
+        bundle.extend_from_slice(&(button.len() as i32).to_be_bytes());
+        bundle.extend(button);
+        bundle.extend_from_slice(&(inner.len() as i32).to_be_bytes());
+        bundle.extend(inner);
+
+        let messages = parse_packet(&bundle).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].address, "/button/A");
+        assert_eq!(messages[1].to_string(), "/note 60 127");
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert!(parse_packet(b"button\0\0,i\0\0\0\0\0\x01").is_err());
+        assert!(parse_packet(b"/button/A\0\0\0,i\0\0\0\0").is_err());
+        assert!(parse_packet(b"/button/A\0\0\0,x\0\0").is_err());
+        assert!(parse_packet(b"/button/A").is_err());
+    }
+
+    #[test]
+    fn matches_address_patterns() {
+        assert!(pattern_matches("/button/A", "/button/A"));
+        assert!(!pattern_matches("/button/A", "/button/ZR"));
+        assert!(pattern_matches("/button/*", "/button/ZR"));
+        assert!(!pattern_matches("/*", "/button/ZR"));
+        assert!(pattern_matches("/*/*", "/button/ZR"));
+        assert!(pattern_matches("/button/Z?", "/button/ZL"));
+        assert!(!pattern_matches("/button/Z?", "/button/Z"));
+        assert!(pattern_matches("/button/[AB]", "/button/B"));
+        assert!(pattern_matches("/button/[A-C]", "/button/B"));
+        assert!(!pattern_matches("/button/[!A-C]", "/button/B"));
+        assert!(pattern_matches("/stick/{left,right}", "/stick/right"));
+        assert!(!pattern_matches("/stick/{left,right}", "/stick/up"));
+    }
+
+    #[test]
+    fn holds_buttons_sticks_and_notes() {
+        let profile = Profile::default();
+        let mut state = OscState::default();
+        assert!(state.apply(&message("/button/A", &[Argument::Int(1)]), &profile));
+        assert!(state.apply(&message("/stick/left", &[Argument::Float(-1.0), Argument::Float(1.0)]), &profile));
+        assert!(state.apply(&message("/note", &[Argument::Int(60), Argument::Int(100)]), &profile));
+        assert!(!state.apply(&message("/button/Turbo", &[Argument::Int(1)]), &profile));
+        assert!(!state.apply(&message("/stick/left", &[Argument::Float(1.0)]), &profile));
+        assert_eq!(state.notes().len(), 1);
+
+        let mut overlay = Overlay::default();
+        state.apply_to(&mut overlay);
+        assert!(overlay.buttons.unwrap().is_pressed(&Button::A));
+        assert_eq!(overlay.left_stick, Some(StickPosition { x: 0, y: StickPosition::MAX }));
+        assert_eq!(overlay.right_stick, None);
+
+        state.apply(&message("/button/A", &[Argument::Int(0)]), &profile);
+        state.apply(&message("/note", &[Argument::Int(60), Argument::Int(0)]), &profile);
+        state.apply(&message("/stick/left", &[Argument::Int(0), Argument::Int(0)]), &profile);
+        assert_eq!(state, OscState::default());
+    }
+
+    #[test]
+    fn follows_profile_addresses() {
+        let profile = Profile::parse(
+            "show",
+            "osc /kart/gas = ZR\nosc /kart/steer = LeftStickX\nosc /kart/brake = RightStickY-\n",
+        )
+        .unwrap();
+        let mut state = OscState::default();
+        state.apply(&message("/kart/gas", &[Argument::True]), &profile);
+        state.apply(&message("/kart/steer", &[Argument::Float(0.5)]), &profile);
+        state.apply(&message("/kart/brake", &[Argument::Float(1.0)]), &profile);
+
+        let mut overlay = Overlay::default();
+        state.apply_to(&mut overlay);
+        assert!(overlay.buttons.unwrap().is_pressed(&Button::ZR));
+        assert_eq!(overlay.left_stick, Some(StickPosition { x: 0xC00, y: 0x800 }));
+        assert_eq!(overlay.right_stick, Some(StickPosition { x: 0x800, y: 0 }));
+
+        // One pattern can release several addresses at once
+        state.apply(&message("/kart/*", &[Argument::Int(0)]), &profile);
+        assert_eq!(state, OscState::default());
+        state.apply(&message("/button/*", &[Argument::Int(1)]), &profile);
+        state.apply(&message("/release", &[]), &profile);
+        assert_eq!(state, OscState::default());
+    }
+
+    #[test]
+    fn profile_addresses_may_be_patterns() {
+        let profile = Profile::parse("show", "osc /deck/{1,2}/play = A\nosc /fader/* = LeftStickY\n").unwrap();
+        let mut state = OscState::default();
+        assert!(state.apply(&message("/deck/2/play", &[Argument::Int(1)]), &profile));
+        assert!(state.apply(&message("/fader/volume", &[Argument::Float(1.0)]), &profile));
+        assert!(!state.apply(&message("/deck/3/play", &[Argument::Int(1)]), &profile));
+
+        let mut overlay = Overlay::default();
+        state.apply_to(&mut overlay);
+        assert!(overlay.buttons.unwrap().is_pressed(&Button::A));
+        assert_eq!(overlay.left_stick, Some(StickPosition { x: 0x800, y: StickPosition::MAX }));
+    }
+
+    #[test]
+    fn ignores_arguments_that_are_not_finite() {
+        let profile = Profile::parse("show", "osc /kart/steer = LeftStickX\n").unwrap();
+        let mut state = OscState::default();
+        assert!(!state.apply(&message("/stick/left", &[Argument::Float(f32::NAN), Argument::Float(0.0)]), &profile));
+        assert!(!state.apply(&message("/kart/steer", &[Argument::Double(f64::INFINITY)]), &profile));
+        assert!(!state.apply(&message("/note", &[Argument::Float(f32::NAN)]), &profile));
+        assert_eq!(state, OscState::default());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::midi::{MidiMessageData, MidiMessageTypes, RESET_ALL_CONTROLLERS};
+
+/// Controller of the damper (sustain) pedal
+pub const SUSTAIN: u8 = 64;
+/// Controller of the sostenuto pedal
+pub const SOSTENUTO: u8 = 66;
+
+/// Damper and sostenuto pedals of a piano, applied to the entries of the MIDI state
+///
+/// While the damper pedal is down released notes stay held. The sostenuto pedal
+/// keeps only the notes that were held when it went down. The releases are
+/// sent once the pedals come up. The pedal controllers themselves pass through,
+/// so a profile can still map them to buttons.
+#[derive(Debug, Default)]
+pub struct Pedals {
+    damper: bool,
+    sostenuto: bool,
+    /// Keys that are down
+    keys: Vec<u8>,
+    /// Released notes the damper pedal holds
+    sustained: Vec<u8>,
+    /// Notes held when the sostenuto pedal went down
+    caught: Vec<u8>,
+}
+
+impl Pedals {
+    /// Adds the entries for `midi_data` to `out`, releases held by a pedal are left out.
+    /// Without `enabled` entries pass unchanged and notes held by a pedal are released.
+    /// All Notes Off forgets held notes, Reset All Controllers puts both pedals up.
+    pub fn apply(&mut self, midi_data: MidiMessageData, enabled: bool, out: &mut Vec<MidiMessageData>) {
+        if midi_data.releases_all_notes() {
+            // The state drops every note, held by a pedal or not, the pedals stay where they are
+            self.keys.clear();
+            self.sustained.clear();
+            self.caught.clear();
+            out.push(midi_data);
+            return;
+        }
+        // Resetting the controllers puts both pedals up
+        let reset = midi_data.is_control_change() && midi_data.data_byte1 == RESET_ALL_CONTROLLERS;
+        if !enabled || reset {
+            if self.damper || self.sostenuto || !self.sustained.is_empty() || !self.caught.is_empty() {
+                self.damper = false;
+                self.sostenuto = false;
+                let held = [std::mem::take(&mut self.sustained), std::mem::take(&mut self.caught)].concat();
+                self.release(held, out);
+            }
+            self.track_key(&midi_data);
+            out.push(midi_data);
+            return;
+        }
+
+        let note = midi_data.data_byte1;
+        if midi_data.should_add_midi_message() {
+            self.track_key(&midi_data);
+            self.sustained.retain(|held| *held != note);
+        } else if midi_data.should_remove_midi_message() {
+            self.track_key(&midi_data);
+            if self.damper {
+                if !self.sustained.contains(&note) {
+                    self.sustained.push(note);
+                }
+                return;
+            }
+            if self.sostenuto && self.caught.contains(&note) {
+                return;
+            }
+        } else if midi_data.is_control_change() && midi_data.data_byte1 == SUSTAIN {
+            let down = midi_data.data_byte2 >= 64;
+            if self.damper && !down {
+                // Notes the sostenuto pedal still holds are released with it
+                let mut released = std::mem::take(&mut self.sustained);
+                if self.sostenuto {
+                    released.retain(|note| !self.caught.contains(note));
+                }
+                self.release(released, out);
+            }
+            self.damper = down;
+        } else if midi_data.is_control_change() && midi_data.data_byte1 == SOSTENUTO {
+            let down = midi_data.data_byte2 >= 64;
+            if !self.sostenuto && down {
+                self.caught = self.keys.clone();
+            } else if self.sostenuto && !down {
+                let mut released = std::mem::take(&mut self.caught);
+                released.retain(|note| !self.keys.contains(note));
+                if self.damper {
+                    // Now held by the damper pedal instead
+                    for note in released.drain(..) {
+                        if !self.sustained.contains(&note) {
+                            self.sustained.push(note);
+                        }
+                    }
+                }
+                self.release(released, out);
+            }
+            self.sostenuto = down;
+        }
+        out.push(midi_data);
+    }
+
+    fn track_key(&mut self, midi_data: &MidiMessageData) {
+        let note = midi_data.data_byte1;
+        if midi_data.should_add_midi_message() {
+            if !self.keys.contains(&note) {
+                self.keys.push(note);
+            }
+        } else if midi_data.should_remove_midi_message() {
+            self.keys.retain(|key| *key != note);
+        }
+    }
+
+    /// Note offs for the notes that are not down any more
+    fn release(&self, notes: Vec<u8>, out: &mut Vec<MidiMessageData>) {
+        for note in notes {
+            if !self.keys.contains(&note) {
+                out.push(MidiMessageData::midi1(MidiMessageTypes::NoteOff, note, 0));
+            }
+        }
+    }
+
+    /// Notes a pedal holds whose keys are up
+    pub fn held(&self) -> Vec<u8> {
+        let mut held: Vec<u8> = self.sustained.iter().chain(self.caught.iter()).copied().collect();
+        held.retain(|note| !self.keys.contains(note));
+        held.sort_unstable();
+        held.dedup();
+        held
+    }
+
+    /// Forgets notes that were released elsewhere, the pedals stay where they are
+    pub fn forget(&mut self, notes: &[u8]) {
+        self.keys.retain(|note| !notes.contains(note));
+        self.sustained.retain(|note| !notes.contains(note));
+        self.caught.retain(|note| !notes.contains(note));
+    }
+
+    /// Forgets keys and pedals, for inputs that went away
+    pub fn clear(&mut self) {
+        *self = Pedals::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{control, note_off, note_on};
+
+    fn apply(pedals: &mut Pedals, entries: &[MidiMessageData]) -> Vec<MidiMessageData> {
+        let mut out = Vec::new();
+        for midi_data in entries {
+            pedals.apply(midi_data.clone(), true, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn damper_holds_released_notes() {
+        let mut pedals = Pedals::default();
+        assert_eq!(
+            apply(&mut pedals, &[note_on(60, 100), control(SUSTAIN, 127), note_on(60, 0), note_on(62, 100), note_off(62)]),
+            vec![note_on(60, 100), control(SUSTAIN, 127), note_on(62, 100)]
+        );
+        // Struck again while sustained, the key holds it past the pedal
+        assert_eq!(apply(&mut pedals, &[note_on(60, 100)]), vec![note_on(60, 100)]);
+        assert_eq!(pedals.held(), vec![62]);
+        assert_eq!(apply(&mut pedals, &[control(SUSTAIN, 0)]), vec![note_off(62), control(SUSTAIN, 0)]);
+        assert!(pedals.held().is_empty());
+        assert_eq!(apply(&mut pedals, &[note_on(60, 0)]), vec![note_on(60, 0)]);
+    }
+
+    #[test]
+    fn sostenuto_holds_only_notes_down_before_it() {
+        let mut pedals = Pedals::default();
+        apply(&mut pedals, &[note_on(48, 100), control(SOSTENUTO, 127)]);
+        assert_eq!(
+            apply(&mut pedals, &[note_on(48, 0), note_on(60, 100), note_on(60, 0)]),
+            vec![note_on(60, 100), note_on(60, 0)]
+        );
+        // Both pedals, the note stays with the damper after the sostenuto comes up
+        apply(&mut pedals, &[control(SUSTAIN, 127)]);
+        assert_eq!(apply(&mut pedals, &[control(SOSTENUTO, 0)]), vec![control(SOSTENUTO, 0)]);
+        assert_eq!(apply(&mut pedals, &[control(SUSTAIN, 0)]), vec![note_off(48), control(SUSTAIN, 0)]);
+    }
+
+    #[test]
+    fn disabling_releases_held_notes() {
+        let mut pedals = Pedals::default();
+        apply(&mut pedals, &[control(SUSTAIN, 127), note_on(60, 100), note_on(60, 0)]);
+        let mut out = Vec::new();
+        pedals.apply(note_on(62, 100), false, &mut out);
+        assert_eq!(out, vec![note_off(60), note_on(62, 100)]);
+    }
+}
@@ -0,0 +1,778 @@
+use crate::clock::PULSES_PER_WHOLE;
+use crate::midi::{MidiMessageData, MidiMessageTypes, ALL_SOUND_OFF};
+use crate::nscontroller::{Button, InputReport, Pitch};
+use crate::report::{ProControllerReport, StickPosition};
+use crate::ump::CENTER_32;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// File extension of profiles in a profile directory
+pub const PROFILE_EXTENSION: &str = "profile";
+
+/// Octaves the keyboard can be shifted either way at runtime
+pub const MAX_OCTAVE_SHIFT: i8 = 10;
+
+/// Distance from the stick center to either end
+const STICK_UP: i32 = StickPosition::MAX as i32 - StickPosition::CENTER.x as i32;
+const STICK_DOWN: i32 = StickPosition::CENTER.x as i32;
+
+/// Stick axis, Y grows upwards like in the input report
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Axis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+}
+
+impl Axis {
+    pub const ALL: [Axis; 4] = [Axis::LeftStickX, Axis::LeftStickY, Axis::RightStickX, Axis::RightStickY];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Axis::LeftStickX => "LeftStickX",
+            Axis::LeftStickY => "LeftStickY",
+            Axis::RightStickX => "RightStickX",
+            Axis::RightStickY => "RightStickY",
+        }
+    }
+}
+
+/// What a note or controller drives
+#[derive(Debug, PartialEq, Clone)]
+pub enum Target {
+    Button(Button),
+    /// The controller value covers the whole axis, 64 is the center
+    Axis(Axis),
+    /// Pushes the axis from the center towards one end, notes all the way
+    HalfAxis { axis: Axis, positive: bool },
+    /// Shifts every other note by this many octaves while the profile is active
+    Octave(i8),
+}
+
+impl Target {
+    /// Parses `A`, `LeftStickX`, `LeftStickX+`/`LeftStickX-` or `OctaveUp`/`OctaveDown`, case insensitive
+    pub fn from_name(name: &str) -> Option<Target> {
+        if let Some(button) = Button::from_name(name) {
+            return Some(Target::Button(button));
+        }
+        if name.eq_ignore_ascii_case("OctaveUp") {
+            return Some(Target::Octave(1));
+        }
+        if name.eq_ignore_ascii_case("OctaveDown") {
+            return Some(Target::Octave(-1));
+        }
+        let (axis_name, positive) = match name.strip_suffix('+') {
+            Some(axis_name) => (axis_name, Some(true)),
+            None => match name.strip_suffix('-') {
+                Some(axis_name) => (axis_name, Some(false)),
+                None => (name, None),
+            },
+        };
+        let axis = *Axis::ALL
+            .iter()
+            .find(|axis| axis.name().eq_ignore_ascii_case(axis_name))?;
+        Some(match positive {
+            Some(positive) => Target::HalfAxis { axis, positive },
+            None => Target::Axis(axis),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Target::Button(button) => button.name().to_string(),
+            Target::Axis(axis) => axis.name().to_string(),
+            Target::HalfAxis { axis, positive } => format!("{}{}", axis.name(), if *positive { "+" } else { "-" }),
+            Target::Octave(octaves) => String::from(if *octaves > 0 { "OctaveUp" } else { "OctaveDown" }),
+        }
+    }
+}
+
+/// Range of the keyboard with mappings of its own, e.g. the lower octaves for the D-pad
+#[derive(Debug, PartialEq, Clone)]
+struct Zone {
+    name: String,
+    low: u8,
+    high: u8,
+    /// Semitones added to notes of the zone before they are looked up
+    transpose: i8,
+    pitches: HashMap<Pitch, Target>,
+    notes: HashMap<u8, Target>,
+}
+
+impl Zone {
+    fn contains(&self, note: u8) -> bool {
+        (self.low..=self.high).contains(&note)
+    }
+}
+
+/// What MIDI changes in an input report, None parts keep the controller's input
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Overlay {
+    pub buttons: Option<InputReport>,
+    pub left_stick: Option<StickPosition>,
+    pub right_stick: Option<StickPosition>,
+}
+
+impl Overlay {
+    pub fn is_empty(&self) -> bool {
+        self.buttons.is_none() && self.left_stick.is_none() && self.right_stick.is_none()
+    }
+
+    pub fn press(&mut self, button: &Button) {
+        // Every Button has a position in the report
+        let _ = self.buttons.get_or_insert_with(InputReport::new).press_one(button);
+    }
+
+    pub fn apply(&self, report: &mut ProControllerReport) {
+        if let Some(buttons) = &self.buttons {
+            report.buttons = buttons.clone();
+        }
+        if let Some(stick) = &self.left_stick {
+            report.left_stick = *stick;
+        }
+        if let Some(stick) = &self.right_stick {
+            report.right_stick = *stick;
+        }
+    }
+}
+
+/// Mapping from MIDI notes and controllers to controller buttons and sticks
+///
+/// Profiles are plain text files, one mapping per line:
+/// ```text
+/// # Mario Kart
+/// name = Kart
+/// quantize = 1/16         # notes wait for the next sixteenth of the MIDI clock
+/// sustain = on            # the sustain (CC 64) and sostenuto (CC 66) pedals hold notes
+/// note C = A              # every C on the keyboard presses A
+/// note F# = ZR
+/// note 60 = B             # only middle C, wins over the pitch mapping
+/// note D = LeftStickX-    # steer left while held
+/// cc 1 = LeftStickX       # modulation wheel steers, 64 is straight
+/// cc 64 = L               # sustain pedal pressed from value 64
+/// bend 60 = RightStickY   # MIDI 2.0 per-note pitch bend of middle C, no bend is centered
+/// osc /kart/gas = ZR      # OSC message with 1 presses, 0 releases, the address may be a pattern
+/// osc /kart/steer = LeftStickX  # OSC value from -1 to 1
+/// transpose = -12         # every note an octave down before it is looked up
+/// note 21 = OctaveUp      # the lowest A moves the keyboard up an octave
+/// cc 20 = OctaveDown      # as does a controller going from below 64 to 64 or more
+/// zone pad = 36-59        # split, notes 36 to 59 use mappings of their own
+/// zone pad transpose = 12
+/// note C in pad = DpadLeft
+/// ```
+/// A note is shifted by `transpose` and the octave shift first, then by the
+/// transpose of the zone it falls into. The zone's mappings come first,
+/// the others apply to every note without a mapping in its zone.
+/// Without a `name` line the file name (without extension) is used.
+/// Comments start with a `#` at the beginning of a line or after whitespace.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Profile {
+    pub name: String,
+    /// Clock pulses of the quantization grid, notes are not delayed without it
+    pub quantize: Option<u32>,
+    /// Piano pedal semantics, see `Pedals`
+    pub sustain: bool,
+    pitches: HashMap<Pitch, Target>,
+    notes: HashMap<u8, Target>,
+    controls: HashMap<u8, Target>,
+    bends: HashMap<u8, Target>,
+    osc: HashMap<String, Target>,
+    /// Semitones added to every note, octave shift sources are matched before
+    transpose: i8,
+    zones: Vec<Zone>,
+}
+
+impl Default for Profile {
+    /// The mapping used before profiles existed, one octave spread over the buttons
+    fn default() -> Self {
+        let mut profile = Profile::new("default");
+        for pitch in Pitch::ALL.iter() {
+            if let Some(button) = pitch.default_button() {
+                profile.map_pitch(pitch.clone(), Target::Button(button));
+            }
+        }
+        profile
+    }
+}
+
+impl Profile {
+    /// Profile without any mapping
+    pub fn new(name: &str) -> Profile {
+        Profile {
+            name: name.to_string(),
+            quantize: None,
+            sustain: false,
+            pitches: HashMap::new(),
+            notes: HashMap::new(),
+            controls: HashMap::new(),
+            bends: HashMap::new(),
+            osc: HashMap::new(),
+            transpose: 0,
+            zones: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Profile, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Unable to read profile {}: {}", path.display(), e))?;
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Profile::parse(&name, &text).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Loads every `*.profile` file of a directory, sorted by file name
+    pub fn load_dir(dir: &Path) -> Result<Vec<Profile>, Box<dyn Error>> {
+        let entries = fs::read_dir(dir).map_err(|e| format!("Unable to read profile directory {}: {}", dir.display(), e))?;
+        let mut paths: Vec<_> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == PROFILE_EXTENSION))
+            .collect();
+        paths.sort();
+        paths.iter().map(|path| Profile::load(path)).collect()
+    }
+
+    /// Parses the profile format, `name` is used unless the text sets one
+    pub fn parse(name: &str, text: &str) -> Result<Profile, Box<dyn Error>> {
+        let mut profile = Profile::new(name);
+        for (number, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            profile
+                .parse_line(line)
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+        }
+        Ok(profile)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("expected <key> = <value>, got {:?}", line))?;
+        let key = key.trim();
+        let value = value.trim();
+
+        if key == "name" {
+            if value.is_empty() {
+                return Err("empty profile name".into());
+            }
+            self.name = value.to_string();
+            return Ok(());
+        }
+        if key == "quantize" {
+            // 1/4 is a quarter note, 1/12 an eighth note triplet
+            let grid = value
+                .strip_prefix("1/")
+                .and_then(|division| division.parse::<u32>().ok())
+                .filter(|division| *division > 0 && PULSES_PER_WHOLE.is_multiple_of(*division))
+                .ok_or_else(|| format!("quantize expects a note value such as 1/16, got {:?}", value))?;
+            self.quantize = Some(PULSES_PER_WHOLE / grid);
+            return Ok(());
+        }
+        if key == "sustain" {
+            self.sustain = match value {
+                "on" => true,
+                "off" => false,
+                _ => return Err(format!("sustain is on or off, got {:?}", value).into()),
+            };
+            return Ok(());
+        }
+        if key == "transpose" {
+            self.transpose = parse_transpose(value)?;
+            return Ok(());
+        }
+
+        let (kind, source) = key
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("unknown key {:?}", key))?;
+        let source = source.trim();
+        if kind == "zone" {
+            return self.parse_zone(source, value);
+        }
+        let target = Target::from_name(value).ok_or_else(|| format!("unknown button or axis {:?}", value))?;
+
+        match kind {
+            "note" => {
+                if let Target::Axis(axis) = target {
+                    return Err(format!(
+                        "a note pushes an axis one way, use {}+ or {}-",
+                        axis.name(),
+                        axis.name()
+                    )
+                    .into());
+                }
+                let (source, zone) = match source.split_once(" in ") {
+                    Some((source, zone)) => (source.trim(), Some(zone.trim())),
+                    None => (source, None),
+                };
+                let number = source.parse::<u8>().ok();
+                if matches!(target, Target::Octave(_)) && (zone.is_some() || number.is_none()) {
+                    return Err("octave shifts take a note number outside of zones".into());
+                }
+                let (pitches, notes) = match zone {
+                    Some(zone) => {
+                        let zone = self
+                            .zones
+                            .iter_mut()
+                            .find(|existing| existing.name == zone)
+                            .ok_or_else(|| format!("unknown zone {:?}, declare it with zone {} = <low>-<high> first", zone, zone))?;
+                        (&mut zone.pitches, &mut zone.notes)
+                    }
+                    None => (&mut self.pitches, &mut self.notes),
+                };
+                match number {
+                    Some(number) => {
+                        notes.insert(check_range(number)?, target);
+                    }
+                    None => {
+                        let pitch = Pitch::from_name(source).ok_or_else(|| format!("unknown note {:?}", source))?;
+                        pitches.insert(pitch, target);
+                    }
+                }
+            }
+            "cc" => {
+                let number = source
+                    .parse::<u8>()
+                    .map_err(|_| format!("unknown controller {:?}", source))?;
+                if (ALL_SOUND_OFF..128).contains(&number) {
+                    return Err(format!("controller {} is a channel mode message and cannot be mapped", number).into());
+                }
+                self.map_control(check_range(number)?, target);
+            }
+            "bend" => {
+                let number = source
+                    .parse::<u8>()
+                    .map_err(|_| format!("unknown note {:?}", source))?;
+                if !matches!(target, Target::Axis(_)) {
+                    return Err(format!("a bend moves a whole axis, got {}", target.name()).into());
+                }
+                self.map_bend(check_range(number)?, target);
+            }
+            "osc" => {
+                if !source.starts_with('/') || source.contains(char::is_whitespace) {
+                    return Err(format!("OSC address {:?} must start with / and have no spaces", source).into());
+                }
+                if matches!(target, Target::Octave(_)) {
+                    return Err("octave shifts take a note or controller".into());
+                }
+                self.map_osc(source, target);
+            }
+            _ => return Err(format!("unknown key {:?}", key).into()),
+        }
+        Ok(())
+    }
+
+    /// `zone <name> = <low>-<high>` declares a zone, `zone <name> transpose = <semitones>` shifts it
+    fn parse_zone(&mut self, source: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        if let Some((name, setting)) = source.split_once(char::is_whitespace) {
+            if setting.trim() != "transpose" {
+                return Err(format!("unknown zone setting {:?}", setting.trim()).into());
+            }
+            let transpose = parse_transpose(value)?;
+            let zone = self
+                .zones
+                .iter_mut()
+                .find(|zone| zone.name == name)
+                .ok_or_else(|| format!("unknown zone {:?}", name))?;
+            zone.transpose = transpose;
+            return Ok(());
+        }
+
+        let range = value.split_once('-').and_then(|(low, high)| {
+            let low = low.trim().parse::<u8>().ok().filter(|low| *low <= 127)?;
+            let high = high.trim().parse::<u8>().ok().filter(|high| *high <= 127 && *high >= low)?;
+            Some((low, high))
+        });
+        let (low, high) = range.ok_or_else(|| format!("expected a note range such as 36-59, got {:?}", value))?;
+        if let Some(zone) = self.zones.iter().find(|zone| zone.name == source) {
+            return Err(format!("zone {:?} is already declared", zone.name).into());
+        }
+        if let Some(zone) = self.zones.iter().find(|zone| zone.low <= high && low <= zone.high) {
+            return Err(format!("{}-{} overlaps zone {} ({}-{})", low, high, zone.name, zone.low, zone.high).into());
+        }
+        self.zones.push(Zone {
+            name: source.to_string(),
+            low,
+            high,
+            transpose: 0,
+            pitches: HashMap::new(),
+            notes: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    /// Text form that `parse` reads back, comments are lost
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "name = {}", self.name);
+        if let Some(grid) = self.quantize {
+            let _ = writeln!(text, "quantize = 1/{}", PULSES_PER_WHOLE / grid);
+        }
+        if self.sustain {
+            let _ = writeln!(text, "sustain = on");
+        }
+        for pitch in Pitch::ALL.iter() {
+            if let Some(target) = self.pitches.get(pitch) {
+                let _ = writeln!(text, "note {} = {}", pitch.name(), target.name());
+            }
+        }
+        for (kind, mappings) in [("note", &self.notes), ("cc", &self.controls), ("bend", &self.bends)] {
+            let mut numbers: Vec<&u8> = mappings.keys().collect();
+            numbers.sort();
+            for number in numbers {
+                let _ = writeln!(text, "{} {} = {}", kind, number, mappings[number].name());
+            }
+        }
+        let mut addresses: Vec<&String> = self.osc.keys().collect();
+        addresses.sort();
+        for address in addresses {
+            let _ = writeln!(text, "osc {} = {}", address, self.osc[address].name());
+        }
+        if self.transpose != 0 {
+            let _ = writeln!(text, "transpose = {}", self.transpose);
+        }
+        for zone in self.zones.iter() {
+            let _ = writeln!(text, "zone {} = {}-{}", zone.name, zone.low, zone.high);
+            if zone.transpose != 0 {
+                let _ = writeln!(text, "zone {} transpose = {}", zone.name, zone.transpose);
+            }
+            for pitch in Pitch::ALL.iter() {
+                if let Some(target) = zone.pitches.get(pitch) {
+                    let _ = writeln!(text, "note {} in {} = {}", pitch.name(), zone.name, target.name());
+                }
+            }
+            let mut numbers: Vec<&u8> = zone.notes.keys().collect();
+            numbers.sort();
+            for number in numbers {
+                let _ = writeln!(text, "note {} in {} = {}", number, zone.name, zone.notes[number].name());
+            }
+        }
+        text
+    }
+
+    /// Maps the pitch in every octave
+    pub fn map_pitch(&mut self, pitch: Pitch, target: Target) {
+        self.pitches.insert(pitch, target);
+    }
+
+    /// Maps one MIDI note number, overrides the pitch mapping
+    pub fn map_note(&mut self, note: u8, target: Target) {
+        self.notes.insert(note, target);
+    }
+
+    /// Maps a controller (CC) number
+    pub fn map_control(&mut self, control: u8, target: Target) {
+        self.controls.insert(control, target);
+    }
+
+    /// Maps the MIDI 2.0 per-note pitch bend of a note to an axis
+    pub fn map_bend(&mut self, note: u8, target: Target) {
+        self.bends.insert(note, target);
+    }
+
+    /// Maps an OSC address, the first argument of its messages is the value
+    pub fn map_osc(&mut self, address: &str, target: Target) {
+        self.osc.insert(address.to_string(), target);
+    }
+
+    pub fn osc_mappings(&self) -> impl Iterator<Item = (&String, &Target)> {
+        self.osc.iter()
+    }
+
+    /// What an entry of the MIDI state drives with the keyboard shifted by `octave`
+    pub fn target_for(&self, midi_data: &MidiMessageData, octave: i8) -> Option<Target> {
+        if midi_data.is_control_change() {
+            return self.controls.get(&midi_data.data_byte1).cloned();
+        }
+        if midi_data.status_byte == MidiMessageTypes::PerNotePitchBend {
+            return self.bends.get(&midi_data.data_byte1).cloned();
+        }
+        // Shift keys stay where they are whatever the transposition
+        if let Some(target @ Target::Octave(_)) = self.notes.get(&midi_data.data_byte1) {
+            return Some(target.clone());
+        }
+        let note = transposed(midi_data.data_byte1, self.transpose as i32 + octave as i32 * 12)?;
+        if let Some(zone) = self.zones.iter().find(|zone| zone.contains(note)) {
+            let note = transposed(note, zone.transpose as i32)?;
+            if let Some(target) = lookup(&zone.pitches, &zone.notes, note) {
+                return Some(target);
+            }
+            return lookup(&self.pitches, &self.notes, note);
+        }
+        lookup(&self.pitches, &self.notes, note)
+    }
+
+    /// Buttons and sticks driven by the held notes and controller values
+    ///
+    /// While any note is held the buttons are replaced, sticks only while
+    /// something mapped to them is active. Several sources on one axis add up.
+    /// Axes follow the 32 bit value, so MIDI 2.0 controllers reach every stick position.
+    /// `octave` gives the shift of each entry, held notes keep the one they were pressed with.
+    pub fn overlay(&self, messages: &[MidiMessageData], octave: impl Fn(&MidiMessageData) -> i8) -> Overlay {
+        let mut overlay = Overlay::default();
+        if messages.iter().any(|midi_data| midi_data.is_note()) {
+            overlay.buttons = Some(InputReport::new());
+        }
+
+        let mut offsets: HashMap<Axis, i32> = HashMap::new();
+        for midi_data in messages {
+            let target = match self.target_for(midi_data, octave(midi_data)) {
+                Some(target) => target,
+                None => continue,
+            };
+            let value = midi_data.value as i64;
+            let control = !midi_data.is_note();
+            match target {
+                Target::Button(button) => {
+                    if !control || midi_data.data_byte2 >= 64 {
+                        overlay.press(&button);
+                    }
+                }
+                Target::Axis(axis) => {
+                    let center = CENTER_32 as i64;
+                    let offset = if value < center {
+                        (value - center) * STICK_DOWN as i64 / center
+                    } else {
+                        (value - center) * STICK_UP as i64 / (u32::MAX as i64 - center)
+                    };
+                    *offsets.entry(axis).or_insert(0) += offset as i32;
+                }
+                Target::HalfAxis { axis, positive } => {
+                    let range = if positive { STICK_UP } else { -STICK_DOWN };
+                    let offset = if control { range as i64 * value / u32::MAX as i64 } else { range as i64 };
+                    *offsets.entry(axis).or_insert(0) += offset as i32;
+                }
+                // Applied when the source is pressed, see `Control::shift_octave`
+                Target::Octave(_) => {}
+            }
+        }
+
+        let position = |axis: Axis| {
+            let offset = offsets.get(&axis).copied().unwrap_or(0);
+            (StickPosition::CENTER.x as i32 + offset).clamp(0, StickPosition::MAX as i32) as u16
+        };
+        if offsets.contains_key(&Axis::LeftStickX) || offsets.contains_key(&Axis::LeftStickY) {
+            overlay.left_stick = Some(StickPosition {
+                x: position(Axis::LeftStickX),
+                y: position(Axis::LeftStickY),
+            });
+        }
+        if offsets.contains_key(&Axis::RightStickX) || offsets.contains_key(&Axis::RightStickY) {
+            overlay.right_stick = Some(StickPosition {
+                x: position(Axis::RightStickX),
+                y: position(Axis::RightStickY),
+            });
+        }
+        overlay
+    }
+}
+
+/// Exact note mapping first, then the pitch in any octave
+fn lookup(pitches: &HashMap<Pitch, Target>, notes: &HashMap<u8, Target>, note: u8) -> Option<Target> {
+    if let Some(target) = notes.get(&note) {
+        return Some(target.clone());
+    }
+    pitches.get(&Pitch::from_note(note)).cloned()
+}
+
+/// Note moved by some semitones, None when it leaves the MIDI range
+fn transposed(note: u8, semitones: i32) -> Option<u8> {
+    let note = note as i32 + semitones;
+    (0..=127).contains(&note).then_some(note as u8)
+}
+
+fn parse_transpose(value: &str) -> Result<i8, Box<dyn Error>> {
+    value
+        .parse::<i8>()
+        .ok()
+        .filter(|semitones| (-127..=127).contains(semitones))
+        .ok_or_else(|| format!("transpose expects semitones from -127 to 127, got {:?}", value).into())
+}
+
+fn check_range(number: u8) -> Result<u8, Box<dyn Error>> {
+    if number > 127 {
+        return Err(format!("{} out of range 0-127", number).into());
+    }
+    Ok(number)
+}
+
+/// A '#' right after a note letter is a sharp, not a comment
+fn strip_comment(line: &str) -> &str {
+    let comment = line
+        .match_indices('#')
+        .map(|(position, _)| position)
+        .find(|position| *position == 0 || line[..*position].ends_with(char::is_whitespace));
+    match comment {
+        Some(position) => &line[..position],
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{control, note_on};
+    use crate::ump::Midi2Message;
+
+    fn buttons(profile: &Profile, messages: &[MidiMessageData]) -> InputReport {
+        profile.overlay(messages, |_| 0).buttons.unwrap()
+    }
+
+    #[test]
+    fn default_profile_uses_classic_mapping() {
+        // data_byte1 0x06u8 maps to Button::L
+        let profile = Profile::default();
+        assert_eq!(profile.name, "default");
+        assert_eq!(buttons(&profile, &[note_on(0x06, 0x40)]).report, [0x00, 0x80, 0x40]);
+    }
+
+    #[test]
+    fn overlay_combines_held_notes() {
+        let r = buttons(&Profile::default(), &[note_on(0x3C, 0x40), note_on(0x42, 0x40)]);
+        assert_eq!(r.report, [0x01, 0x80, 0x40]);
+        assert!(r.is_pressed(&Button::Y));
+        assert!(r.is_pressed(&Button::L));
+        assert!(!r.is_pressed(&Button::A));
+    }
+
+    #[test]
+    fn parses_profile_text() {
+        let text = "# Kart\nname = Kart\n\nnote C = A\nnote F# = ZR # accelerate\nnote 60 = B\ncc 1 = LeftStickX\n";
+        let profile = Profile::parse("kart-file", text).unwrap();
+        assert_eq!(profile.name, "Kart");
+        // Exact note wins over the pitch
+        assert_eq!(profile.target_for(&note_on(60, 0x40), 0), Some(Target::Button(Button::B)));
+        assert_eq!(profile.target_for(&note_on(48, 0x40), 0), Some(Target::Button(Button::A)));
+        assert_eq!(profile.target_for(&note_on(66, 0x40), 0), Some(Target::Button(Button::ZR)));
+        assert_eq!(profile.target_for(&note_on(61, 0x40), 0), None);
+        // Controller 1 and note 1 are different sources
+        assert_eq!(profile.target_for(&control(1, 0), 0), Some(Target::Axis(Axis::LeftStickX)));
+        assert_eq!(profile.target_for(&note_on(1, 0x40), 0), None);
+    }
+
+    #[test]
+    fn name_defaults_to_given_name() {
+        assert_eq!(Profile::parse("piano", "note C = A").unwrap().name, "piano");
+    }
+
+    #[test]
+    fn reports_line_of_error() {
+        let error = Profile::parse("bad", "note C = A\nnote C = Start\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: unknown button or axis \"Start\"");
+        assert!(Profile::parse("bad", "note 128 = A").is_err());
+        assert!(Profile::parse("bad", "note H = A").is_err());
+        assert!(Profile::parse("bad", "quantize = 1/5").is_err());
+        assert!(Profile::parse("bad", "quantize = 16").is_err());
+        assert!(Profile::parse("bad", "pitch C = A").is_err());
+        assert!(Profile::parse("bad", "cc C = A").is_err());
+        assert!(Profile::parse("bad", "cc 120 = A").is_err());
+        assert!(Profile::parse("bad", "cc 127 = A").is_err());
+        assert!(Profile::parse("bad", "note C = LeftStickX").is_err());
+        assert!(Profile::parse("bad", "note C A").is_err());
+        assert!(Profile::parse("bad", "osc kart/gas = ZR").is_err());
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let text = "name = Kart\nquantize = 1/16\nsustain = on\nnote C = A\nnote F# = ZR\nnote 60 = B\ncc 1 = LeftStickX\ncc 2 = RightStickY-\nbend 60 = RightStickX\nosc /kart/gas = ZR\nosc /kart/steer = LeftStickX\ntranspose = -12\nzone pad = 36-59\nzone pad transpose = 2\nnote C in pad = DpadLeft\nnote 40 in pad = B\nzone face = 60-127\n";
+        let profile = Profile::parse("kart", text).unwrap();
+        assert_eq!(profile.to_text(), text);
+        assert_eq!(Profile::parse("other", &profile.to_text()).unwrap(), profile);
+    }
+
+    #[test]
+    fn splits_and_transposes_the_keyboard() {
+        let text = "note C = A\nnote 21 = OctaveUp\ntranspose = 12\nzone pad = 0-59\nzone pad transpose = -2\nnote C in pad = DpadLeft\nnote E in pad = LeftStickX-";
+        let profile = Profile::parse("split", text).unwrap();
+        // 40 + 12 is in the pad, 52 - 2 is a D without a pad mapping
+        assert_eq!(profile.target_for(&note_on(40, 0x40), 0), None);
+        // 38 + 12 - 2 is a C of the pad
+        assert_eq!(profile.target_for(&note_on(38, 0x40), 0), Some(Target::Button(Button::DpadLeft)));
+        // 48 + 12 is above the pad, the global C applies
+        assert_eq!(profile.target_for(&note_on(48, 0x40), 0), Some(Target::Button(Button::A)));
+        // One octave down moves it into the pad, 48 - 2 is an A#
+        assert_eq!(profile.target_for(&note_on(48, 0x40), -1), None);
+        assert_eq!(profile.target_for(&note_on(50, 0x40), -1), Some(Target::Button(Button::DpadLeft)));
+        // Shift keys are not transposed, shifted notes out of range have no target
+        assert_eq!(profile.target_for(&note_on(21, 0x40), 5), Some(Target::Octave(1)));
+        assert_eq!(profile.target_for(&note_on(120, 0x40), 1), None);
+        assert_eq!(profile.overlay(&[note_on(21, 0x40)], |_| 0).buttons, Some(InputReport::new()));
+
+        assert!(Profile::parse("bad", "note C in pad = A").is_err());
+        assert!(Profile::parse("bad", "zone pad = 0-59\nzone keys = 59-127").is_err());
+        assert!(Profile::parse("bad", "zone pad = 60-59").is_err());
+        assert!(Profile::parse("bad", "zone pad = 0-59\nnote C in pad = OctaveUp").is_err());
+        assert!(Profile::parse("bad", "note C = OctaveUp").is_err());
+        assert!(Profile::parse("bad", "transpose = 200").is_err());
+    }
+
+    #[test]
+    fn controllers_drive_sticks_and_buttons() {
+        let profile = Profile::parse("kart", "cc 1 = LeftStickX\ncc 2 = RightStickY+\ncc 64 = L").unwrap();
+
+        let overlay = profile.overlay(&[control(1, 0)], |_| 0);
+        assert_eq!(overlay.buttons, None);
+        assert_eq!(overlay.left_stick, Some(StickPosition { x: 0, y: 0x800 }));
+        assert_eq!(overlay.right_stick, None);
+
+        let overlay = profile.overlay(&[control(1, 64), control(2, 127)], |_| 0);
+        assert_eq!(overlay.left_stick, Some(StickPosition::CENTER));
+        assert_eq!(overlay.right_stick, Some(StickPosition { x: 0x800, y: 0xFFF }));
+
+        assert_eq!(profile.overlay(&[control(1, 127)], |_| 0).left_stick, Some(StickPosition { x: 0xFFF, y: 0x800 }));
+        assert_eq!(profile.overlay(&[control(64, 63)], |_| 0), Overlay::default());
+        assert!(buttons(&profile, &[control(64, 64)]).is_pressed(&Button::L));
+    }
+
+    #[test]
+    fn high_resolution_values_reach_every_stick_position() {
+        let profile = Profile::parse("kart", "cc 1 = LeftStickX\ncc 2 = RightStickY+\nbend 60 = RightStickX").unwrap();
+        let control32 = |number: u8, value: u32| Midi2Message::ControlChange { controller: number, value }.to_data().unwrap();
+
+        // One 7 bit step spans 32 stick positions, MIDI 2.0 gets in between
+        let overlay = profile.overlay(&[control32(1, CENTER_32 + 0x0100_0000), control32(2, u32::MAX / 2)], |_| 0);
+        assert_eq!(overlay.left_stick, Some(StickPosition { x: 0x800 + 0x0F, y: 0x800 }));
+        assert_eq!(overlay.right_stick, Some(StickPosition { x: 0x800, y: 0x800 + 0x3FF }));
+
+        // Per-note bends are control-like, they never replace the buttons
+        let bend = Midi2Message::PerNotePitchBend { note: 60, value: 0 }.to_data().unwrap();
+        assert_eq!(profile.target_for(&bend, 0), Some(Target::Axis(Axis::RightStickX)));
+        let overlay = profile.overlay(&[bend], |_| 0);
+        assert_eq!(overlay.buttons, None);
+        assert_eq!(overlay.right_stick, Some(StickPosition { x: 0, y: 0x800 }));
+
+        assert!(Profile::parse("bad", "bend 60 = A").is_err());
+        assert!(Profile::parse("bad", "bend 60 = LeftStickX+").is_err());
+    }
+
+    #[test]
+    fn notes_push_sticks_to_the_end() {
+        let profile = Profile::parse("kart", "note C = LeftStickX-\nnote D = LeftStickX+\nnote E = LeftStickY+").unwrap();
+        let overlay = profile.overlay(&[note_on(60, 0x40)], |_| 0);
+        assert_eq!(overlay.left_stick, Some(StickPosition { x: 0, y: 0x800 }));
+        // Held notes replace the buttons even when they only move sticks
+        assert_eq!(overlay.buttons, Some(InputReport::new()));
+
+        // Opposite directions cancel out
+        let overlay = profile.overlay(&[note_on(60, 0x40), note_on(62, 0x40), note_on(64, 0x40)], |_| 0);
+        assert_eq!(overlay.left_stick, Some(StickPosition { x: 0x7FF, y: 0xFFF }));
+    }
+
+    #[test]
+    fn overlay_applies_to_report() {
+        let profile = Profile::parse("kart", "note C = A\ncc 1 = RightStickX").unwrap();
+        let mut report = ProControllerReport::neutral(0);
+        report.left_stick = StickPosition { x: 0x123, y: 0x456 };
+        profile.overlay(&[note_on(60, 0x40), control(1, 0)], |_| 0).apply(&mut report);
+        assert!(report.buttons.is_pressed(&Button::A));
+        assert_eq!(report.left_stick, StickPosition { x: 0x123, y: 0x456 });
+        assert_eq!(report.right_stick, StickPosition { x: 0, y: 0x800 });
+    }
+}
@@ -0,0 +1,349 @@
+use std::fmt;
+
+/// Output report ids (console -> controller)
+pub const OUTPUT_RUMBLE_AND_SUBCOMMAND: u8 = 0x01;
+pub const OUTPUT_RUMBLE_ONLY: u8 = 0x10;
+pub const OUTPUT_USB_COMMAND: u8 = 0x80;
+
+/// Input report ids (controller -> console) that answer output reports
+pub const INPUT_SUBCOMMAND_REPLY: u8 = 0x21;
+pub const INPUT_USB_REPLY: u8 = 0x81;
+
+/// USB command that ends the console's handshake, no more timeouts after it
+pub const USB_FORCE_USB: u8 = 0x04;
+
+/// Name of a USB command (second byte of 0x80 / 0x81 packets)
+pub fn usb_command_name(command: u8) -> &'static str {
+    match command {
+        0x01 => "Status",
+        0x02 => "Handshake",
+        0x03 => "HighSpeed",
+        0x04 => "ForceUsb",
+        0x05 => "DisableForceUsb",
+        0x06 => "Reset",
+        _ => "Unknown",
+    }
+}
+
+/// Name of a subcommand id, see dekuNukem/Nintendo_Switch_Reverse_Engineering
+pub fn subcommand_name(id: u8) -> &'static str {
+    match id {
+        0x00 => "GetOnlyControllerState",
+        0x01 => "BluetoothManualPairing",
+        0x02 => "RequestDeviceInfo",
+        0x03 => "SetInputReportMode",
+        0x04 => "TriggerButtonsElapsedTime",
+        0x05 => "GetPageListState",
+        0x06 => "SetHciState",
+        0x07 => "ResetPairingInfo",
+        0x08 => "SetShipmentLowPower",
+        0x10 => "SpiFlashRead",
+        0x11 => "SpiFlashWrite",
+        0x12 => "SpiSectorErase",
+        0x20 => "ResetNfcIrMcu",
+        0x21 => "SetNfcIrMcuConfig",
+        0x22 => "SetNfcIrMcuState",
+        0x30 => "SetPlayerLights",
+        0x31 => "GetPlayerLights",
+        0x38 => "SetHomeLight",
+        0x40 => "EnableImu",
+        0x41 => "SetImuSensitivity",
+        0x42 => "WriteImuRegisters",
+        0x43 => "ReadImuRegisters",
+        0x48 => "EnableVibration",
+        0x50 => "GetRegulatedVoltage",
+        _ => "Unknown",
+    }
+}
+
+/// Subcommand sent by the console with its arguments decoded where we know them
+#[derive(Debug, PartialEq, Clone)]
+pub enum Subcommand {
+    SetInputReportMode { mode: u8 },
+    SpiFlashRead { address: u32, length: u8 },
+    SpiFlashWrite { address: u32, length: u8 },
+    SetPlayerLights { on: u8, flashing: u8 },
+    EnableImu { enabled: bool },
+    EnableVibration { enabled: bool },
+    Other { id: u8, args: Vec<u8> },
+}
+
+impl Subcommand {
+    pub fn decode(id: u8, args: &[u8]) -> Subcommand {
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+        let address = || u32::from_le_bytes([arg(0), arg(1), arg(2), arg(3)]);
+        match id {
+            0x03 => Subcommand::SetInputReportMode { mode: arg(0) },
+            0x10 => Subcommand::SpiFlashRead { address: address(), length: arg(4) },
+            0x11 => Subcommand::SpiFlashWrite { address: address(), length: arg(4) },
+            0x30 => Subcommand::SetPlayerLights { on: arg(0) & 0x0F, flashing: arg(0) >> 4 },
+            0x40 => Subcommand::EnableImu { enabled: arg(0) != 0 },
+            0x48 => Subcommand::EnableVibration { enabled: arg(0) != 0 },
+            _ => Subcommand::Other { id, args: args.to_vec() },
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Subcommand::SetInputReportMode { .. } => 0x03,
+            Subcommand::SpiFlashRead { .. } => 0x10,
+            Subcommand::SpiFlashWrite { .. } => 0x11,
+            Subcommand::SetPlayerLights { .. } => 0x30,
+            Subcommand::EnableImu { .. } => 0x40,
+            Subcommand::EnableVibration { .. } => 0x48,
+            Subcommand::Other { id, .. } => *id,
+        }
+    }
+}
+
+impl fmt::Display for Subcommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", subcommand_name(self.id()))?;
+        match self {
+            Subcommand::SetInputReportMode { mode } => write!(f, " mode={:#04X}", mode),
+            Subcommand::SpiFlashRead { address, length } | Subcommand::SpiFlashWrite { address, length } => {
+                write!(f, " address={:#06X} length={}", address, length)
+            }
+            Subcommand::SetPlayerLights { on, flashing } => write!(f, " on={:04b} flashing={:04b}", on, flashing),
+            Subcommand::EnableImu { enabled } | Subcommand::EnableVibration { enabled } => {
+                write!(f, " enabled={}", enabled)
+            }
+            Subcommand::Other { id, args } => {
+                // Arguments are zero padded to the end of the packet
+                let used = args.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+                write!(f, " id={:#04X} args={:02X?}", id, &args[..used])
+            }
+        }
+    }
+}
+
+/// Packet received from the console
+#[derive(Debug, PartialEq, Clone)]
+pub enum OutputPacket {
+    /// Zero packets the console sends while enumerating
+    Empty,
+    UsbCommand { command: u8 },
+    Subcommand { counter: u8, rumble: [u8; 8], subcommand: Subcommand },
+    Rumble { counter: u8, rumble: [u8; 8] },
+    Unknown { report_id: u8 },
+}
+
+impl OutputPacket {
+    pub fn decode(data: &[u8]) -> OutputPacket {
+        let report_id = match data.first() {
+            Some(value) => *value,
+            None => return OutputPacket::Empty,
+        };
+        let mut rumble = [0u8; 8];
+        if data.len() >= 10 {
+            rumble.copy_from_slice(&data[2..10]);
+        }
+        match report_id {
+            OUTPUT_USB_COMMAND if data.len() >= 2 => OutputPacket::UsbCommand { command: data[1] },
+            OUTPUT_RUMBLE_AND_SUBCOMMAND if data.len() >= 11 => OutputPacket::Subcommand {
+                counter: data[1],
+                rumble,
+                subcommand: Subcommand::decode(data[10], &data[11..]),
+            },
+            OUTPUT_RUMBLE_ONLY if data.len() >= 10 => OutputPacket::Rumble { counter: data[1], rumble },
+            _ if data.iter().all(|b| *b == 0) => OutputPacket::Empty,
+            _ => OutputPacket::Unknown { report_id },
+        }
+    }
+}
+
+impl fmt::Display for OutputPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputPacket::Empty => write!(f, "empty"),
+            OutputPacket::UsbCommand { command } => {
+                write!(f, "usb command {} ({:#04X})", usb_command_name(*command), command)
+            }
+            OutputPacket::Subcommand { counter, subcommand, .. } => {
+                write!(f, "subcommand {} counter={}", subcommand, counter)
+            }
+            OutputPacket::Rumble { counter, rumble } => write!(f, "rumble {:02X?} counter={}", rumble, counter),
+            OutputPacket::Unknown { report_id } => write!(f, "unknown report {:#04X}", report_id),
+        }
+    }
+}
+
+/// Reply sent by the controller, full input reports (0x30) are not decoded here
+#[derive(Debug, PartialEq, Clone)]
+pub enum ReplyPacket {
+    UsbReply { command: u8, data: Vec<u8> },
+    SubcommandReply { ack: u8, subcommand_id: u8, data: Vec<u8> },
+}
+
+impl ReplyPacket {
+    pub fn decode(data: &[u8]) -> Option<ReplyPacket> {
+        match data.first() {
+            Some(&INPUT_USB_REPLY) if data.len() >= 2 => Some(ReplyPacket::UsbReply {
+                command: data[1],
+                data: data[2..].to_vec(),
+            }),
+            Some(&INPUT_SUBCOMMAND_REPLY) if data.len() >= 15 => Some(ReplyPacket::SubcommandReply {
+                ack: data[13],
+                subcommand_id: data[14],
+                data: data[15..].to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ReplyPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplyPacket::UsbReply { command, data } => {
+                write!(f, "usb reply {} ({:#04X})", usb_command_name(*command), command)?;
+                if *command == 0x01 && data.len() >= 8 {
+                    // Status reply carries the controller type and its MAC address
+                    write!(f, " type={:#04X} mac={:02X?}", data[1], &data[2..8])?;
+                }
+                Ok(())
+            }
+            ReplyPacket::SubcommandReply { ack, subcommand_id, data } => {
+                // Bit 7 of the ACK byte is set for success, the rest tells the reply type
+                write!(
+                    f,
+                    "subcommand reply {} ({:#04X}) {}",
+                    subcommand_name(*subcommand_id),
+                    subcommand_id,
+                    if ack & 0x80 != 0 { "ack" } else { "nack" }
+                )?;
+                if *subcommand_id == 0x10 && data.len() >= 5 {
+                    let address = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                    let length = (data[4] as usize).min(data.len() - 5);
+                    write!(f, " address={:#06X} data={:02X?}", address, &data[5..5 + length])?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Console packets that configured the controller
+///
+/// A re-plugged controller starts from scratch while the console assumes the
+/// handshake already happened, so these packets are replayed to bring the new
+/// controller into the same state. Only the latest packet per command is kept,
+/// in the order the commands first appeared.
+#[derive(Debug, Default, Clone)]
+pub struct Handshake {
+    packets: Vec<((u8, u8), Vec<u8>)>,
+}
+
+impl Handshake {
+    pub fn new() -> Handshake {
+        Handshake::default()
+    }
+
+    pub fn record(&mut self, data: &[u8]) {
+        let key = match OutputPacket::decode(data) {
+            OutputPacket::UsbCommand { command } => (OUTPUT_USB_COMMAND, command),
+            // Queries do not change the controller state
+            OutputPacket::Subcommand { subcommand, .. } => match subcommand.id() {
+                0x00 | 0x02 | 0x10 | 0x31 | 0x43 | 0x50 => return,
+                id => (OUTPUT_RUMBLE_AND_SUBCOMMAND, id),
+            },
+            _ => return,
+        };
+        match self.packets.iter_mut().find(|(existing, _)| *existing == key) {
+            Some((_, packet)) => *packet = data.to_vec(),
+            None => self.packets.push((key, data.to_vec())),
+        }
+    }
+
+    pub fn packets(&self) -> Vec<Vec<u8>> {
+        self.packets.iter().map(|(_, packet)| packet.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subcommand_packet(id: u8, args: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 64];
+        data[0] = OUTPUT_RUMBLE_AND_SUBCOMMAND;
+        data[1] = 0x05;
+        data[2..10].copy_from_slice(&[0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40]);
+        data[10] = id;
+        data[11..11 + args.len()].copy_from_slice(args);
+        data
+    }
+
+    #[test]
+    fn decodes_usb_commands() {
+        let packet = OutputPacket::decode(&[0x80, 0x05]);
+        assert_eq!(packet, OutputPacket::UsbCommand { command: 0x05 });
+        assert_eq!(packet.to_string(), "usb command DisableForceUsb (0x05)");
+        assert_eq!(OutputPacket::decode(&[0x00, 0x00]), OutputPacket::Empty);
+    }
+
+    #[test]
+    fn decodes_subcommands_with_arguments() {
+        let packet = OutputPacket::decode(&subcommand_packet(0x30, &[0x21]));
+        assert_eq!(packet.to_string(), "subcommand SetPlayerLights on=0001 flashing=0010 counter=5");
+
+        let packet = OutputPacket::decode(&subcommand_packet(0x03, &[0x30]));
+        assert_eq!(packet.to_string(), "subcommand SetInputReportMode mode=0x30 counter=5");
+
+        let packet = OutputPacket::decode(&subcommand_packet(0x10, &[0x3D, 0x60, 0x00, 0x00, 0x19]));
+        match packet {
+            OutputPacket::Subcommand { subcommand, .. } => {
+                assert_eq!(subcommand, Subcommand::SpiFlashRead { address: 0x603D, length: 0x19 })
+            }
+            other => panic!("unexpected packet {:?}", other),
+        }
+
+        let packet = OutputPacket::decode(&subcommand_packet(0x38, &[0x01, 0x02]));
+        assert_eq!(packet.to_string(), "subcommand SetHomeLight id=0x38 args=[01, 02] counter=5");
+    }
+
+    #[test]
+    fn decodes_rumble_only() {
+        let mut data = subcommand_packet(0x00, &[]);
+        data[0] = OUTPUT_RUMBLE_ONLY;
+        assert!(matches!(OutputPacket::decode(&data), OutputPacket::Rumble { counter: 5, .. }));
+    }
+
+    #[test]
+    fn handshake_keeps_latest_state_changing_packets_in_order() {
+        let mut handshake = Handshake::new();
+        handshake.record(&[0x00, 0x00]);
+        handshake.record(&[0x80, 0x02]);
+        handshake.record(&[0x80, 0x04]);
+        handshake.record(&subcommand_packet(0x03, &[0x3F]));
+        handshake.record(&subcommand_packet(0x10, &[0x00, 0x60, 0x00, 0x00, 0x10]));
+        handshake.record(&subcommand_packet(0x30, &[0x01]));
+        handshake.record(&subcommand_packet(0x03, &[0x30]));
+
+        let packets = handshake.packets();
+        assert_eq!(packets.len(), 4);
+        assert_eq!(packets[0], vec![0x80, 0x02]);
+        assert_eq!(packets[1], vec![0x80, 0x04]);
+        // The later report mode replaced the first one but kept its position
+        assert_eq!(packets[2], subcommand_packet(0x03, &[0x30]));
+        assert_eq!(packets[3], subcommand_packet(0x30, &[0x01]));
+    }
+
+    #[test]
+    fn decodes_replies() {
+        let reply = ReplyPacket::decode(&[0x81, 0x01, 0x00, 0x03, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66]).unwrap();
+        assert_eq!(reply.to_string(), "usb reply Status (0x01) type=0x03 mac=[11, 22, 33, 44, 55, 66]");
+
+        let mut data = vec![0u8; 64];
+        data[0] = INPUT_SUBCOMMAND_REPLY;
+        data[13] = 0x90;
+        data[14] = 0x10;
+        data[15..22].copy_from_slice(&[0x3D, 0x60, 0x00, 0x00, 0x02, 0xAA, 0xBB]);
+        let reply = ReplyPacket::decode(&data).unwrap();
+        assert_eq!(reply.to_string(), "subcommand reply SpiFlashRead (0x10) ack address=0x603D data=[AA, BB]");
+
+        let mut full = vec![0u8; 64];
+        full[0] = 0x30;
+        assert_eq!(ReplyPacket::decode(&full), None);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::nscontroller::InputReport;
+use std::error::Error;
+
+pub const REPORT_LENGTH: usize = 64;
+
+/// Standard full input report, sent ~125 times per second once the console enabled it
+pub const REPORT_ID_FULL: u8 = 0x30;
+/// Standard input report carrying a subcommand reply
+pub const REPORT_ID_SUBCOMMAND_REPLY: u8 = 0x21;
+/// Standard input report carrying NFC/IR data
+pub const REPORT_ID_NFC_IR: u8 = 0x31;
+
+/// Position of one analog stick, both axes are 12 bit (0..=4095)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StickPosition {
+    pub x: u16,
+    pub y: u16,
+}
+
+impl StickPosition {
+    pub const CENTER: StickPosition = StickPosition { x: 0x800, y: 0x800 };
+    pub const MAX: u16 = 0xFFF;
+
+    /// Sticks are packed as two 12 bit values in 3 bytes
+    /// ```text
+    /// byte 0: x[7:0]
+    /// byte 1: y[3:0] x[11:8]
+    /// byte 2: y[11:4]
+    /// ```
+    pub fn decode(data: &[u8]) -> StickPosition {
+        StickPosition {
+            x: data[0] as u16 | ((data[1] as u16 & 0x0F) << 8),
+            y: (data[1] as u16 >> 4) | ((data[2] as u16) << 4),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; 3] {
+        let x = self.x & Self::MAX;
+        let y = self.y & Self::MAX;
+        [
+            (x & 0xFF) as u8,
+            ((x >> 8) as u8) | (((y & 0x0F) as u8) << 4),
+            (y >> 4) as u8,
+        ]
+    }
+}
+
+/// One 6-axis sample, the 0x30 report carries three of them taken 5 ms apart
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ImuSample {
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+}
+
+impl ImuSample {
+    pub const LENGTH: usize = 12;
+
+    /// Controller lying flat on the table: 1G on the Z axis at the default ±8G range
+    pub const RESTING: ImuSample = ImuSample {
+        accel: [0, 0, 4096],
+        gyro: [0, 0, 0],
+    };
+
+    pub fn decode(data: &[u8]) -> ImuSample {
+        let value = |i: usize| i16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+        ImuSample {
+            accel: [value(0), value(1), value(2)],
+            gyro: [value(3), value(4), value(5)],
+        }
+    }
+
+    pub fn encode(&self) -> [u8; Self::LENGTH] {
+        let mut data = [0u8; Self::LENGTH];
+        for (i, value) in self.accel.iter().chain(self.gyro.iter()).enumerate() {
+            data[i * 2..i * 2 + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        data
+    }
+}
+
+/// Bytes 0x0D..0x31 of a standard input report
+#[derive(Debug, PartialEq, Clone)]
+pub enum ReportPayload {
+    /// 0x30 reports carry IMU samples
+    Imu([ImuSample; 3]),
+    /// Every other report id is kept untouched (e.g. ACK and subcommand reply of 0x21)
+    Raw([u8; 36]),
+}
+
+/// Typed model of the 64 byte standard input report (0x21, 0x30, 0x31)
+///
+/// ```text
+/// | Byte      | Content                                        |
+/// | 0x00      | Report id                                      |
+/// | 0x01      | Timer, increments with every report            |
+/// | 0x02      | Battery level (high nibble), connection (low)  |
+/// | 0x03-0x05 | Buttons, see InputReport                       |
+/// | 0x06-0x08 | Left stick                                     |
+/// | 0x09-0x0B | Right stick                                    |
+/// | 0x0C      | Vibrator input report                          |
+/// | 0x0D-0x30 | IMU samples (0x30) or report specific payload  |
+/// | 0x31-0x3F | Report specific trailer, zero for 0x30         |
+/// ```
+///
+/// Parsing and serializing is lossless, so forwarded reports keep every bit the
+/// controller sent except the fields we deliberately change.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProControllerReport {
+    pub report_id: u8,
+    pub timer: u8,
+    pub battery_level: u8,
+    pub connection_info: u8,
+    pub buttons: InputReport,
+    pub left_stick: StickPosition,
+    pub right_stick: StickPosition,
+    pub vibrator_report: u8,
+    pub payload: ReportPayload,
+    pub trailer: [u8; 15],
+}
+
+impl ProControllerReport {
+    /// Builds a complete 0x30 report with nothing pressed, sticks centered and the
+    /// controller resting on a table, as a wired controller with a full battery
+    pub fn neutral(timer: u8) -> ProControllerReport {
+        ProControllerReport {
+            report_id: REPORT_ID_FULL,
+            timer,
+            battery_level: 0x8,
+            connection_info: 0x1,
+            buttons: InputReport::new(),
+            left_stick: StickPosition::CENTER,
+            right_stick: StickPosition::CENTER,
+            vibrator_report: 0x00,
+            payload: ReportPayload::Imu([ImuSample::RESTING; 3]),
+            trailer: [0u8; 15],
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<ProControllerReport, Box<dyn Error>> {
+        if data.len() != REPORT_LENGTH {
+            return Err(format!("Input report must be {} bytes, got {}", REPORT_LENGTH, data.len()).into());
+        }
+        let report_id = data[0];
+        let payload = match report_id {
+            REPORT_ID_FULL => ReportPayload::Imu([
+                ImuSample::decode(&data[0x0D..0x19]),
+                ImuSample::decode(&data[0x19..0x25]),
+                ImuSample::decode(&data[0x25..0x31]),
+            ]),
+            REPORT_ID_SUBCOMMAND_REPLY | REPORT_ID_NFC_IR => {
+                let mut raw = [0u8; 36];
+                raw.copy_from_slice(&data[0x0D..0x31]);
+                ReportPayload::Raw(raw)
+            }
+            _ => return Err(format!("Not a standard input report: {:#04X?}", report_id).into()),
+        };
+        let mut buttons = InputReport::new();
+        buttons.report.copy_from_slice(&data[0x03..0x06]);
+        let mut trailer = [0u8; 15];
+        trailer.copy_from_slice(&data[0x31..0x40]);
+
+        Ok(ProControllerReport {
+            report_id,
+            timer: data[0x01],
+            battery_level: data[0x02] >> 4,
+            connection_info: data[0x02] & 0x0F,
+            buttons,
+            left_stick: StickPosition::decode(&data[0x06..0x09]),
+            right_stick: StickPosition::decode(&data[0x09..0x0C]),
+            vibrator_report: data[0x0C],
+            payload,
+            trailer,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; REPORT_LENGTH];
+        data[0x00] = self.report_id;
+        data[0x01] = self.timer;
+        data[0x02] = (self.battery_level << 4) | (self.connection_info & 0x0F);
+        data[0x03..0x06].copy_from_slice(&self.buttons.report);
+        data[0x06..0x09].copy_from_slice(&self.left_stick.encode());
+        data[0x09..0x0C].copy_from_slice(&self.right_stick.encode());
+        data[0x0C] = self.vibrator_report;
+        match &self.payload {
+            ReportPayload::Imu(samples) => {
+                for (i, sample) in samples.iter().enumerate() {
+                    let start = 0x0D + i * ImuSample::LENGTH;
+                    data[start..start + ImuSample::LENGTH].copy_from_slice(&sample.encode());
+                }
+            }
+            ReportPayload::Raw(raw) => data[0x0D..0x31].copy_from_slice(raw),
+        }
+        data[0x31..0x40].copy_from_slice(&self.trailer);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nscontroller::Button;
+
+    const EXAMPLE_REPORT: [u8; 64] = [
+        0x30, 0x00, 0x81, 0x00, 0x80, 0x00, 0xFB, 0xE7,
+        0x7F, 0xE1, 0xC7, 0x81, 0x01, 0xE9, 0xFC, 0x1E,
+        0x00, 0xD6, 0x0F, 0xEA, 0xFF, 0x04, 0x00, 0xFA,
+        0xFF, 0xEE, 0xFC, 0x28, 0x00, 0xD8, 0x0F, 0xEA,
+        0xFF, 0x04, 0x00, 0xFA, 0xFF, 0xF0, 0xFC, 0x2D,
+        0x00, 0xD4, 0x0F, 0xEC, 0xFF, 0x08, 0x00, 0xF9,
+        0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn parses_example_report() {
+        let report = ProControllerReport::parse(&EXAMPLE_REPORT).unwrap();
+        assert_eq!(report.report_id, REPORT_ID_FULL);
+        assert_eq!(report.battery_level, 0x8);
+        assert_eq!(report.connection_info, 0x1);
+        assert_eq!(report.left_stick, StickPosition { x: 0x7FB, y: 0x7FE });
+        assert_eq!(report.right_stick, StickPosition { x: 0x7E1, y: 0x81C });
+        match report.payload {
+            ReportPayload::Imu(samples) => {
+                assert_eq!(samples[0].accel, [-791, 30, 4054]);
+                assert_eq!(samples[0].gyro, [-22, 4, -6]);
+            }
+            ReportPayload::Raw(_) => panic!("0x30 should carry IMU samples"),
+        }
+    }
+
+    #[test]
+    fn round_trip_is_lossless() {
+        let report = ProControllerReport::parse(&EXAMPLE_REPORT).unwrap();
+        assert_eq!(report.to_bytes(), EXAMPLE_REPORT.to_vec());
+
+        let mut reply = [0xA5u8; 64];
+        reply[0] = REPORT_ID_SUBCOMMAND_REPLY;
+        let report = ProControllerReport::parse(&reply).unwrap();
+        assert_eq!(report.to_bytes(), reply.to_vec());
+    }
+
+    #[test]
+    fn stick_encoding_round_trips() {
+        for stick in [StickPosition::CENTER, StickPosition { x: 0, y: 0xFFF }, StickPosition { x: 0xABC, y: 0x123 }] {
+            assert_eq!(StickPosition::decode(&stick.encode()), stick);
+        }
+    }
+
+    #[test]
+    fn neutral_report_builds_full_buffer() {
+        let mut report = ProControllerReport::neutral(0x42);
+        report.buttons.set(&Button::A, true).unwrap();
+        let data = report.to_bytes();
+        assert_eq!(data.len(), REPORT_LENGTH);
+        assert_eq!(&data[0..6], &[0x30, 0x42, 0x81, 0x08, 0x80, 0x00]);
+        assert_eq!(&data[6..9], &[0x00, 0x08, 0x80]);
+        assert_eq!(ProControllerReport::parse(&data).unwrap(), report);
+    }
+
+    #[test]
+    fn rejects_short_and_unknown_reports() {
+        assert!(ProControllerReport::parse(&[0x30, 0x00]).is_err());
+        let mut usb_reply = [0u8; 64];
+        usb_reply[0] = 0x81;
+        assert!(ProControllerReport::parse(&usb_reply).is_err());
+    }
+}
@@ -0,0 +1,688 @@
+use crate::control::Control;
+use crate::midi::MidiReceiver;
+use crate::midi_parser::data_length;
+use crate::shutdown;
+use log::{debug, info, warn};
+use std::error::Error;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Control port announced by macOS and rtpMIDI, the data port is the next one
+pub const DEFAULT_PORT: u16 = 5004;
+
+/// Name other participants show for this session
+pub const SESSION_NAME: &str = "midi_to_switch";
+
+/// AppleMIDI protocol version, the only one in use
+const PROTOCOL_VERSION: u32 = 2;
+
+/// RTP payload type of RTP-MIDI
+const PAYLOAD_TYPE: u8 = 0x61;
+
+/// Initiators synchronize clocks every 10 seconds, a silent session is gone
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the last received sequence number is acknowledged, so the sender can shorten its journal
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a receive waits before checking for a shutdown again
+const POLL_TIMEOUT_MS: libc::c_int = 100;
+
+/// AppleMIDI session command, recognised by its leading 0xFFFF
+///
+/// ```text
+/// FF FF 'I' 'N' | version u32 | token u32 | ssrc u32 | name NUL
+/// FF FF 'C' 'K' | ssrc u32 | count u8 | 3 padding | 3 x timestamp u64
+/// FF FF 'R' 'S' | ssrc u32 | sequence u16 | 2 padding
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    Invitation { token: u32, ssrc: u32, name: String },
+    Accept { token: u32, ssrc: u32, name: String },
+    Reject { token: u32, ssrc: u32 },
+    End { token: u32, ssrc: u32 },
+    /// Timestamps in units of 100 microseconds, `count` says how many are filled in
+    ClockSync { ssrc: u32, count: u8, timestamps: [u64; 3] },
+    ReceiverFeedback { ssrc: u32, sequence: u16 },
+}
+
+impl Command {
+    pub fn parse(packet: &[u8]) -> Option<Command> {
+        if packet.len() < 12 || packet[0..2] != [0xFF, 0xFF] {
+            return None;
+        }
+        let u32_at = |offset: usize| packet.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+        let name = || {
+            let bytes = packet.get(16..).unwrap_or_default();
+            let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).to_string()
+        };
+        match &packet[2..4] {
+            b"IN" => Some(Command::Invitation { token: u32_at(8)?, ssrc: u32_at(12)?, name: name() }),
+            b"OK" => Some(Command::Accept { token: u32_at(8)?, ssrc: u32_at(12)?, name: name() }),
+            b"NO" => Some(Command::Reject { token: u32_at(8)?, ssrc: u32_at(12)? }),
+            b"BY" => Some(Command::End { token: u32_at(8)?, ssrc: u32_at(12)? }),
+            b"CK" => {
+                let timestamp = |offset: usize| {
+                    packet
+                        .get(offset..offset + 8)
+                        .map(|b| u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+                };
+                Some(Command::ClockSync {
+                    ssrc: u32_at(4)?,
+                    count: packet[8],
+                    timestamps: [timestamp(12)?, timestamp(20)?, timestamp(28)?],
+                })
+            }
+            b"RS" => Some(Command::ReceiverFeedback {
+                ssrc: u32_at(4)?,
+                sequence: u16::from_be_bytes([packet[8], packet[9]]),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xFF];
+        let session = |bytes: &mut Vec<u8>, command: &[u8], token: u32, ssrc: u32| {
+            bytes.extend_from_slice(command);
+            bytes.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            bytes.extend_from_slice(&token.to_be_bytes());
+            bytes.extend_from_slice(&ssrc.to_be_bytes());
+        };
+        match self {
+            Command::Invitation { token, ssrc, name } | Command::Accept { token, ssrc, name } => {
+                let command = if matches!(self, Command::Invitation { .. }) { b"IN" } else { b"OK" };
+                session(&mut bytes, command, *token, *ssrc);
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.push(0);
+            }
+            Command::Reject { token, ssrc } => session(&mut bytes, b"NO", *token, *ssrc),
+            Command::End { token, ssrc } => session(&mut bytes, b"BY", *token, *ssrc),
+            Command::ClockSync { ssrc, count, timestamps } => {
+                bytes.extend_from_slice(b"CK");
+                bytes.extend_from_slice(&ssrc.to_be_bytes());
+                bytes.extend_from_slice(&[*count, 0, 0, 0]);
+                for timestamp in timestamps.iter() {
+                    bytes.extend_from_slice(&timestamp.to_be_bytes());
+                }
+            }
+            Command::ReceiverFeedback { ssrc, sequence } => {
+                bytes.extend_from_slice(b"RS");
+                bytes.extend_from_slice(&ssrc.to_be_bytes());
+                bytes.extend_from_slice(&sequence.to_be_bytes());
+                bytes.extend_from_slice(&[0, 0]);
+            }
+        }
+        bytes
+    }
+}
+
+/// MIDI carried by one RTP packet
+#[derive(Debug, PartialEq, Clone)]
+pub struct RtpMidi {
+    pub sequence: u16,
+    pub ssrc: u32,
+    /// MIDI 1.0 bytes of the command list, delta times removed
+    pub midi: Vec<u8>,
+    /// Running status at the end of the packet, the next one may rely on it
+    pub running_status: Option<u8>,
+    /// A recovery journal follows the command list, it is skipped
+    pub journal: bool,
+}
+
+impl RtpMidi {
+    /// Parses an RTP-MIDI packet (RFC 6295)
+    ///
+    /// ```text
+    /// RTP header, 12 bytes and CSRC list | B J Z P LEN | command list | recovery journal
+    /// ```
+    /// `running_status` is the one left by the previous packet, used when the
+    /// first command omits its status byte (the P flag).
+    pub fn parse(packet: &[u8], running_status: Option<u8>) -> Result<RtpMidi, Box<dyn Error>> {
+        if packet.len() < 13 || packet[0] >> 6 != 2 {
+            return Err("Not an RTP packet".into());
+        }
+        if packet[1] & 0x7F != PAYLOAD_TYPE {
+            return Err(format!("Unexpected RTP payload type {}", packet[1] & 0x7F).into());
+        }
+        let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let mut offset = 12 + 4 * (packet[0] & 0x0F) as usize;
+        if packet[0] & 0x10 != 0 {
+            let extension = packet.get(offset + 2..offset + 4).ok_or("Truncated RTP header extension")?;
+            offset += 4 + 4 * u16::from_be_bytes([extension[0], extension[1]]) as usize;
+        }
+
+        let flags = *packet.get(offset).ok_or("Missing MIDI command section")?;
+        let (length, header) = match flags & 0x80 != 0 {
+            true => {
+                let low = *packet.get(offset + 1).ok_or("Truncated MIDI command section")?;
+                (((flags & 0x0F) as usize) << 8 | low as usize, 2)
+            }
+            false => ((flags & 0x0F) as usize, 1),
+        };
+        let list = packet
+            .get(offset + header..offset + header + length)
+            .ok_or("Truncated MIDI command list")?;
+        let (midi, running_status) = command_list(list, flags & 0x20 != 0, running_status)?;
+        Ok(RtpMidi {
+            sequence,
+            ssrc,
+            midi,
+            running_status,
+            journal: flags & 0x40 != 0,
+        })
+    }
+}
+
+/// Removes the delta times in front of the commands and undoes the segmenting of system exclusive
+fn command_list(list: &[u8], first_delta: bool, mut running_status: Option<u8>) -> Result<(Vec<u8>, Option<u8>), Box<dyn Error>> {
+    let mut midi = Vec::new();
+    let mut position = 0;
+    let mut first = true;
+    while position < list.len() {
+        if !first || first_delta {
+            // Variable length, at most 4 bytes, high bit set on all but the last
+            for _ in 0..4 {
+                let byte = *list.get(position).ok_or("Truncated delta time")?;
+                position += 1;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            if position == list.len() {
+                break;
+            }
+        }
+        first = false;
+
+        let status = list[position];
+        match status {
+            0xF0 | 0xF7 => {
+                let end = list[position + 1..]
+                    .iter()
+                    .position(|byte| matches!(byte, 0xF0 | 0xF7 | 0xF4))
+                    .map(|end| position + 1 + end)
+                    .ok_or("Unterminated system exclusive segment")?;
+                // F0 .. F7 complete, F0 .. F0 first segment, F7 .. F0 middle, F7 .. F7 last, F7 .. F4 cancelled
+                if status == 0xF0 {
+                    midi.push(0xF0);
+                }
+                midi.extend_from_slice(&list[position + 1..end]);
+                if list[end] == 0xF7 {
+                    midi.push(0xF7);
+                }
+                running_status = None;
+                position = end + 1;
+            }
+            0xF8..=0xFF => {
+                midi.push(status);
+                position += 1;
+            }
+            0x80..=0xF6 => {
+                let length = 1 + data_length(status);
+                let command = list.get(position..position + length).ok_or("Truncated MIDI command")?;
+                midi.extend_from_slice(command);
+                running_status = if status < 0xF0 { Some(status) } else { None };
+                position += length;
+            }
+            _ => {
+                let status = running_status.ok_or("MIDI data without status")?;
+                let length = data_length(status);
+                let command = list.get(position..position + length).ok_or("Truncated MIDI command")?;
+                midi.extend_from_slice(command);
+                position += length;
+            }
+        }
+    }
+    Ok((midi, running_status))
+}
+
+/// Which of the two sockets a packet arrived on
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Port {
+    Control,
+    Data,
+}
+
+/// The session with the initiator currently playing
+struct Session {
+    ssrc: u32,
+    name: String,
+    control: SocketAddr,
+    data: Option<SocketAddr>,
+    sequence: Option<u16>,
+    running_status: Option<u8>,
+    last_seen: Instant,
+    acknowledged: Option<u16>,
+    last_feedback: Option<Instant>,
+}
+
+/// RTP-MIDI session participant
+///
+/// Waits for an initiator, usually a DAW on a laptop, to invite it and then
+/// plays the MIDI it sends. One session is active at a time, other invitations
+/// are rejected until it ends. The recovery journals the sender adds to every
+/// packet are skipped, a lost packet releases all held notes instead so a lost
+/// Note Off cannot keep a button pressed.
+pub struct Participant {
+    control: UdpSocket,
+    data: UdpSocket,
+    name: String,
+    ssrc: u32,
+    started: Instant,
+    session: Option<Session>,
+}
+
+impl Participant {
+    /// Binds the control port and the data port following it, with port 0 both are picked by the system
+    pub fn bind(ip: IpAddr, port: u16, name: &str) -> Result<Participant, io::Error> {
+        for _ in 0..16 {
+            let control = UdpSocket::bind((ip, port))?;
+            let control_port = control.local_addr()?.port();
+            let data_port = control_port
+                .checked_add(1)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "No port left for RTP data"));
+            match data_port.and_then(|data_port| UdpSocket::bind((ip, data_port))) {
+                Ok(data) => {
+                    control.set_nonblocking(true)?;
+                    data.set_nonblocking(true)?;
+                    let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+                    return Ok(Participant {
+                        control,
+                        data,
+                        name: name.to_string(),
+                        ssrc: seed ^ process::id().rotate_left(16),
+                        started: Instant::now(),
+                        session: None,
+                    });
+                }
+                Err(_) if port == 0 => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "No free pair of ports for RTP-MIDI"))
+    }
+
+    pub fn control_address(&self) -> Result<SocketAddr, io::Error> {
+        self.control.local_addr()
+    }
+
+    /// Answers session commands and plays received MIDI until `stopped` returns true
+    pub fn run(&mut self, receiver: &mut MidiReceiver, control: &Control, stopped: &dyn Fn() -> bool) -> Result<(), Box<dyn Error>> {
+        let mut buffer = [0u8; 1500];
+        while !stopped() {
+            let mut poll_fds = [self.control.as_raw_fd(), self.data.as_raw_fd()].map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            if unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, POLL_TIMEOUT_MS) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error.into());
+                }
+            }
+
+            for (poll_fd, port) in poll_fds.iter().zip([Port::Control, Port::Data]) {
+                if poll_fd.revents == 0 {
+                    continue;
+                }
+                match self.socket(port).recv_from(&mut buffer) {
+                    Ok((length, from)) => self.handle(&buffer[..length], from, port, receiver, control)?,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            if self.session.as_ref().is_some_and(|session| session.last_seen.elapsed() > SESSION_TIMEOUT) {
+                self.end_session("timed out", receiver, control);
+            }
+        }
+
+        if let Some(session) = &self.session {
+            let end = Command::End { token: 0, ssrc: self.ssrc };
+            self.control.send_to(&end.to_bytes(), session.control)?;
+            self.end_session("closed", receiver, control);
+        }
+        Ok(())
+    }
+
+    fn socket(&self, port: Port) -> &UdpSocket {
+        match port {
+            Port::Control => &self.control,
+            Port::Data => &self.data,
+        }
+    }
+
+    /// Current time in the 100 microsecond units of clock synchronisation
+    fn timestamp(&self) -> u64 {
+        (self.started.elapsed().as_micros() / 100) as u64
+    }
+
+    fn handle(&mut self, packet: &[u8], from: SocketAddr, port: Port, receiver: &mut MidiReceiver, control: &Control) -> Result<(), io::Error> {
+        let command = match Command::parse(packet) {
+            Some(command) => command,
+            None if port == Port::Data && !packet.starts_with(&[0xFF, 0xFF]) => {
+                self.handle_midi(packet, receiver);
+                return Ok(());
+            }
+            None => {
+                debug!("Ignoring unknown RTP-MIDI packet {:02X?}", packet);
+                return Ok(());
+            }
+        };
+        let current = self.session.as_ref().map(|session| session.ssrc);
+
+        match command {
+            Command::Invitation { token, ssrc, name } => {
+                let accepted = match &mut self.session {
+                    None if port == Port::Control => {
+                        info!("RTP-MIDI session with {} at {}", name, from);
+                        control.set_midi(Some(format!("RTP-MIDI {} ({})", name, from.ip())));
+                        self.session = Some(Session {
+                            ssrc,
+                            name,
+                            control: from,
+                            data: None,
+                            sequence: None,
+                            running_status: None,
+                            last_seen: Instant::now(),
+                            acknowledged: None,
+                            last_feedback: None,
+                        });
+                        true
+                    }
+                    Some(session) if session.ssrc == ssrc => {
+                        if port == Port::Data {
+                            session.data = Some(from);
+                        }
+                        session.last_seen = Instant::now();
+                        true
+                    }
+                    _ => {
+                        info!("Rejecting RTP-MIDI invitation from {} at {}", name, from);
+                        false
+                    }
+                };
+                let reply = match accepted {
+                    true => Command::Accept { token, ssrc: self.ssrc, name: self.name.clone() },
+                    false => Command::Reject { token, ssrc: self.ssrc },
+                };
+                self.socket(port).send_to(&reply.to_bytes(), from)?;
+            }
+            Command::End { ssrc, .. } if current == Some(ssrc) => self.end_session("ended by the initiator", receiver, control),
+            Command::ClockSync { ssrc, count, timestamps } if current == Some(ssrc) => {
+                if count == 0 {
+                    let reply = Command::ClockSync {
+                        ssrc: self.ssrc,
+                        count: 1,
+                        timestamps: [timestamps[0], self.timestamp(), 0],
+                    };
+                    self.socket(port).send_to(&reply.to_bytes(), from)?;
+                }
+                if let Some(session) = &mut self.session {
+                    session.last_seen = Instant::now();
+                }
+            }
+            other => debug!("Ignoring RTP-MIDI command {:?} from {}", other, from),
+        }
+        Ok(())
+    }
+
+    fn handle_midi(&mut self, packet: &[u8], receiver: &mut MidiReceiver) {
+        let session = match &mut self.session {
+            Some(session) => session,
+            None => return,
+        };
+        let rtp = match RtpMidi::parse(packet, session.running_status) {
+            Ok(rtp) if rtp.ssrc == session.ssrc => rtp,
+            Ok(_) => return,
+            Err(error) => {
+                warn!("Invalid RTP-MIDI packet from {}: {}", session.name, error);
+                return;
+            }
+        };
+
+        if let Some(previous) = session.sequence {
+            let lost = rtp.sequence.wrapping_sub(previous).wrapping_sub(1);
+            if lost >= 0x8000 {
+                debug!("Dropping late RTP-MIDI packet {}", rtp.sequence);
+                return;
+            }
+            if lost > 0 {
+                warn!("Lost {} RTP-MIDI packets from {}, releasing held notes", lost, session.name);
+                receiver.release_all();
+            }
+        }
+        session.sequence = Some(rtp.sequence);
+        session.running_status = rtp.running_status;
+        session.last_seen = Instant::now();
+        receiver.receive(&rtp.midi);
+
+        let due = session.last_feedback.is_none_or(|sent| sent.elapsed() >= FEEDBACK_INTERVAL);
+        if session.acknowledged != session.sequence && due {
+            let feedback = Command::ReceiverFeedback { ssrc: self.ssrc, sequence: rtp.sequence };
+            if let Err(error) = self.control.send_to(&feedback.to_bytes(), session.control) {
+                warn!("Unable to acknowledge RTP-MIDI packets: {}", error);
+            }
+            session.acknowledged = session.sequence;
+            session.last_feedback = Some(Instant::now());
+        }
+    }
+
+    fn end_session(&mut self, reason: &str, receiver: &mut MidiReceiver, control: &Control) {
+        if let Some(session) = self.session.take() {
+            info!("RTP-MIDI session with {} {}", session.name, reason);
+            receiver.release_all();
+            control.set_midi(None);
+        }
+    }
+}
+
+/// Takes part in RTP-MIDI sessions on `port` and `port + 1` until a shutdown is requested
+pub fn process_rtp(port: u16, mut receiver: MidiReceiver, control: Arc<Control>) -> Result<(), Box<dyn Error>> {
+    let mut participant = Participant::bind(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port, SESSION_NAME)
+        .map_err(|e| format!("Unable to listen for RTP-MIDI on port {}: {}", port, e))?;
+    info!("Waiting for RTP-MIDI sessions on {}", participant.control_address()?);
+    participant.run(&mut receiver, &control, &shutdown::requested)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{midi_state, receiver};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    fn rtp(sequence: u16, ssrc: u32, flags: u8, list: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, 0x80 | PAYLOAD_TYPE];
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(&1000u32.to_be_bytes());
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        packet.push(flags | list.len() as u8);
+        packet.extend_from_slice(list);
+        packet
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = [
+            Command::Invitation { token: 7, ssrc: 0x1234, name: String::from("Session 1") },
+            Command::Accept { token: 7, ssrc: 0x5678, name: String::from(SESSION_NAME) },
+            Command::Reject { token: 7, ssrc: 0x5678 },
+            Command::End { token: 0, ssrc: 0x1234 },
+            Command::ClockSync { ssrc: 0x1234, count: 1, timestamps: [1, 2, 0] },
+            Command::ReceiverFeedback { ssrc: 0x5678, sequence: 42 },
+        ];
+        for command in commands.iter() {
+            assert_eq!(Command::parse(&command.to_bytes()).as_ref(), Some(command));
+        }
+        assert_eq!(&commands[0].to_bytes()[..8], b"\xFF\xFFIN\x00\x00\x00\x02");
+        assert_eq!(Command::parse(&[0x80, 0x61, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn parses_command_list_with_delta_times() {
+        // Note on, delta, running status note on, delta over two bytes, clock
+        let packet = rtp(1, 9, 0x00, &[0x90, 0x3C, 0x40, 0x00, 0x3E, 0x40, 0x81, 0x00, 0xF8]);
+        let rtp = RtpMidi::parse(&packet, None).unwrap();
+        assert_eq!(rtp.sequence, 1);
+        assert_eq!(rtp.ssrc, 9);
+        assert_eq!(rtp.midi, vec![0x90, 0x3C, 0x40, 0x3E, 0x40, 0xF8]);
+        assert_eq!(rtp.running_status, Some(0x90));
+        assert!(!rtp.journal);
+    }
+
+    #[test]
+    fn continues_running_status_of_previous_packet() {
+        // Z flag: the first command has a delta time too, P flag: its status is omitted
+        let packet = rtp(2, 9, 0x30, &[0x05, 0x3C, 0x00]);
+        assert_eq!(RtpMidi::parse(&packet, Some(0x80)).unwrap().midi, vec![0x3C, 0x00]);
+        assert!(RtpMidi::parse(&packet, None).is_err());
+    }
+
+    #[test]
+    fn skips_recovery_journal() {
+        let mut packet = rtp(3, 9, 0x40, &[0xB0, 0x01, 0x7F]);
+        packet.extend_from_slice(&[0x20, 0x00, 0x02, 0x00, 0x06, 0x08, 0x81, 0xBC]);
+        let rtp = RtpMidi::parse(&packet, None).unwrap();
+        assert!(rtp.journal);
+        assert_eq!(rtp.midi, vec![0xB0, 0x01, 0x7F]);
+    }
+
+    #[test]
+    fn joins_system_exclusive_segments() {
+        let first = rtp(1, 9, 0x00, &[0xF0, 0x7E, 0x7F, 0xF0]);
+        let last = rtp(2, 9, 0x00, &[0xF7, 0x06, 0x01, 0xF7, 0x00, 0x90, 0x3C, 0x40]);
+        let mut midi = RtpMidi::parse(&first, None).unwrap().midi;
+        midi.extend(RtpMidi::parse(&last, None).unwrap().midi);
+        assert_eq!(midi, vec![0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7, 0x90, 0x3C, 0x40]);
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert!(RtpMidi::parse(&[0x40; 16], None).is_err());
+        let mut truncated = rtp(1, 9, 0x00, &[0x90, 0x3C, 0x40]);
+        truncated.pop();
+        assert!(RtpMidi::parse(&truncated, None).is_err());
+        assert!(RtpMidi::parse(&rtp(1, 9, 0x00, &[0x90, 0x3C]), None).is_err());
+        let mut other_payload = rtp(1, 9, 0x00, &[0xF8]);
+        other_payload[1] = 0x60;
+        assert!(RtpMidi::parse(&other_payload, None).is_err());
+    }
+
+    /// Initiator side of a session, as a DAW would run it
+    struct Peer {
+        control: UdpSocket,
+        data: UdpSocket,
+        participant: SocketAddr,
+    }
+
+    impl Peer {
+        fn new(participant: SocketAddr) -> Peer {
+            let peer = Peer {
+                control: UdpSocket::bind("127.0.0.1:0").unwrap(),
+                data: UdpSocket::bind("127.0.0.1:0").unwrap(),
+                participant,
+            };
+            peer.control.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            peer.data.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            peer
+        }
+
+        fn data_address(&self) -> SocketAddr {
+            SocketAddr::new(self.participant.ip(), self.participant.port() + 1)
+        }
+
+        fn control_request(&self, command: &Command) -> Command {
+            self.control.send_to(&command.to_bytes(), self.participant).unwrap();
+            receive(&self.control)
+        }
+
+        fn data_request(&self, command: &Command) -> Command {
+            self.data.send_to(&command.to_bytes(), self.data_address()).unwrap();
+            receive(&self.data)
+        }
+    }
+
+    fn receive(socket: &UdpSocket) -> Command {
+        let mut buffer = [0u8; 1500];
+        let (length, _) = socket.recv_from(&mut buffer).unwrap();
+        Command::parse(&buffer[..length]).unwrap()
+    }
+
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn plays_session_from_loopback_peer() {
+        let mut participant = Participant::bind(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, SESSION_NAME).unwrap();
+        let address = participant.control_address().unwrap();
+        let state = midi_state(Vec::new());
+        let control = Arc::new(Control::new(Vec::new()));
+        let mut receiver = receiver(&state, &control);
+        let stop = Arc::new(AtomicBool::new(false));
+        let participant_stop = stop.clone();
+        let participant_control = control.clone();
+        let participant_thread = thread::spawn(move || {
+            participant
+                .run(&mut receiver, &participant_control, &|| participant_stop.load(Ordering::SeqCst))
+                .map_err(|e| e.to_string())
+        });
+
+        let peer = Peer::new(address);
+        let invitation = Command::Invitation { token: 11, ssrc: 0xABCD, name: String::from("Laptop") };
+        assert!(matches!(peer.control_request(&invitation), Command::Accept { token: 11, .. }));
+        assert!(matches!(peer.data_request(&invitation), Command::Accept { token: 11, .. }));
+        assert_eq!(control.connections().midi, Some(String::from("RTP-MIDI Laptop (127.0.0.1)")));
+
+        // A second initiator waits for the first one to leave
+        let intruder = Peer::new(address);
+        let invitation = Command::Invitation { token: 12, ssrc: 0x1111, name: String::from("Phone") };
+        assert!(matches!(intruder.control_request(&invitation), Command::Reject { token: 12, .. }));
+
+        let sync = Command::ClockSync { ssrc: 0xABCD, count: 0, timestamps: [5000, 0, 0] };
+        match peer.data_request(&sync) {
+            Command::ClockSync { count: 1, timestamps, .. } => assert_eq!(timestamps[0], 5000),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut note_on = rtp(100, 0xABCD, 0x40, &[0x90, 0x3C, 0x40, 0x00, 0x3E, 0x40]);
+        note_on.extend_from_slice(&[0x20, 0x00, 0x63, 0x00]);
+        peer.data.send_to(&note_on, peer.data_address()).unwrap();
+        wait_for(|| state.get().0.len() == 2);
+        assert!(matches!(receive(&peer.control), Command::ReceiverFeedback { sequence: 100, .. }));
+
+        // Packets of other senders and late packets are ignored
+        peer.data.send_to(&rtp(101, 0x1111, 0x00, &[0x80, 0x3C, 0x00]), peer.data_address()).unwrap();
+        peer.data.send_to(&rtp(99, 0xABCD, 0x00, &[0x80, 0x3C, 0x00]), peer.data_address()).unwrap();
+        peer.data.send_to(&rtp(101, 0xABCD, 0x30, &[0x00, 0x3C, 0x00]), peer.data_address()).unwrap();
+        wait_for(|| state.get().0.len() == 1);
+        assert_eq!(state.get().0[0].data_byte1, 0x3E);
+
+        // Packet 102 is lost, its Note Off may have been in it
+        peer.data.send_to(&rtp(103, 0xABCD, 0x00, &[0x90, 0x40, 0x40]), peer.data_address()).unwrap();
+        wait_for(|| state.get().0.first().is_some_and(|held| held.data_byte1 == 0x40));
+        assert_eq!(state.get().0.len(), 1);
+
+        peer.control.send_to(&Command::End { token: 0, ssrc: 0xABCD }.to_bytes(), address).unwrap();
+        wait_for(|| state.get().0.is_empty());
+        wait_for(|| control.connections().midi.is_none());
+        assert!(matches!(intruder.control_request(&invitation), Command::Accept { token: 12, .. }));
+
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(participant_thread.join().unwrap(), Ok(()));
+        assert!(matches!(receive(&intruder.control), Command::End { .. }));
+    }
+}
@@ -0,0 +1,53 @@
+use libc::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Set once SIGTERM/SIGINT arrives or a part of the service decides to stop.
+/// Signal handlers cannot capture state, so this has to be a global.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_signal: c_int) {
+    // Only async-signal-safe work is allowed here
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Routes SIGTERM (systemd stop) and SIGINT (Ctrl+C) to the shutdown flag
+pub fn install_signal_handlers() {
+    let handler = handle_signal as extern "C" fn(c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+/// Asks every thread to finish its loop
+pub fn request() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Stop flag for one generation of worker threads
+///
+/// Lets the supervisor stop and restart workers without shutting down the whole service,
+/// a global shutdown stops every token as well
+#[derive(Clone, Default)]
+pub struct StopToken {
+    stopped: Arc<AtomicBool>,
+}
+
+impl StopToken {
+    pub fn new() -> StopToken {
+        StopToken::default()
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn stopped(&self) -> bool {
+        requested() || self.stopped.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Single slot holding only the most recent value, tagged with a generation counter
+///
+/// Writers replace the value instead of queueing it, so a fast controller sweep can
+/// never build up a backlog and readers never apply outdated states. Every publish
+/// bumps the generation, which lets a reader tell whether anything changed since
+/// it last looked. Because the value is the complete state (e.g. all held notes)
+/// a reader that skips intermediate generations still ends up with the right result,
+/// a release always wins over the press it follows.
+pub struct LatestState<T> {
+    slot: Mutex<(T, u64)>,
+    changed: Condvar,
+}
+
+impl<T: Clone> LatestState<T> {
+    pub fn new(initial: T) -> LatestState<T> {
+        LatestState {
+            slot: Mutex::new((initial, 0)),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Replaces the value with what `change` makes of the current one, returns the new generation
+    ///
+    /// Reading and replacing happen under one lock, so writers never undo each other.
+    /// `change` gets the generation the new value is published as and returns None to
+    /// keep the current value without a new generation.
+    pub fn update<F: FnOnce(&T, u64) -> Option<T>>(&self, change: F) -> Option<u64> {
+        let mut slot = self.lock();
+        let value = change(&slot.0, slot.1 + 1)?;
+        slot.0 = value;
+        slot.1 += 1;
+        self.changed.notify_all();
+        Some(slot.1)
+    }
+
+    /// Current value and its generation
+    pub fn get(&self) -> (T, u64) {
+        let slot = self.lock();
+        (slot.0.clone(), slot.1)
+    }
+
+    /// Waits up to `timeout` for a generation newer than `seen`
+    pub fn wait_newer(&self, seen: u64, timeout: Duration) -> Option<(T, u64)> {
+        let slot = self.lock();
+        let (slot, _) = match self.changed.wait_timeout_while(slot, timeout, |slot| slot.1 <= seen) {
+            Ok(result) => result,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if slot.1 > seen {
+            Some((slot.0.clone(), slot.1))
+        } else {
+            None
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, (T, u64)> {
+        match self.slot.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Replaces the value whatever it was
+    fn publish<T: Clone>(state: &LatestState<T>, value: T) -> u64 {
+        state.update(|_, _| Some(value)).unwrap()
+    }
+
+    #[test]
+    fn publish_replaces_value_and_bumps_generation() {
+        let state = LatestState::new(Vec::<u8>::new());
+        assert_eq!(state.get(), (vec![], 0));
+        assert_eq!(publish(&state, vec![0x3C]), 1);
+        assert_eq!(publish(&state, vec![0x3C, 0x40]), 2);
+        assert_eq!(state.get(), (vec![0x3C, 0x40], 2));
+    }
+
+    #[test]
+    fn wait_newer_times_out_without_change() {
+        let state = LatestState::new(0u8);
+        publish(&state, 1);
+        assert_eq!(state.wait_newer(1, Duration::from_millis(5)), None);
+        assert_eq!(state.wait_newer(0, Duration::from_millis(5)), Some((1, 1)));
+    }
+
+    #[test]
+    fn update_changes_the_current_value() {
+        let state = LatestState::new(vec![0x3C]);
+        assert_eq!(state.update(|_, _| None), None);
+        assert_eq!(
+            state.update(|held, generation| {
+                assert_eq!(generation, 1);
+                Some([held.as_slice(), &[0x40]].concat())
+            }),
+            Some(1)
+        );
+        assert_eq!(state.get(), (vec![0x3C, 0x40], 1));
+    }
+
+    #[test]
+    fn concurrent_updates_are_never_lost() {
+        let state = Arc::new(LatestState::new(0u32));
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let state = state.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        state.update(|count, _| Some(count + 1));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(state.get(), (400, 400));
+    }
+
+    #[test]
+    fn release_is_never_lost_to_a_slow_reader() {
+        // Press and release before the reader looks: only the release is visible
+        let state = LatestState::new(Vec::<u8>::new());
+        publish(&state, vec![0x3C]);
+        publish(&state, vec![]);
+        assert_eq!(state.get(), (vec![], 2));
+    }
+
+    #[test]
+    fn concurrent_reader_always_ends_with_final_state() {
+        let state = Arc::new(LatestState::new(Vec::<u8>::new()));
+        let writer_state = state.clone();
+        let writer = thread::spawn(move || {
+            for note in 0..200u8 {
+                publish(&writer_state, vec![note % 128]);
+                publish(&writer_state, vec![]);
+            }
+        });
+
+        let mut seen = 0;
+        let mut last = vec![0xFF];
+        while seen < 400 {
+            if let Some((value, generation)) = state.wait_newer(seen, Duration::from_millis(100)) {
+                // Generations only move forward
+                assert!(generation > seen);
+                seen = generation;
+                last = value;
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(seen, 400);
+        assert!(last.is_empty(), "the final release must be applied");
+    }
+}
@@ -0,0 +1,296 @@
+use crate::metrics::METRICS;
+use libc::c_int;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Latency samples kept for the percentile
+const SAMPLE_WINDOW: usize = 1024;
+/// Events still waiting for a report, older ones count as dropped
+const MAX_PENDING: usize = 1024;
+
+/// Set by SIGUSR1, asks for the statistics to be logged right away
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_signal: c_int) {
+    DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Routes SIGUSR1 to `take_dump_request`, e.g. `kill -USR1 $(pidof midi_to_switch)`
+pub fn install_signal_handler() {
+    let handler = handle_signal as extern "C" fn(c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler);
+    }
+}
+
+/// True once after every SIGUSR1
+pub fn take_dump_request() -> bool {
+    DUMP_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Time from a MIDI event arriving to the first report that carries it
+///
+/// Events are identified by the generation of the MIDI state they produced.
+/// A report sent with generation `n` resolves the event of generation `n`,
+/// events superseded by a newer state before any report carried them were
+/// never seen by the console and count as dropped.
+pub struct LatencyStats {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    pending: VecDeque<(u64, Instant)>,
+    samples: VecDeque<Duration>,
+    events: u64,
+    dropped: u64,
+    min: Option<Duration>,
+    max: Option<Duration>,
+    total: Duration,
+    reports: u64,
+    rate_window_start: Option<Instant>,
+    rate_window_reports: u64,
+    report_rate: f64,
+}
+
+/// Statistics at one point in time, min/mean/max cover everything since start,
+/// p99 the most recent events
+#[derive(Debug, PartialEq, Clone)]
+pub struct StatsSnapshot {
+    /// Events that reached a report
+    pub events: u64,
+    pub dropped: u64,
+    pub min: Option<Duration>,
+    pub mean: Option<Duration>,
+    pub p99: Option<Duration>,
+    pub max: Option<Duration>,
+    pub reports: u64,
+    /// Reports per second over the last second
+    pub report_rate: f64,
+}
+
+impl LatencyStats {
+    pub fn new() -> LatencyStats {
+        LatencyStats {
+            inner: Mutex::new(Inner {
+                pending: VecDeque::new(),
+                samples: VecDeque::new(),
+                events: 0,
+                dropped: 0,
+                min: None,
+                max: None,
+                total: Duration::ZERO,
+                reports: 0,
+                rate_window_start: None,
+                rate_window_reports: 0,
+                report_rate: 0.0,
+            }),
+        }
+    }
+
+    /// A MIDI event that arrived at `arrived` produced state `generation`
+    pub fn midi_event(&self, generation: u64, arrived: Instant) {
+        let mut inner = self.lock();
+        if inner.pending.len() >= MAX_PENDING {
+            inner.pending.pop_front();
+            inner.dropped += 1;
+        }
+        inner.pending.push_back((generation, arrived));
+    }
+
+    /// A report carrying state `generation` left at `sent`
+    pub fn report_sent(&self, generation: u64, sent: Instant) {
+        let mut inner = self.lock();
+        inner.reports += 1;
+
+        match inner.rate_window_start {
+            None => inner.rate_window_start = Some(sent),
+            Some(start) => {
+                inner.rate_window_reports += 1;
+                let elapsed = sent.saturating_duration_since(start);
+                if elapsed >= Duration::from_secs(1) {
+                    inner.report_rate = inner.rate_window_reports as f64 / elapsed.as_secs_f64();
+                    inner.rate_window_start = Some(sent);
+                    inner.rate_window_reports = 0;
+                }
+            }
+        }
+
+        while let Some(&(pending, arrived)) = inner.pending.front() {
+            if pending > generation {
+                break;
+            }
+            inner.pending.pop_front();
+            if pending < generation {
+                inner.dropped += 1;
+                continue;
+            }
+            let latency = sent.saturating_duration_since(arrived);
+            METRICS.observe_latency(latency);
+            inner.events += 1;
+            inner.total += latency;
+            inner.min = Some(inner.min.map_or(latency, |min| min.min(latency)));
+            inner.max = Some(inner.max.map_or(latency, |max| max.max(latency)));
+            if inner.samples.len() >= SAMPLE_WINDOW {
+                inner.samples.pop_front();
+            }
+            inner.samples.push_back(latency);
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let inner = self.lock();
+        let mean = if inner.events > 0 {
+            Some(Duration::from_nanos((inner.total.as_nanos() / inner.events as u128) as u64))
+        } else {
+            None
+        };
+        let mut samples: Vec<Duration> = inner.samples.iter().copied().collect();
+        samples.sort();
+        let p99 = if samples.is_empty() {
+            None
+        } else {
+            let index = (samples.len() * 99).div_ceil(100) - 1;
+            Some(samples[index])
+        };
+        StatsSnapshot {
+            events: inner.events,
+            dropped: inner.dropped,
+            min: inner.min,
+            mean,
+            p99,
+            max: inner.max,
+            reports: inner.reports,
+            report_rate: inner.report_rate,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn format_latency(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) => format!("{:.2} ms", latency.as_secs_f64() * 1000.0),
+        None => String::from("-"),
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "latency min {} mean {} p99 {} max {}, {} events, {} dropped, {} reports ({:.1}/s)",
+            format_latency(self.min),
+            format_latency(self.mean),
+            format_latency(self.p99),
+            format_latency(self.max),
+            self.events,
+            self.dropped,
+            self.reports,
+            self.report_rate
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(value: u64) -> Duration {
+        Duration::from_millis(value)
+    }
+
+    #[test]
+    fn event_is_matched_to_first_report_carrying_it() {
+        let stats = LatencyStats::new();
+        let start = Instant::now();
+        stats.midi_event(1, start);
+        // A report with the old state does not resolve the event
+        stats.report_sent(0, start + ms(2));
+        stats.report_sent(1, start + ms(5));
+        stats.report_sent(1, start + ms(13));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.events, 1);
+        assert_eq!(snapshot.dropped, 0);
+        assert_eq!(snapshot.min, Some(ms(5)));
+        assert_eq!(snapshot.mean, Some(ms(5)));
+        assert_eq!(snapshot.p99, Some(ms(5)));
+        assert_eq!(snapshot.reports, 3);
+    }
+
+    #[test]
+    fn superseded_events_count_as_dropped() {
+        let stats = LatencyStats::new();
+        let start = Instant::now();
+        stats.midi_event(1, start);
+        stats.midi_event(2, start + ms(1));
+        stats.midi_event(3, start + ms(2));
+        stats.report_sent(3, start + ms(6));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.events, 1);
+        assert_eq!(snapshot.dropped, 2);
+        assert_eq!(snapshot.min, Some(ms(4)));
+    }
+
+    #[test]
+    fn min_mean_and_p99() {
+        let stats = LatencyStats::new();
+        let start = Instant::now();
+        for generation in 1..=100u64 {
+            stats.midi_event(generation, start);
+            stats.report_sent(generation, start + ms(generation));
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.events, 100);
+        assert_eq!(snapshot.min, Some(ms(1)));
+        assert_eq!(snapshot.max, Some(ms(100)));
+        assert_eq!(snapshot.mean, Some(Duration::from_micros(50_500)));
+        assert_eq!(snapshot.p99, Some(ms(99)));
+    }
+
+    #[test]
+    fn mean_survives_event_counts_beyond_u32() {
+        let stats = LatencyStats::new();
+        {
+            let mut inner = stats.lock();
+            inner.events = 1 << 32;
+            inner.total = Duration::from_millis(3 << 32);
+        }
+        assert_eq!(stats.snapshot().mean, Some(ms(3)));
+    }
+
+    #[test]
+    fn report_rate_over_last_second() {
+        let stats = LatencyStats::new();
+        let start = Instant::now();
+        for report in 0..=125u64 {
+            stats.report_sent(0, start + ms(report * 8));
+        }
+        assert_eq!(stats.snapshot().report_rate, 125.0);
+    }
+
+    #[test]
+    fn empty_snapshot_displays_dashes() {
+        let snapshot = LatencyStats::new().snapshot();
+        assert_eq!(
+            snapshot.to_string(),
+            "latency min - mean - p99 - max -, 0 events, 0 dropped, 0 reports (0.0/s)"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use crate::shutdown::{self, StopToken};
+use log::{error, info, warn};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Worker thread whose error is turned into a String so it can cross the join
+pub type Worker = JoinHandle<Result<(), String>>;
+
+/// Keeps a group of worker threads alive
+///
+/// The workers of one group depend on each other (e.g. gadget and controller share
+/// channels), so when one of them fails the whole group is stopped and started again
+/// after a growing backoff. After too many failures in a row the supervisor gives up
+/// so systemd can restart the service.
+pub struct Supervisor {
+    pub name: String,
+    /// Consecutive failures tolerated before giving up
+    pub max_failures: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// A group running at least this long counts as healthy and resets the failure count
+    pub healthy_after: Duration,
+    pub poll_interval: Duration,
+}
+
+impl Supervisor {
+    pub fn new(name: &str) -> Supervisor {
+        Supervisor {
+            name: name.to_string(),
+            max_failures: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            healthy_after: Duration::from_secs(60),
+            poll_interval: Duration::from_millis(50),
+        }
+    }
+
+    /// Delay before the given restart attempt (1 based), doubling up to max_backoff
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    /// Starts the group with `start` and restarts it on failure until a shutdown is requested
+    ///
+    /// `start` must (re)open everything the workers need, it is called again for every restart
+    pub fn run<F>(&self, mut start: F) -> Result<(), String>
+    where
+        F: FnMut(StopToken) -> Result<Vec<Worker>, String>,
+    {
+        let mut failures = 0;
+
+        while !shutdown::requested() {
+            let token = StopToken::new();
+            let started = Instant::now();
+            let result = match start(token.clone()) {
+                Ok(workers) => self.watch(workers, &token),
+                Err(error) => Err(vec![format!("start failed: {}", error)]),
+            };
+
+            let errors = match result {
+                Ok(()) => return Ok(()),
+                Err(errors) => errors,
+            };
+            for error in errors.iter() {
+                error!("{}: {}", self.name, error);
+            }
+            if shutdown::requested() {
+                return Err(format!("{} stopped with errors", self.name));
+            }
+
+            if started.elapsed() >= self.healthy_after {
+                failures = 0;
+            }
+            failures += 1;
+            if failures >= self.max_failures {
+                return Err(format!("{} failed {} times in a row, giving up", self.name, failures));
+            }
+
+            let backoff = self.backoff(failures);
+            warn!("Restarting {} in {:?} (failure {} of {})", self.name, backoff, failures, self.max_failures);
+            self.sleep(backoff);
+        }
+        Ok(())
+    }
+
+    /// Waits until a worker stops or a shutdown is requested, then stops and joins the group
+    ///
+    /// Returns Ok only when the group was stopped because of a shutdown without errors
+    fn watch(&self, workers: Vec<Worker>, token: &StopToken) -> Result<(), Vec<String>> {
+        while !shutdown::requested() && !workers.iter().any(|worker| worker.is_finished()) {
+            thread::sleep(self.poll_interval);
+        }
+        token.stop();
+
+        let mut errors = Vec::new();
+        for worker in workers {
+            let name = worker.thread().name().unwrap_or("worker").to_string();
+            match worker.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => errors.push(format!("{} thread failed: {}", name, error)),
+                Err(_) => errors.push(format!("{} thread panicked", name)),
+            }
+        }
+
+        if errors.is_empty() && !shutdown::requested() {
+            errors.push(String::from("worker stopped unexpectedly"));
+        }
+        if errors.is_empty() {
+            info!("{} stopped", self.name);
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Sleeps in small steps so a shutdown is not delayed by a long backoff
+    fn sleep(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        while !shutdown::requested() && Instant::now() < until {
+            thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_maximum() {
+        let supervisor = Supervisor::new("test");
+        assert_eq!(supervisor.backoff(1), Duration::from_millis(500));
+        assert_eq!(supervisor.backoff(2), Duration::from_secs(1));
+        assert_eq!(supervisor.backoff(3), Duration::from_secs(2));
+        assert_eq!(supervisor.backoff(6), Duration::from_secs(10));
+        assert_eq!(supervisor.backoff(100), Duration::from_secs(10));
+    }
+}
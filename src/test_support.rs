@@ -0,0 +1,27 @@
+use crate::control::Control;
+use crate::midi::{MidiMessageData, MidiMessageTypes, MidiReceiver};
+use crate::state::LatestState;
+use crate::stats::LatencyStats;
+use std::sync::Arc;
+
+pub fn note_on(note: u8, velocity: u8) -> MidiMessageData {
+    MidiMessageData::midi1(MidiMessageTypes::NoteOn, note, velocity)
+}
+
+pub fn note_off(note: u8) -> MidiMessageData {
+    MidiMessageData::midi1(MidiMessageTypes::NoteOff, note, 0)
+}
+
+pub fn control(number: u8, value: u8) -> MidiMessageData {
+    MidiMessageData::midi1(MidiMessageTypes::ControlChange, number, value)
+}
+
+/// Shared MIDI state starting with `entries`
+pub fn midi_state(entries: Vec<MidiMessageData>) -> Arc<LatestState<Vec<MidiMessageData>>> {
+    Arc::new(LatestState::new(entries))
+}
+
+/// Receiver writing `state` through the profiles of `control`
+pub fn receiver(state: &Arc<LatestState<Vec<MidiMessageData>>>, control: &Arc<Control>) -> MidiReceiver {
+    MidiReceiver::new(state.clone(), Arc::new(LatencyStats::new()), control.clone(), None)
+}
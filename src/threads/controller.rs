@@ -1,32 +1,77 @@
+use crate::capture::{self, Capture, Direction, Interface};
+use crate::control::Control;
 use crate::device_file::DeviceFile;
+use crate::hidraw::find_controller;
+use crate::metrics::{Device, Operation, ReportDirection, METRICS};
+use crate::protocol::{Handshake, ReplyPacket};
+use crate::report::ProControllerReport;
+use crate::shutdown::StopToken;
 use core::time;
-use log::{error, info, trace};
+use log::{debug, error, info, trace, warn};
 use std::error::Error;
+use std::io::ErrorKind::WouldBlock;
+use std::path::Path;
 use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
 /// Gamepad control thread
 /// Reads data from gamepad and sends it to the gadget api device
 /// Receives data from gadget api device and sends it to the controller
+///
+/// When the controller is unplugged, or missing at startup, the console keeps
+/// receiving neutral input reports so it stays connected (MIDI input still works
+/// on top of them). Once a controller shows up the recorded handshake is replayed
+/// to it and relaying continues.
 pub fn start_controller(
     tx_gadget: Sender<Vec<u8>>,
     rx_controller: Receiver<Vec<u8>>,
+    controller_path: Option<String>,
+    capture: Option<Arc<Capture>>,
+    control: Arc<Control>,
+    stop: StopToken,
 ) -> Result<(), Box<dyn Error>> {
-    info!("Starting controller thread /dev/hidraw0");
-
-    let mut controller = DeviceFile::new("/dev/hidraw0", true).unwrap();
+    let mut controller = match open(&controller_path) {
+        Ok((device, path)) => {
+            info!("Starting controller thread {}", path);
+            control.set_controller(Some(path));
+            Some(device)
+        }
+        Err(error) => {
+            warn!("Starting controller thread without a controller, sending neutral reports: {}", error);
+            None
+        }
+    };
+    let mut handshake = Handshake::new();
 
     let wait_ms = time::Duration::from_millis(5);
+    // The console expects an input report about every 8 ms
+    let neutral_interval = time::Duration::from_millis(8);
+    let attach_interval = time::Duration::from_secs(1);
+    let mut timer: u8 = 0;
+    let mut last_neutral = Instant::now();
+    let mut last_attach = Instant::now();
 
-    loop {
+    while !stop.stopped() {
         match rx_controller.try_recv() {
             Ok(received) => {
                 trace!("rx_controller -> controller {:02X?}", received);
-                match controller.write(received) {
-                    Ok(_) => {
-                        trace!("conroller <-");
+                handshake.record(&received);
+                if let Some(device) = controller.as_mut() {
+                    capture::record(&capture, Interface::Controller, Direction::Outbound, &received, None);
+                    match device.write(received) {
+                        Ok(_) => {
+                            trace!("conroller <-");
+                            METRICS.report_forwarded(ReportDirection::ToController);
+                        }
+                        Err(error) => {
+                            METRICS.device_error(Device::Controller, Operation::Write);
+                            warn!("Controller removed, unable to write: {}", error);
+                            controller = None;
+                            control.set_controller(None);
+                        }
                     }
-                    Err(error) => error!("Unable to write to controller: {}", error),
                 }
             }
             Err(TryRecvError::Empty) => {}
@@ -35,17 +80,108 @@ pub fn start_controller(
             }
         };
 
-        if let Ok(buf) = controller.read() {
+        let buf = match controller.as_mut() {
+            Some(device) => match device.read() {
+                Ok(buf) => Some(buf),
+                // WouldBlock only means there is no report yet
+                Err(error) if error.kind() == WouldBlock => None,
+                Err(error) => {
+                    METRICS.device_error(Device::Controller, Operation::Read);
+                    warn!("Controller removed, unable to read: {}", error);
+                    controller = None;
+                    control.set_controller(None);
+                    None
+                }
+            },
+            None => {
+                if last_attach.elapsed() >= attach_interval {
+                    last_attach = Instant::now();
+                    if let Some((device, path)) = reattach(&controller_path, &handshake) {
+                        controller = Some(device);
+                        control.set_controller(Some(path));
+                        METRICS.reconnect(Device::Controller);
+                    }
+                }
+                if controller.is_none() && last_neutral.elapsed() >= neutral_interval {
+                    last_neutral = Instant::now();
+                    timer = timer.wrapping_add(1);
+                    Some(ProControllerReport::neutral(timer).to_bytes())
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some(buf) = buf {
             trace!("controller ->");
-            match tx_gadget.send(buf) {
-                Ok(()) => {
-                    trace!("tx_gadget <- controller");
+            if controller.is_some() {
+                capture::record(&capture, Interface::Controller, Direction::Inbound, &buf, None);
+                if let Some(reply) = ReplyPacket::decode(&buf) {
+                    debug!("controller -> {}", reply);
                 }
-                Err(error) => {
-                    panic!("Cannot send to tx_gadget {}", error);
+            }
+            // The gadget thread may already be gone while shutting down
+            if let Err(error) = tx_gadget.send(buf) {
+                if !stop.stopped() {
+                    control.set_controller(None);
+                    return Err(format!("Cannot send to tx_gadget {error}").into());
                 }
-            };
+            }
+            trace!("tx_gadget <- controller");
         };
         thread::sleep(wait_ms);
     }
+
+    control.set_controller(None);
+    info!("Controller thread stopped");
+    Ok(())
+}
+
+/// hidraw numbering depends on what else is plugged in, so look for the controller
+/// unless a path was configured
+fn resolve_path(controller_path: &Option<String>) -> Result<String, Box<dyn Error>> {
+    match controller_path {
+        Some(path) => Ok(path.clone()),
+        None => Ok(find_controller(Path::new("/sys"), Path::new("/dev"))?
+            .to_string_lossy()
+            .to_string()),
+    }
+}
+
+fn open(controller_path: &Option<String>) -> Result<(DeviceFile, String), Box<dyn Error>> {
+    let path = resolve_path(controller_path)?;
+    let device = DeviceFile::new(&path, true).map_err(|error| format!("Unable to open {}: {}", path, error))?;
+    Ok((device, path))
+}
+
+/// Opens the controller again and replays the console's handshake to it
+fn reattach(controller_path: &Option<String>, handshake: &Handshake) -> Option<(DeviceFile, String)> {
+    let (mut device, path) = open(controller_path).ok()?;
+    info!("Controller found at {}, replaying {} handshake packets", path, handshake.packets().len());
+
+    let reply_wait = time::Duration::from_millis(20);
+    for packet in handshake.packets() {
+        if let Err(error) = device.write(packet) {
+            warn!("Unable to replay handshake to {}: {}", path, error);
+            return None;
+        }
+        thread::sleep(reply_wait);
+        // The console did not ask for these replies, so they are dropped
+        loop {
+            match device.read() {
+                Ok(reply) => {
+                    if let Some(reply) = ReplyPacket::decode(&reply) {
+                        debug!("controller (replay) -> {}", reply);
+                    }
+                }
+                Err(error) if error.kind() == WouldBlock => break,
+                Err(error) => {
+                    warn!("Controller at {} gone during replay: {}", path, error);
+                    return None;
+                }
+            }
+        }
+    }
+    info!("Controller re-attached");
+    Some((device, path))
 }
@@ -1,12 +1,23 @@
+use crate::activity::ACTIVITY;
+use crate::capture::{self, Capture, Direction, Interface};
+use crate::control::Control;
 use crate::device_file::DeviceFile;
 use crate::midi::MidiMessageData;
-use crate::nscontroller::InputReport;
+use crate::metrics::{Device, Operation, ReportDirection, METRICS};
+use crate::protocol::{OutputPacket, USB_FORCE_USB};
+use crate::report::{ProControllerReport, REPORT_ID_FULL};
+use crate::shutdown::StopToken;
+use crate::state::LatestState;
+use crate::stats::LatencyStats;
+use crate::timing::FrameShaper;
 use core::time;
 use log::{debug, error, info, trace};
 use std::error::Error;
 use std::io::ErrorKind::WouldBlock;
 use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
 /// Gadget control thread
 ///
@@ -23,52 +34,101 @@ use std::thread;
 /// approx. 80 times per second
 ///
 /// In this thread we re-send everything received from the controller to the USB gadget
-/// However if there is input from midi device received from rx_midi
-/// We replace the pressed keys in the input report with de keys we hit on the midi device
+/// However while notes are held on the midi device (see midi_state) or buttons are pressed through the API
+/// We replace the pressed keys in the input report with de keys the active profile maps them to
+/// With frame timing every input report is one frame, see `FrameShaper`
 pub fn start_gadget(
     tx_controller: Sender<Vec<u8>>,
     rx_gadget: Receiver<Vec<u8>>,
-    rx_midi: Receiver<Vec<MidiMessageData>>,
+    midi_state: Arc<LatestState<Vec<MidiMessageData>>>,
+    stats: Arc<LatencyStats>,
+    control: Arc<Control>,
+    capture: Option<Arc<Capture>>,
+    stop: StopToken,
 ) -> Result<(), Box<dyn Error>> {
     info!("Starting gadget thread /dev/hidg0");
     let wait_ms = time::Duration::from_millis(5);
-    let mut gadget_device = DeviceFile::new("/dev/hidg0", true).unwrap();
+    let mut gadget_device = DeviceFile::new("/dev/hidg0", true)?;
+    control.set_gadget(Some(String::from("/dev/hidg0")));
+    // Timer of the last input report sent to the console, None until reports flow
+    let mut last_timer: Option<u8> = None;
+    let mut applied_generation = 0;
+    // Latest state generation an input report fully shows
+    let mut reflected_generation = 0;
+    let mut shaper = control.frame_timing().map(FrameShaper::new);
 
-    loop {
-        // Always receive MIDI messages at the top of the loop.
-        // Drain all available messages so we don't miss any button states.
-        let mut midi_messages = Vec::new();
-        while let Ok(batch) = rx_midi.try_recv() {
-            midi_messages.extend(batch);
+    while !stop.stopped() {
+        // Always take the latest MIDI state at the top of the loop,
+        // it holds every note currently held, so older states do not matter
+        let (midi_messages, generation) = midi_state.get();
+        if generation != applied_generation {
+            for midi_data in &midi_messages {
+                debug!("midi_rx -> {:#04X?}", midi_data.data_byte1);
+            }
+            applied_generation = generation;
         }
 
         match rx_gadget.try_recv() {
             Ok(mut controller_data) => {
                 trace!("rx_gadget -> gadget {:02X?}", controller_data);
                 // Check if input report from controller
-                if controller_data[0] == 0x30 {
-                    // Apply MIDI state if any
-                    if !midi_messages.is_empty() {
-                        let mut combined_report = [0u8; 3];
-                        for midi_data in midi_messages {
-                            debug!("midi_rx -> {:#04X?}", midi_data.data_byte1);
-                            let input_report = InputReport::from(&midi_data);
-                            combined_report[0] |= input_report.report[0];
-                            combined_report[1] |= input_report.report[1];
-                            combined_report[2] |= input_report.report[2];
-                        };
-
-                        controller_data[3] = combined_report[0];
-                        controller_data[4] = combined_report[1];
-                        controller_data[5] = combined_report[2];
+                // and apply MIDI state if any
+                let mut modified = false;
+                let overlay = if controller_data[0] != REPORT_ID_FULL {
+                    None
+                } else if let Some(shaper) = shaper.as_mut() {
+                    let wanted = control.overlay(&midi_messages);
+                    let shaped = shaper.next(wanted.clone(), control.take_presses());
+                    // While the shaper holds a change back the report does not carry it yet
+                    if shaped == wanted {
+                        reflected_generation = generation;
+                    }
+                    shaped
+                } else {
+                    reflected_generation = generation;
+                    control.overlay(&midi_messages)
+                };
+                if let Some(overlay) = overlay {
+                    match ProControllerReport::parse(&controller_data) {
+                        Ok(mut report) => {
+                            overlay.apply(&mut report);
+                            controller_data = report.to_bytes();
+                            modified = true;
+                        }
+                        Err(error) => error!("Unable to parse input report: {}", error),
                     }
                 }
 
+                if controller_data[0] == REPORT_ID_FULL {
+                    last_timer = Some(controller_data[1]);
+                }
+
+                capture::record(
+                    &capture,
+                    Interface::Gadget,
+                    Direction::Outbound,
+                    &controller_data,
+                    if modified { Some("modified by MIDI") } else { None },
+                );
+                let input_report = controller_data[0] == REPORT_ID_FULL;
+                if input_report {
+                    ACTIVITY.input_report(&controller_data);
+                }
                 match gadget_device.write(controller_data) {
                     Ok(()) => {
                         trace!("gadget <-");
+                        METRICS.report_forwarded(ReportDirection::ToConsole);
+                        if modified {
+                            METRICS.report_modified();
+                        }
+                        if input_report {
+                            stats.report_sent(reflected_generation, Instant::now());
+                        }
+                    }
+                    Err(error) => {
+                        METRICS.device_error(Device::Gadget, Operation::Write);
+                        error!("Unable to write to gadget: {}", error)
                     }
-                    Err(error) => error!("Unable to write to gadget: {}", error),
                 };
             }
             Err(TryRecvError::Empty) => {}
@@ -79,21 +139,49 @@ pub fn start_gadget(
         match gadget_device.read() {
             Ok(value) => {
                 trace!("gadget -> {:02X?}", value);
-                match tx_controller.send(value) {
-                    Ok(()) => {}
-                    Err(error) => {
-                        panic!("Cannot send to tx_controller {}", error);
+                capture::record(&capture, Interface::Gadget, Direction::Inbound, &value, None);
+                match OutputPacket::decode(&value) {
+                    // Rumble arrives continuously while a game vibrates
+                    packet @ OutputPacket::Rumble { .. } => trace!("console -> {}", packet),
+                    // ForceUsb is the last command of the USB handshake
+                    packet @ OutputPacket::UsbCommand { command: USB_FORCE_USB } => {
+                        METRICS.handshake_completed();
+                        debug!("console -> {}", packet);
+                        ACTIVITY.console_packet(&packet);
                     }
-                };
+                    packet => {
+                        debug!("console -> {}", packet);
+                        ACTIVITY.console_packet(&packet);
+                    }
+                }
+                // The controller thread may already be gone while shutting down
+                if let Err(error) = tx_controller.send(value) {
+                    if !stop.stopped() {
+                        control.set_gadget(None);
+                        return Err(format!("Cannot send to tx_controller {error}").into());
+                    }
+                }
             }
             Err(error) => {
                 // WouldBlock is expected behavior
                 // usually meaning there is no data in the device yet
                 if error.kind() != WouldBlock {
+                    METRICS.device_error(Device::Gadget, Operation::Read);
                     error!("Gadget read error: {}", error);
                 }
             }
         };
         thread::sleep(wait_ms);
     }
+
+    control.set_gadget(None);
+    // Make sure the console does not keep seeing buttons held by MIDI
+    if let Some(timer) = last_timer {
+        info!("Releasing all buttons before shutdown");
+        let neutral = ProControllerReport::neutral(timer.wrapping_add(1)).to_bytes();
+        capture::record(&capture, Interface::Gadget, Direction::Outbound, &neutral, Some("neutral on shutdown"));
+        gadget_device.write(neutral)?;
+    }
+    info!("Gadget thread stopped");
+    Ok(())
 }
@@ -0,0 +1,52 @@
+use crate::api::{self, Api};
+use crate::http::Request;
+use crate::shutdown;
+use core::time;
+use log::{debug, info, warn};
+use std::io::ErrorKind::WouldBlock;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+/// HTTP API thread
+/// Answers one request per connection until a shutdown is requested
+pub fn start_http(listener: TcpListener, api: Api) {
+    let wait_ms = time::Duration::from_millis(50);
+    if let Err(error) = listener.set_nonblocking(true) {
+        warn!("HTTP API disabled, unable to poll listener: {}", error);
+        return;
+    }
+    if let Ok(address) = listener.local_addr() {
+        info!("Starting HTTP API on http://{}", address);
+    }
+
+    while !shutdown::requested() {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                if let Err(error) = serve(stream, &api) {
+                    warn!("HTTP request from {} failed: {}", peer, error);
+                }
+            }
+            Err(error) if error.kind() == WouldBlock => thread::sleep(wait_ms),
+            Err(error) => {
+                warn!("HTTP accept failed: {}", error);
+                thread::sleep(wait_ms);
+            }
+        }
+    }
+    info!("HTTP API stopped");
+}
+
+fn serve(stream: TcpStream, api: &Api) -> Result<(), Box<dyn std::error::Error>> {
+    stream.set_nonblocking(false)?;
+    // A stuck client must not block the API for everybody else
+    stream.set_read_timeout(Some(time::Duration::from_secs(2)))?;
+    let response = match Request::read(&stream) {
+        Ok(request) => {
+            debug!("HTTP {} {}", request.method, request.path);
+            api.handle(&request)
+        }
+        Err(error) => api::error(400, &error.to_string()),
+    };
+    response.write(&stream)?;
+    Ok(())
+}
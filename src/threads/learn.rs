@@ -0,0 +1,81 @@
+use crate::control::Control;
+use crate::shutdown;
+use core::time;
+use log::info;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+
+/// Learn thread
+/// Prompts on the terminal for every target of the learn session in `control`,
+/// the MIDI callback answers them. Enter skips a target, `q` finishes early.
+/// Writes the learned profile to `path`, nothing when the service stops first.
+pub fn start_learn(control: Arc<Control>, path: PathBuf) -> Result<(), Box<dyn Error>> {
+    let wait_ms = time::Duration::from_millis(10);
+    let mut lines = Some(read_lines()?);
+    println!("Press a key or push a controller for every target, Enter skips, q and Enter finishes");
+
+    let mut prompted = None;
+    let mut assigned = 0;
+    let mut conflict = None;
+    while !shutdown::requested() {
+        let learn = control.learning().ok_or("Learn session ended")?;
+        for (source, target) in learn.assigned().iter().skip(assigned) {
+            println!("    {} = {}", source, target.name());
+        }
+        assigned = learn.assigned().len();
+        if learn.conflict() != conflict.as_ref() {
+            conflict = learn.conflict().cloned();
+            if let Some((source, target)) = &conflict {
+                println!("    {} already drives {}, try another one", source, target.name());
+            }
+        }
+        let target = match learn.current() {
+            Some(target) => target,
+            None => break,
+        };
+        let (step, steps) = learn.progress();
+        if prompted != Some(step) {
+            prompted = Some(step);
+            println!("[{}/{}] {}?", step, steps, target.name());
+        }
+
+        match lines.as_ref().map(|lines| lines.recv_timeout(wait_ms)) {
+            Some(Ok(line)) if line.trim().eq_ignore_ascii_case("q") => break,
+            Some(Ok(_)) => {
+                control.skip_learning();
+            }
+            Some(Err(RecvTimeoutError::Timeout)) => {}
+            // Without a terminal the session can only be answered by MIDI
+            Some(Err(RecvTimeoutError::Disconnected)) => lines = None,
+            None => thread::sleep(wait_ms),
+        }
+    }
+
+    if shutdown::requested() {
+        control.cancel_learning();
+        info!("Learning cancelled, {} is unchanged", path.display());
+        return Ok(());
+    }
+    let profile = control.finish_learning().ok_or("Learn session ended")?;
+    fs::write(&path, profile.to_text()).map_err(|e| format!("Unable to write {}: {}", path.display(), e))?;
+    info!("Wrote profile {} to {}", profile.name, path.display());
+    Ok(())
+}
+
+/// Lines typed on the terminal, the reader thread blocks on stdin until the process exits
+fn read_lines() -> Result<Receiver<String>, Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new().name(String::from("stdin")).spawn(move || {
+        for line in io::stdin().lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    })?;
+    Ok(rx)
+}
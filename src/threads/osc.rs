@@ -0,0 +1,47 @@
+use crate::control::Control;
+use crate::osc;
+use crate::shutdown;
+use core::time;
+use log::{debug, info, warn};
+use std::io::ErrorKind::{TimedOut, WouldBlock};
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+
+/// OSC thread
+/// Applies every received OSC message to the control state until a shutdown is requested
+pub fn start_osc(socket: UdpSocket, control: Arc<Control>) {
+    let wait_ms = time::Duration::from_millis(50);
+    if let Err(error) = socket.set_read_timeout(Some(wait_ms)) {
+        warn!("OSC input disabled, unable to poll socket: {}", error);
+        return;
+    }
+    if let Ok(address) = socket.local_addr() {
+        info!("Listening for OSC on udp://{}", address);
+    }
+
+    // The largest UDP payload, OSC has no framing of its own
+    let mut buffer = vec![0u8; 65536];
+    while !shutdown::requested() {
+        match socket.recv_from(&mut buffer) {
+            Ok((length, peer)) => match osc::parse_packet(&buffer[..length]) {
+                Ok(messages) => {
+                    for message in messages.iter() {
+                        if control.osc_message(message) {
+                            debug!("OSC {} from {}", message, peer);
+                        } else {
+                            debug!("Ignoring OSC {} from {}, no address matches", message, peer);
+                        }
+                    }
+                }
+                Err(error) => warn!("Invalid OSC packet from {}: {}", peer, error),
+            },
+            Err(error) if error.kind() == WouldBlock || error.kind() == TimedOut => {}
+            Err(error) => {
+                warn!("OSC receive failed: {}", error);
+                thread::sleep(wait_ms);
+            }
+        }
+    }
+    info!("OSC input stopped");
+}
@@ -0,0 +1,25 @@
+use crate::shutdown;
+use crate::stats::{self, LatencyStats};
+use core::time;
+use log::info;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Statistics thread
+/// Logs the MIDI-to-report latency every `interval` and whenever SIGUSR1 arrives,
+/// a last time when the service stops
+pub fn start_stats(stats: Arc<LatencyStats>, interval: Option<Duration>) {
+    let wait_ms = time::Duration::from_millis(100);
+    let mut last_log = Instant::now();
+
+    while !shutdown::requested() {
+        let due = interval.is_some_and(|interval| last_log.elapsed() >= interval);
+        if due || stats::take_dump_request() {
+            last_log = Instant::now();
+            info!("{}", stats.snapshot());
+        }
+        thread::sleep(wait_ms);
+    }
+    info!("{}", stats.snapshot());
+}
@@ -0,0 +1,80 @@
+use crate::activity::ACTIVITY;
+use crate::control::Control;
+use crate::dashboard::{self, Frame, Throughput};
+use crate::metrics::{ReportDirection, METRICS};
+use crate::shutdown;
+use crate::stats::LatencyStats;
+use core::time;
+use std::error::Error;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+/// Switches to the alternate screen and hides the cursor, the terminal is restored on exit
+const ENTER: &str = "\x1b[?1049h\x1b[?25l";
+const LEAVE: &str = "\x1b[?25h\x1b[?1049l";
+
+/// Leaves the alternate screen when dropped, also when drawing fails
+struct Screen;
+
+impl Screen {
+    fn enter() -> io::Result<Screen> {
+        let mut stdout = io::stdout();
+        write!(stdout, "{}", ENTER)?;
+        stdout.flush()?;
+        Ok(Screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "{}", LEAVE);
+        let _ = stdout.flush();
+    }
+}
+
+/// Dashboard thread
+/// Redraws the terminal dashboard ten times a second until the service stops
+pub fn start_tui(control: Arc<Control>, stats: Arc<LatencyStats>) -> Result<(), Box<dyn Error>> {
+    let wait_ms = time::Duration::from_millis(100);
+    let _screen = Screen::enter()?;
+    let mut stdout = io::stdout();
+
+    let totals = || {
+        [
+            METRICS.midi_messages_total(),
+            METRICS.reports_forwarded_total(ReportDirection::ToConsole),
+            METRICS.reports_forwarded_total(ReportDirection::ToController),
+        ]
+    };
+    let mut previous = (Instant::now(), totals());
+
+    while !shutdown::requested() {
+        thread::sleep(wait_ms);
+        let now = Instant::now();
+        let current = totals();
+        let seconds = now.duration_since(previous.0).as_secs_f64();
+        let rate = |i: usize| (current[i] - previous.1[i]) as f64 / seconds;
+        let throughput = Throughput {
+            midi: rate(0),
+            to_console: rate(1),
+            to_controller: rate(2),
+        };
+        previous = (now, current);
+
+        let frame = Frame {
+            now,
+            profile: &control.active_profile().name,
+            connections: &control.connections(),
+            activity: &ACTIVITY.snapshot(),
+            throughput: &throughput,
+            stats: &stats.snapshot(),
+            clock: &control.clock(),
+        };
+        write!(stdout, "{}", dashboard::render(&frame))?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use crate::activity::ACTIVITY;
+use crate::midi::MidiMessageData;
+use crate::control::Control;
+use crate::report::ProControllerReport;
+use crate::shutdown::StopToken;
+use crate::state::LatestState;
+use crate::stats::LatencyStats;
+use crate::timing::FrameShaper;
+use crate::uinput::{axis_changes, button_changes, UinputDevice};
+use core::time;
+use log::{debug, info};
+use std::error::Error;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+/// Virtual gamepad thread
+///
+/// Used instead of the gadget and controller threads when there is no Switch around.
+/// Every MIDI state update and API press is turned into the same report the gadget thread
+/// would send and the differences to the previous report are written to uinput,
+/// so mappings can be tried with evtest, jstest or any game on a Linux desktop.
+/// With frame timing the reports follow the console's pace instead of every update.
+pub fn start_uinput(
+    midi_state: Arc<LatestState<Vec<MidiMessageData>>>,
+    stats: Arc<LatencyStats>,
+    control: Arc<Control>,
+    stop: StopToken,
+) -> Result<(), Box<dyn Error>> {
+    info!("Starting uinput thread /dev/uinput");
+    let mut device = UinputDevice::new("/dev/uinput")?;
+    let mut previous = ProControllerReport::neutral(0);
+    control.set_gadget(Some(String::from("/dev/uinput")));
+    // Same pace as the console polls input reports, so presses from the API end on time
+    let wait_ms = time::Duration::from_millis(8);
+
+    let mut applied_generation = 0;
+    let mut shaper = control.frame_timing().map(FrameShaper::new);
+
+    while !stop.stopped() {
+        // Only the latest state matters, it holds every note currently held
+        let (midi_messages, generation) = match shaper {
+            Some(_) => {
+                thread::sleep(wait_ms);
+                midi_state.get()
+            }
+            None => midi_state
+                .wait_newer(applied_generation, wait_ms)
+                .unwrap_or_else(|| midi_state.get()),
+        };
+
+        // There is no controller underneath, so everything starts from neutral
+        let mut current = ProControllerReport::neutral(0);
+        let overlay = match shaper.as_mut() {
+            Some(shaper) => shaper.next(control.overlay(&midi_messages), control.take_presses()),
+            None => control.overlay(&midi_messages),
+        };
+        if let Some(overlay) = overlay {
+            overlay.apply(&mut current);
+        }
+        apply(&mut device, &previous, &current)?;
+        if current != previous && ACTIVITY.is_enabled() {
+            ACTIVITY.input_report(&current.to_bytes());
+        }
+        if generation != applied_generation {
+            stats.report_sent(generation, Instant::now());
+            applied_generation = generation;
+        }
+        previous = current;
+    }
+    control.set_gadget(None);
+
+    info!("Releasing all buttons before shutdown");
+    apply(&mut device, &previous, &ProControllerReport::neutral(0))?;
+    info!("uinput thread stopped");
+    Ok(())
+}
+
+/// Writes the differences between two reports as one batch of input events
+fn apply(device: &mut UinputDevice, previous: &ProControllerReport, current: &ProControllerReport) -> Result<(), Box<dyn Error>> {
+    let buttons = button_changes(&previous.buttons, &current.buttons);
+    let axes = axis_changes(previous, current);
+    if buttons.is_empty() && axes.is_empty() {
+        return Ok(());
+    }
+    for (button, pressed) in buttons {
+        debug!("uinput <- {:?} {}", button, if pressed { "pressed" } else { "released" });
+        device.press(&button, pressed)?;
+    }
+    for (axis, value) in axes {
+        debug!("uinput <- axis {:#04X?} {}", axis, value);
+        device.move_axis(axis, value)?;
+    }
+    device.sync()?;
+    Ok(())
+}
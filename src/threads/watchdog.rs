@@ -0,0 +1,37 @@
+use crate::control::Control;
+use crate::midi::MidiMessageData;
+use crate::shutdown;
+use crate::state::LatestState;
+use crate::watchdog::{self, Watchdog};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Watchdog thread
+/// Releases notes held longer than `max_hold` and all notes when Active Sensing
+/// stops for `sensing_timeout`. The state is changed in one atomic update so a
+/// press or release of the receiver in between is never lost, the receiver
+/// learns about the released notes through `control`
+pub fn start_watchdog(
+    midi_state: Arc<LatestState<Vec<MidiMessageData>>>,
+    control: Arc<Control>,
+    max_hold: Option<Duration>,
+    sensing_timeout: Option<Duration>,
+) {
+    let wait_ms = Duration::from_millis(10);
+    let mut watchdog = Watchdog::new(max_hold);
+
+    while !shutdown::requested() {
+        let now = Instant::now();
+        let sensing_lost = sensing_timeout.is_some_and(|timeout| control.sensing_lost(timeout, now));
+        let mut stuck = Vec::new();
+        midi_state.update(|held, _| {
+            stuck = watchdog.check(held, sensing_lost, now);
+            (!stuck.is_empty()).then(|| watchdog::release(held, &stuck))
+        });
+        if !stuck.is_empty() {
+            control.release_notes(&stuck);
+        }
+        thread::sleep(wait_ms);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::nscontroller::{Button, InputReport};
+use crate::profile::Overlay;
+
+/// Report counts that MIDI presses are stretched to
+///
+/// The console polls input reports about 125 times a second, games sample
+/// buttons once per report at best. A staccato note shorter than a report
+/// would be missed and two quick presses of one button would merge.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FrameTiming {
+    /// Reports a press is held for at least
+    pub min_press: u32,
+    /// Released reports between two presses of the same button
+    pub min_gap: u32,
+}
+
+impl Default for FrameTiming {
+    fn default() -> Self {
+        FrameTiming { min_press: 1, min_gap: 1 }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct ButtonTiming {
+    /// Pressed in the reports sent
+    sent: bool,
+    /// Reports since the last change of `sent`
+    frames: u32,
+    /// A press that still has to reach a report
+    queued: bool,
+}
+
+/// Applies `FrameTiming` report by report
+///
+/// Presses and releases take effect at the next report, a press is kept until
+/// it was sent `min_press` times and a new press waits until the button was
+/// released for `min_gap` reports. Presses are never dropped, a note that was
+/// pressed and released between two reports still presses its button.
+#[derive(Debug)]
+pub struct FrameShaper {
+    timing: FrameTiming,
+    buttons: Vec<ButtonTiming>,
+}
+
+impl FrameShaper {
+    pub fn new(timing: FrameTiming) -> FrameShaper {
+        let released = ButtonTiming {
+            frames: u32::MAX,
+            ..ButtonTiming::default()
+        };
+        FrameShaper {
+            timing,
+            buttons: vec![released; Button::ALL.len()],
+        }
+    }
+
+    /// Overlay of the next report
+    ///
+    /// `overlay` is what MIDI and the API hold right now, `pressed` the buttons
+    /// of notes pressed since the previous report, held or not.
+    pub fn next(&mut self, overlay: Option<Overlay>, pressed: Option<InputReport>) -> Option<Overlay> {
+        let mut overlay = overlay.unwrap_or_default();
+        let wanted = overlay.buttons.clone().unwrap_or_default();
+        let pressed = pressed.unwrap_or_default();
+        let mut shaped = InputReport::new();
+        for (button, timing) in Button::ALL.iter().zip(self.buttons.iter_mut()) {
+            let held = wanted.is_pressed(button);
+            if pressed.is_pressed(button) {
+                timing.queued = true;
+            }
+            if timing.sent {
+                // A queued press while sent is a repeat, it needs a release in between
+                if timing.frames >= self.timing.min_press && (!held || timing.queued) {
+                    timing.sent = false;
+                    timing.frames = 0;
+                }
+            } else if (held || timing.queued) && timing.frames >= self.timing.min_gap {
+                timing.sent = true;
+                timing.frames = 0;
+                timing.queued = false;
+            }
+            timing.frames = timing.frames.saturating_add(1);
+            if timing.sent {
+                let _ = shaped.press_one(button);
+            }
+        }
+
+        if overlay.buttons.is_some() || shaped != InputReport::new() {
+            overlay.buttons = Some(shaped);
+        }
+        if overlay.is_empty() {
+            None
+        } else {
+            Some(overlay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::StickPosition;
+
+    fn buttons(pressed: &[Button]) -> Option<InputReport> {
+        let mut report = InputReport::new();
+        for button in pressed {
+            report.press_one(button).unwrap();
+        }
+        Some(report)
+    }
+
+    fn overlay(pressed: &[Button]) -> Option<Overlay> {
+        Some(Overlay {
+            buttons: buttons(pressed),
+            ..Overlay::default()
+        })
+    }
+
+    fn sent(shaper: &mut FrameShaper, held: &[Button], pressed: &[Button]) -> Option<InputReport> {
+        let held = if held.is_empty() { None } else { overlay(held) };
+        shaper.next(held, buttons(pressed)).and_then(|overlay| overlay.buttons)
+    }
+
+    #[test]
+    fn short_notes_are_held_for_the_minimum() {
+        let mut shaper = FrameShaper::new(FrameTiming { min_press: 2, min_gap: 1 });
+        // Pressed and released between two reports
+        assert_eq!(sent(&mut shaper, &[], &[Button::A]), buttons(&[Button::A]));
+        assert_eq!(sent(&mut shaper, &[], &[]), buttons(&[Button::A]));
+        assert_eq!(sent(&mut shaper, &[], &[]), None);
+
+        // Longer presses are left alone
+        assert_eq!(sent(&mut shaper, &[Button::B], &[Button::B]), buttons(&[Button::B]));
+        assert_eq!(sent(&mut shaper, &[Button::B], &[]), buttons(&[Button::B]));
+        assert_eq!(sent(&mut shaper, &[Button::B], &[]), buttons(&[Button::B]));
+        // Buttons held through the API have no recorded press
+        assert_eq!(sent(&mut shaper, &[Button::X], &[]), buttons(&[Button::X]));
+    }
+
+    #[test]
+    fn repeated_presses_keep_a_gap() {
+        let mut shaper = FrameShaper::new(FrameTiming { min_press: 1, min_gap: 2 });
+        assert_eq!(sent(&mut shaper, &[Button::A], &[Button::A]), buttons(&[Button::A]));
+        // Released and pressed again before the next report
+        assert_eq!(sent(&mut shaper, &[Button::A], &[Button::A]), buttons(&[]));
+        assert_eq!(sent(&mut shaper, &[Button::A], &[]), buttons(&[]));
+        assert_eq!(sent(&mut shaper, &[Button::A], &[]), buttons(&[Button::A]));
+
+        // Other buttons are not held up
+        assert_eq!(sent(&mut shaper, &[], &[Button::B]), buttons(&[Button::B]));
+    }
+
+    #[test]
+    fn keeps_sticks_of_the_overlay() {
+        let mut shaper = FrameShaper::new(FrameTiming::default());
+        let sticks = Overlay {
+            left_stick: Some(StickPosition::CENTER),
+            ..Overlay::default()
+        };
+        assert_eq!(shaper.next(Some(sticks.clone()), None), Some(sticks));
+        assert_eq!(shaper.next(None, None), None);
+    }
+}
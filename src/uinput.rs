@@ -0,0 +1,227 @@
+use crate::nscontroller::{Button, InputReport};
+use crate::report::ProControllerReport;
+use libc::{c_int, input_event, uinput_abs_setup, uinput_setup};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::os::unix::io::AsRawFd;
+
+// Linux input event types and codes (linux/input-event-codes.h)
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0x00;
+const BUS_USB: u16 = 0x03;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const ABS_RX: u16 = 0x03;
+pub const ABS_RY: u16 = 0x04;
+
+/// Sticks are reported with the same 12-bit resolution the Pro Controller uses
+pub const AXIS_MIN: i32 = 0;
+pub const AXIS_MAX: i32 = 4095;
+pub const AXIS_CENTER: i32 = 2048;
+
+// uinput ioctls (linux/uinput.h)
+const UI_DEV_CREATE: u32 = 0x5501;
+const UI_DEV_DESTROY: u32 = 0x5502;
+const UI_DEV_SETUP: u32 = 0x405c5503;
+const UI_ABS_SETUP: u32 = 0x401c5504;
+const UI_SET_EVBIT: u32 = 0x40045564;
+const UI_SET_KEYBIT: u32 = 0x40045565;
+const UI_SET_ABSBIT: u32 = 0x40045567;
+
+const DEVICE_NAME: &str = "Pro Controller (midi_to_switch)";
+
+/// Key codes follow the kernel's hid-nintendo driver, so a real Pro Controller
+/// and the virtual one look the same to games and SDL mappings
+pub fn button_code(button: &Button) -> u16 {
+    match button {
+        Button::B => 0x130,          // BTN_SOUTH
+        Button::A => 0x131,          // BTN_EAST
+        Button::X => 0x133,          // BTN_NORTH
+        Button::Y => 0x134,          // BTN_WEST
+        Button::Capture => 0x135,    // BTN_Z
+        Button::L => 0x136,          // BTN_TL
+        Button::R => 0x137,          // BTN_TR
+        Button::ZL => 0x138,         // BTN_TL2
+        Button::ZR => 0x139,         // BTN_TR2
+        Button::Minus => 0x13a,      // BTN_SELECT
+        Button::Plus => 0x13b,       // BTN_START
+        Button::Home => 0x13c,       // BTN_MODE
+        Button::LeftStick => 0x13d,  // BTN_THUMBL
+        Button::RightStick => 0x13e, // BTN_THUMBR
+        Button::DpadUp => 0x220,     // BTN_DPAD_UP
+        Button::DpadDown => 0x221,   // BTN_DPAD_DOWN
+        Button::DpadLeft => 0x222,   // BTN_DPAD_LEFT
+        Button::DpadRight => 0x223,  // BTN_DPAD_RIGHT
+    }
+}
+
+/// Lists the buttons whose state differs between two reports
+/// together with their new state
+pub fn button_changes(previous: &InputReport, current: &InputReport) -> Vec<(Button, bool)> {
+    Button::ALL
+        .iter()
+        .filter(|button| previous.is_pressed(button) != current.is_pressed(button))
+        .map(|button| (button.clone(), current.is_pressed(button)))
+        .collect()
+}
+
+/// Lists the axes whose value differs between two reports together with their new value
+///
+/// Switch sticks report up as larger values while evdev expects up to be smaller,
+/// the same inversion hid-nintendo applies
+pub fn axis_changes(previous: &ProControllerReport, current: &ProControllerReport) -> Vec<(u16, i32)> {
+    let axes = [
+        (ABS_X, previous.left_stick.x, current.left_stick.x, false),
+        (ABS_Y, previous.left_stick.y, current.left_stick.y, true),
+        (ABS_RX, previous.right_stick.x, current.right_stick.x, false),
+        (ABS_RY, previous.right_stick.y, current.right_stick.y, true),
+    ];
+    axes.iter()
+        .filter(|(_, before, after, _)| before != after)
+        .map(|(axis, _, after, inverted)| {
+            let value = *after as i32;
+            (*axis, if *inverted { AXIS_MAX - value } else { value })
+        })
+        .collect()
+}
+
+/// Virtual gamepad created through /dev/uinput
+///
+/// The device disappears again when the struct is dropped
+pub struct UinputDevice {
+    fp: File,
+}
+
+impl UinputDevice {
+    pub fn new(dev_path: &str) -> Result<UinputDevice, Error> {
+        let fp = OpenOptions::new().write(true).open(dev_path)?;
+        let device = UinputDevice { fp };
+
+        device.ioctl_int(UI_SET_EVBIT, EV_KEY as c_int)?;
+        for button in Button::ALL.iter() {
+            device.ioctl_int(UI_SET_KEYBIT, button_code(button) as c_int)?;
+        }
+
+        device.ioctl_int(UI_SET_EVBIT, EV_ABS as c_int)?;
+        for axis in [ABS_X, ABS_Y, ABS_RX, ABS_RY] {
+            device.ioctl_int(UI_SET_ABSBIT, axis as c_int)?;
+            let mut abs_setup: uinput_abs_setup = unsafe { std::mem::zeroed() };
+            abs_setup.code = axis;
+            abs_setup.absinfo.value = AXIS_CENTER;
+            abs_setup.absinfo.minimum = AXIS_MIN;
+            abs_setup.absinfo.maximum = AXIS_MAX;
+            abs_setup.absinfo.fuzz = 16;
+            abs_setup.absinfo.flat = 128;
+            device.ioctl_ptr(UI_ABS_SETUP, &abs_setup)?;
+        }
+
+        let mut setup: uinput_setup = unsafe { std::mem::zeroed() };
+        setup.id.bustype = BUS_USB;
+        setup.id.vendor = 0x057e;
+        setup.id.product = 0x2009;
+        setup.id.version = 1;
+        for (dst, src) in setup.name.iter_mut().zip(DEVICE_NAME.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        device.ioctl_ptr(UI_DEV_SETUP, &setup)?;
+        device.ioctl_int(UI_DEV_CREATE, 0)?;
+
+        Ok(device)
+    }
+
+    pub fn press(&mut self, button: &Button, pressed: bool) -> Result<(), Error> {
+        self.emit(EV_KEY, button_code(button), pressed as i32)
+    }
+
+    pub fn move_axis(&mut self, axis: u16, value: i32) -> Result<(), Error> {
+        self.emit(EV_ABS, axis, value)
+    }
+
+    /// Marks the end of a batch of changes so readers apply them atomically
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.emit(EV_SYN, SYN_REPORT, 0)
+    }
+
+    fn emit(&mut self, type_: u16, code: u16, value: i32) -> Result<(), Error> {
+        // The kernel fills in the timestamp
+        let mut event: input_event = unsafe { std::mem::zeroed() };
+        event.type_ = type_;
+        event.code = code;
+        event.value = value;
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &event as *const input_event as *const u8,
+                std::mem::size_of::<input_event>(),
+            )
+        };
+        self.fp.write_all(bytes)
+    }
+
+    fn ioctl_int(&self, request: u32, value: c_int) -> Result<(), Error> {
+        if unsafe { libc::ioctl(self.fp.as_raw_fd(), request as _, value) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn ioctl_ptr<T>(&self, request: u32, value: &T) -> Result<(), Error> {
+        if unsafe { libc::ioctl(self.fp.as_raw_fd(), request as _, value as *const T) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UinputDevice {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ioctl(self.fp.as_raw_fd(), UI_DEV_DESTROY as _);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::StickPosition;
+    use std::collections::HashSet;
+
+    #[test]
+    fn every_button_has_a_unique_key_code() {
+        let codes: HashSet<u16> = Button::ALL.iter().map(button_code).collect();
+        assert_eq!(codes.len(), Button::ALL.len());
+    }
+
+    #[test]
+    fn button_changes_reports_presses_and_releases() {
+        let mut previous = InputReport::new();
+        previous.report[0] = 0x01; // Y
+        let mut current = InputReport::new();
+        current.report[0] = 0x08; // A
+
+        let changes = button_changes(&previous, &current);
+        assert_eq!(changes, vec![(Button::Y, false), (Button::A, true)]);
+        assert!(button_changes(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn axis_changes_inverts_vertical_axes() {
+        let previous = ProControllerReport::neutral(0);
+        let mut current = ProControllerReport::neutral(0);
+        current.left_stick.y = StickPosition::MAX;
+        current.right_stick.x = 0;
+
+        let changes = axis_changes(&previous, &current);
+        assert_eq!(changes, vec![(ABS_Y, AXIS_MIN), (ABS_RX, AXIS_MIN)]);
+    }
+
+    #[test]
+    fn ioctl_sizes_match_kernel_structs() {
+        // The size is encoded in bits 16..30 of the request number
+        assert_eq!((UI_DEV_SETUP >> 16) & 0x3fff, std::mem::size_of::<uinput_setup>() as u32);
+        assert_eq!((UI_ABS_SETUP >> 16) & 0x3fff, std::mem::size_of::<uinput_abs_setup>() as u32);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::midi::{MidiMessageData, MidiMessageTypes};
+use crate::midi_parser::{data_length, MidiMessage, MidiParser};
+
+/// Center of a 32 bit controller or pitch bend value
+pub const CENTER_32: u32 = 0x8000_0000;
+
+/// MIDI 2.0 channel voice message, values at full resolution
+///
+/// ```text
+/// word 0: 4 group | status channel | index 1 | index 2
+/// word 1: data, 16 bit velocity and 16 bit attribute for notes, else 32 bit value
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub enum Midi2Message {
+    NoteOff { note: u8, velocity: u16 },
+    /// Unlike MIDI 1.0, velocity 0 is still a note on
+    NoteOn { note: u8, velocity: u16 },
+    PolyphonicPressure { note: u8, pressure: u32 },
+    ControlChange { controller: u8, value: u32 },
+    ProgramChange { program: u8 },
+    ChannelPressure { pressure: u32 },
+    PitchBend { value: u32 },
+    /// Bends one held note, `CENTER_32` is no bend
+    PerNotePitchBend { note: u8, value: u32 },
+}
+
+/// Message of a Universal MIDI Packet stream, groups are squashed like channels
+#[derive(Debug, PartialEq, Clone)]
+pub enum UmpMessage {
+    /// System messages and MIDI 1.0 channel voice messages
+    Midi1(MidiMessage),
+    Midi2 { channel: u8, message: Midi2Message },
+}
+
+impl UmpMessage {
+    /// Same message for MIDI 1.0 consumers, values are cut to 7 or 14 bits.
+    /// Per-note pitch bend has no MIDI 1.0 form.
+    pub fn to_midi1(&self) -> Option<MidiMessage> {
+        let (channel, message) = match self {
+            UmpMessage::Midi1(message) => return Some(message.clone()),
+            UmpMessage::Midi2 { channel, message } => (*channel, message),
+        };
+        Some(match *message {
+            Midi2Message::NoteOff { note, velocity } => MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity: scale_down(velocity as u32, 16, 7) as u8,
+            },
+            // Velocity 0 would turn it into a note off
+            Midi2Message::NoteOn { note, velocity } => MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity: (scale_down(velocity as u32, 16, 7) as u8).max(1),
+            },
+            Midi2Message::PolyphonicPressure { note, pressure } => MidiMessage::PolyphonicPressure {
+                channel,
+                note,
+                pressure: scale_down(pressure, 32, 7) as u8,
+            },
+            Midi2Message::ControlChange { controller, value } => MidiMessage::ControlChange {
+                channel,
+                controller,
+                value: scale_down(value, 32, 7) as u8,
+            },
+            Midi2Message::ProgramChange { program } => MidiMessage::ProgramChange { channel, program },
+            Midi2Message::ChannelPressure { pressure } => MidiMessage::ChannelPressure {
+                channel,
+                pressure: scale_down(pressure, 32, 7) as u8,
+            },
+            Midi2Message::PitchBend { value } => MidiMessage::PitchBend {
+                channel,
+                value: scale_down(value, 32, 14) as u16,
+            },
+            Midi2Message::PerNotePitchBend { .. } => return None,
+        })
+    }
+}
+
+impl Midi2Message {
+    /// Entry of the shared MIDI state keeping the full resolution,
+    /// None for messages the state does not track
+    pub fn to_data(&self) -> Option<MidiMessageData> {
+        let (status_byte, data_byte1, value) = match *self {
+            Midi2Message::NoteOff { note, velocity } => (MidiMessageTypes::NoteOff, note, scale_up(velocity as u32, 16, 32)),
+            Midi2Message::NoteOn { note, velocity } => (MidiMessageTypes::NoteOn, note, scale_up(velocity as u32, 16, 32)),
+            Midi2Message::ControlChange { controller, value } => (MidiMessageTypes::ControlChange, controller, value),
+            Midi2Message::PerNotePitchBend { note, value } => (MidiMessageTypes::PerNotePitchBend, note, value),
+            _ => return None,
+        };
+        let mut data_byte2 = scale_down(value, 32, 7) as u8;
+        if status_byte == MidiMessageTypes::NoteOn {
+            data_byte2 = data_byte2.max(1);
+        }
+        Some(MidiMessageData {
+            status_byte,
+            data_byte1,
+            data_byte2,
+            value,
+        })
+    }
+}
+
+/// Universal MIDI Packet stream parser
+///
+/// Packets are 1 to 4 words long, the message type in the top nibble of the
+/// first word tells how many. Words of an unfinished packet are kept for the
+/// next call. Utility, data, flex data and stream messages are skipped, as are
+/// MIDI 2.0 messages without a counterpart in the state (RPN, NRPN and
+/// per-note controllers).
+#[derive(Debug, Default)]
+pub struct UmpParser {
+    words: Vec<u32>,
+}
+
+impl UmpParser {
+    pub fn new() -> UmpParser {
+        UmpParser::default()
+    }
+
+    pub fn parse(&mut self, words: &[u32]) -> Vec<UmpMessage> {
+        words.iter().filter_map(|word| self.push(*word)).collect()
+    }
+
+    pub fn push(&mut self, word: u32) -> Option<UmpMessage> {
+        self.words.push(word);
+        if self.words.len() < packet_words(self.words[0]) {
+            return None;
+        }
+        let packet = std::mem::take(&mut self.words);
+        decode(&packet)
+    }
+}
+
+/// Length of a packet in words by its message type
+fn packet_words(first: u32) -> usize {
+    match first >> 28 {
+        0x0..=0x2 | 0x6 | 0x7 => 1,
+        0x3 | 0x4 | 0x8..=0xA => 2,
+        0xB | 0xC => 3,
+        _ => 4,
+    }
+}
+
+fn decode(packet: &[u32]) -> Option<UmpMessage> {
+    let [_, status, index1, index2] = packet[0].to_be_bytes();
+    match packet[0] >> 28 {
+        // System real-time and common, MIDI 1.0 channel voice: the MIDI 1.0 bytes follow the group
+        0x1 | 0x2 => {
+            let length = 1 + data_length(status);
+            let bytes = [status, index1, index2];
+            match MidiParser::new().parse(&bytes[..length]).pop() {
+                Some(Ok(message)) => Some(UmpMessage::Midi1(message)),
+                _ => None,
+            }
+        }
+        0x4 => {
+            let data = packet[1];
+            let note = index1 & 0x7F;
+            let message = match status >> 4 {
+                0x8 => Midi2Message::NoteOff { note, velocity: (data >> 16) as u16 },
+                0x9 => Midi2Message::NoteOn { note, velocity: (data >> 16) as u16 },
+                0xA => Midi2Message::PolyphonicPressure { note, pressure: data },
+                0xB => Midi2Message::ControlChange { controller: note, value: data },
+                0xC => Midi2Message::ProgramChange { program: (data >> 24) as u8 & 0x7F },
+                0xD => Midi2Message::ChannelPressure { pressure: data },
+                0xE => Midi2Message::PitchBend { value: data },
+                0x6 => Midi2Message::PerNotePitchBend { note, value: data },
+                // Per-note management with the reset flag sets the note's controllers back
+                0xF if index2 & 0x01 != 0 => Midi2Message::PerNotePitchBend { note, value: CENTER_32 },
+                _ => return None,
+            };
+            Some(UmpMessage::Midi2 { channel: status & 0x0F, message })
+        }
+        _ => None,
+    }
+}
+
+/// Min-center-max upscaling of the MIDI 2.0 translation rules
+///
+/// Values up to the center are shifted, above it the lower bits repeat so the
+/// maximum stays the maximum: 64 of 7 bits is exactly `CENTER_32`, 127 is `u32::MAX`.
+pub fn scale_up(value: u32, from_bits: u32, to_bits: u32) -> u32 {
+    let scale_bits = to_bits - from_bits;
+    let shifted = (value as u64) << scale_bits;
+    let center = 1u64 << (from_bits - 1);
+    if (value as u64) <= center {
+        return shifted as u32;
+    }
+    let repeat_bits = from_bits - 1;
+    let mut repeat = value as u64 & ((1u64 << repeat_bits) - 1);
+    repeat = if scale_bits > repeat_bits {
+        repeat << (scale_bits - repeat_bits)
+    } else {
+        repeat >> (repeat_bits - scale_bits)
+    };
+    let mut result = shifted;
+    while repeat != 0 {
+        result |= repeat;
+        repeat >>= repeat_bits;
+    }
+    result as u32
+}
+
+/// Downscaling keeps the upper bits
+pub fn scale_down(value: u32, from_bits: u32, to_bits: u32) -> u32 {
+    value >> (from_bits - to_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn midi2(status: u8, index1: u8, index2: u8, data: u32) -> [u32; 2] {
+        [u32::from_be_bytes([0x40, status, index1, index2]), data]
+    }
+
+    #[test]
+    fn scales_between_resolutions() {
+        assert_eq!(scale_up(0, 7, 32), 0);
+        assert_eq!(scale_up(64, 7, 32), CENTER_32);
+        assert_eq!(scale_up(127, 7, 32), u32::MAX);
+        assert_eq!(scale_up(0x2000, 14, 32), CENTER_32);
+        assert_eq!(scale_up(0x3FFF, 14, 32), u32::MAX);
+        assert_eq!(scale_up(0xFFFF, 16, 32), u32::MAX);
+        // Every 7 bit value survives the round trip
+        for value in 0..128 {
+            assert_eq!(scale_down(scale_up(value, 7, 32), 32, 7), value);
+        }
+    }
+
+    #[test]
+    fn parses_midi2_channel_voice() {
+        let mut parser = UmpParser::new();
+        let mut words = Vec::new();
+        words.extend(midi2(0x93, 60, 0, 0xFFFF_0000));
+        words.extend(midi2(0xB3, 1, 0, 0x1234_5678));
+        words.extend(midi2(0x63, 60, 0, 0xC000_0000));
+        words.extend(midi2(0xF3, 60, 0x01, 0));
+        words.extend(midi2(0x23, 0, 0, 0)); // NRPN, skipped
+        assert_eq!(
+            parser.parse(&words),
+            vec![
+                UmpMessage::Midi2 { channel: 3, message: Midi2Message::NoteOn { note: 60, velocity: 0xFFFF } },
+                UmpMessage::Midi2 { channel: 3, message: Midi2Message::ControlChange { controller: 1, value: 0x1234_5678 } },
+                UmpMessage::Midi2 { channel: 3, message: Midi2Message::PerNotePitchBend { note: 60, value: 0xC000_0000 } },
+                UmpMessage::Midi2 { channel: 3, message: Midi2Message::PerNotePitchBend { note: 60, value: CENTER_32 } },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_midi1_and_system_packets_across_calls() {
+        let mut parser = UmpParser::new();
+        let note = midi2(0x80, 60, 0, 0x8000_0000);
+        assert_eq!(parser.parse(&[0x2090_3C40, 0x10F8_0000, note[0]]), vec![
+            UmpMessage::Midi1(MidiMessage::NoteOn { channel: 0, note: 60, velocity: 0x40 }),
+            UmpMessage::Midi1(MidiMessage::TimingClock),
+        ]);
+        assert_eq!(
+            parser.parse(&[note[1], 0x0000_0000, 0x3001_0203, 0x0405_0000]),
+            vec![UmpMessage::Midi2 { channel: 0, message: Midi2Message::NoteOff { note: 60, velocity: 0x8000 } }]
+        );
+    }
+
+    #[test]
+    fn downconverts_for_midi1_consumers() {
+        let convert = |message| UmpMessage::Midi2 { channel: 2, message }.to_midi1();
+        assert_eq!(
+            convert(Midi2Message::NoteOn { note: 60, velocity: 0 }),
+            Some(MidiMessage::NoteOn { channel: 2, note: 60, velocity: 1 })
+        );
+        assert_eq!(
+            convert(Midi2Message::ControlChange { controller: 1, value: u32::MAX }),
+            Some(MidiMessage::ControlChange { channel: 2, controller: 1, value: 127 })
+        );
+        assert_eq!(
+            convert(Midi2Message::PitchBend { value: CENTER_32 }),
+            Some(MidiMessage::PitchBend { channel: 2, value: 0x2000 })
+        );
+        assert_eq!(convert(Midi2Message::PerNotePitchBend { note: 60, value: 0 }), None);
+    }
+
+    #[test]
+    fn keeps_full_resolution_in_the_state() {
+        let control = Midi2Message::ControlChange { controller: 1, value: 0x8123_4567 }.to_data().unwrap();
+        assert_eq!(control.status_byte, MidiMessageTypes::ControlChange);
+        assert_eq!((control.data_byte1, control.data_byte2, control.value), (1, 0x40, 0x8123_4567));
+
+        let note = Midi2Message::NoteOn { note: 60, velocity: 0x0100 }.to_data().unwrap();
+        assert_eq!(note.data_byte2, 1);
+        assert!(note.should_add_midi_message());
+        assert_eq!(Midi2Message::PitchBend { value: 0 }.to_data(), None);
+    }
+}
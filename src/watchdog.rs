@@ -0,0 +1,110 @@
+use crate::midi::MidiMessageData;
+use log::warn;
+use std::time::{Duration, Instant};
+
+/// Time without Active Sensing after which a receiver turns its notes off, from the MIDI specification
+pub const SENSING_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Releases notes whose Note Off got lost
+///
+/// A note held longer than `max_hold` is released, as are all notes once an input
+/// that sent Active Sensing goes quiet. Controllers are left alone.
+#[derive(Debug)]
+pub struct Watchdog {
+    max_hold: Option<Duration>,
+    /// Held notes and when the watchdog first saw them
+    held: Vec<(u8, Instant)>,
+}
+
+impl Watchdog {
+    pub fn new(max_hold: Option<Duration>) -> Watchdog {
+        Watchdog {
+            max_hold,
+            held: Vec::new(),
+        }
+    }
+
+    /// Notes of the state to release
+    pub fn check(&mut self, state: &[MidiMessageData], sensing_lost: bool, now: Instant) -> Vec<u8> {
+        let notes: Vec<u8> = state
+            .iter()
+            .filter(|entry| entry.is_note())
+            .map(|entry| entry.data_byte1)
+            .collect();
+        self.held.retain(|(note, _)| notes.contains(note));
+        for note in notes.iter() {
+            if !self.held.iter().any(|(held, _)| held == note) {
+                self.held.push((*note, now));
+            }
+        }
+        if notes.is_empty() {
+            return notes;
+        }
+
+        let stuck: Vec<u8> = if sensing_lost {
+            warn!("Active Sensing stopped, releasing all notes");
+            notes
+        } else {
+            let Some(max_hold) = self.max_hold else {
+                return Vec::new();
+            };
+            let stuck: Vec<u8> = self
+                .held
+                .iter()
+                .filter(|(_, since)| now.saturating_duration_since(*since) >= max_hold)
+                .map(|(note, _)| *note)
+                .collect();
+            for note in stuck.iter() {
+                warn!("Releasing note {} held for more than {:?}", note, max_hold);
+            }
+            stuck
+        };
+        self.held.retain(|(note, _)| !stuck.contains(note));
+        stuck
+    }
+}
+
+/// The state without `notes`, per-note pitch bends go with their note
+pub fn release(state: &[MidiMessageData], notes: &[u8]) -> Vec<MidiMessageData> {
+    state
+        .iter()
+        .filter(|entry| entry.is_control_change() || !notes.contains(&entry.data_byte1))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{control, note_on};
+
+    #[test]
+    fn releases_notes_held_too_long() {
+        let mut watchdog = Watchdog::new(Some(Duration::from_secs(5)));
+        let start = Instant::now();
+        let modulation = control(1, 64);
+        assert!(watchdog.check(&[note_on(60, 100), modulation.clone()], false, start).is_empty());
+        let later = start + Duration::from_secs(3);
+        assert!(watchdog.check(&[note_on(60, 100), note_on(62, 100), modulation.clone()], false, later).is_empty());
+        let state = [note_on(60, 100), note_on(62, 100), modulation.clone()];
+        let stuck = watchdog.check(&state, false, start + Duration::from_secs(5));
+        assert_eq!(stuck, vec![60]);
+        assert_eq!(release(&state, &stuck), vec![note_on(62, 100), modulation.clone()]);
+
+        // Pressed again, the note counts from then
+        let again = start + Duration::from_secs(6);
+        assert!(watchdog.check(&[note_on(62, 100), modulation.clone()], false, again).is_empty());
+        assert!(watchdog.check(&[note_on(60, 100), note_on(62, 100), modulation.clone()], false, again).is_empty());
+        assert_eq!(watchdog.check(&[note_on(60, 100), note_on(62, 100)], false, start + Duration::from_secs(10)), vec![62]);
+    }
+
+    #[test]
+    fn lost_sensing_releases_every_note() {
+        let mut watchdog = Watchdog::new(None);
+        let start = Instant::now();
+        let modulation = control(1, 64);
+        assert!(watchdog.check(&[note_on(60, 100), modulation.clone()], false, start + Duration::from_secs(60)).is_empty());
+        assert_eq!(watchdog.check(&[note_on(60, 100), note_on(62, 100), modulation.clone()], true, start), vec![60, 62]);
+        assert!(watchdog.check(&[modulation], true, start).is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+use midi_to_switch::capture::{Capture, Direction, Interface, LINKTYPE_USER0};
+use std::fs::remove_file;
+use std::path::PathBuf;
+
+fn temp_path(name: &str) -> PathBuf {
+    let mut p = std::env::temp_dir();
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    p.push(format!("midi_to_switch_test_{}_{}.pcapng", name, ts));
+    p
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// Splits the file into (block type, block) pairs, checking both length fields agree
+fn blocks(data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let block_type = u32_at(data, offset);
+        let length = u32_at(data, offset + 4) as usize;
+        assert!(length.is_multiple_of(4), "blocks must be 32 bit aligned");
+        assert_eq!(u32_at(data, offset + length - 4) as usize, length);
+        result.push((block_type, data[offset..offset + length].to_vec()));
+        offset += length;
+    }
+    result
+}
+
+#[test]
+fn writes_header_and_interfaces() {
+    let path = temp_path("header");
+    Capture::new(path.to_str().unwrap()).expect("create capture");
+
+    let data = std::fs::read(&path).expect("read capture");
+    let blocks = blocks(&data);
+    assert_eq!(blocks.len(), 4);
+    assert_eq!(blocks[0].0, 0x0A0D0D0A);
+    assert_eq!(u32_at(&blocks[0].1, 8), 0x1A2B3C4D);
+    for (block_type, block) in &blocks[1..] {
+        assert_eq!(*block_type, 0x00000001);
+        assert_eq!(u16::from_le_bytes([block[8], block[9]]), LINKTYPE_USER0);
+    }
+
+    remove_file(path).expect("cleanup");
+}
+
+#[test]
+fn records_packets_with_interface_and_direction() {
+    let path = temp_path("packets");
+    let capture = Capture::new(path.to_str().unwrap()).expect("create capture");
+    capture
+        .record(Interface::Gadget, Direction::Outbound, &[0x30, 0x01, 0x81], Some("modified by MIDI"))
+        .expect("record");
+    capture
+        .record(Interface::Midi, Direction::Inbound, &[0x90, 0x3C, 0x40], None)
+        .expect("record");
+
+    let data = std::fs::read(&path).expect("read capture");
+    let packets: Vec<Vec<u8>> = blocks(&data)
+        .into_iter()
+        .filter(|(block_type, _)| *block_type == 0x00000006)
+        .map(|(_, block)| block)
+        .collect();
+    assert_eq!(packets.len(), 2);
+
+    let first = &packets[0];
+    assert_eq!(u32_at(first, 8), Interface::Gadget as u32);
+    assert_eq!(u32_at(first, 20), 3);
+    assert_eq!(&first[28..31], &[0x30, 0x01, 0x81]);
+    // comment option follows the padded packet data
+    assert_eq!(u16::from_le_bytes([first[32], first[33]]), 1);
+    assert_eq!(&first[36..52], b"modified by MIDI");
+    // then epb_flags with the direction
+    assert_eq!(u16::from_le_bytes([first[52], first[53]]), 2);
+    assert_eq!(u32_at(first, 56), Direction::Outbound as u32);
+
+    let second = &packets[1];
+    assert_eq!(u32_at(second, 8), Interface::Midi as u32);
+    assert_eq!(u32_at(second, 36), Direction::Inbound as u32);
+
+    remove_file(path).expect("cleanup");
+}
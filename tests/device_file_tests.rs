@@ -74,3 +74,12 @@ fn write_overwrites_file() {
 
 	remove_file(path).expect("cleanup");
 }
+
+#[test]
+fn write_reports_errors() {
+	// Every write to /dev/full fails with ENOSPC, like writes to an unplugged controller fail
+	let mut dev = midi_to_switch::device_file::DeviceFile::new("/dev/full", false)
+		.expect("open /dev/full");
+
+	assert!(dev.write(vec![1u8, 2, 3]).is_err());
+}
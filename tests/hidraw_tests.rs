@@ -0,0 +1,67 @@
+use midi_to_switch::hidraw::{find_controller, scan};
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::path::{Path, PathBuf};
+
+fn temp_sysfs(name: &str) -> PathBuf {
+    let mut p = std::env::temp_dir();
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    p.push(format!("midi_to_switch_test_{}_{}", name, ts));
+    create_dir_all(p.join("class/hidraw")).expect("create fake sysfs");
+    p
+}
+
+fn add_hidraw(sysfs: &Path, node: &str, hid_id: &str, name: &str) {
+    let device = sysfs.join("class/hidraw").join(node).join("device");
+    create_dir_all(&device).expect("create device dir");
+    let uevent = format!("DRIVER=hid-generic\nHID_ID={}\nHID_NAME={}\nHID_PHYS=usb-3f980000.usb-1.3/input0\n", hid_id, name);
+    write(device.join("uevent"), uevent).expect("write uevent");
+}
+
+#[test]
+fn finds_pro_controller_behind_other_devices() {
+    let sysfs = temp_sysfs("hidraw_pro");
+    add_hidraw(&sysfs, "hidraw0", "0003:0000046D:0000C52B", "Logitech USB Receiver");
+    add_hidraw(&sysfs, "hidraw1", "0003:0000057E:00002006", "Nintendo Co., Ltd. Joy-Con (L)");
+    add_hidraw(&sysfs, "hidraw2", "0003:0000057E:00002009", "Nintendo Co., Ltd. Pro Controller");
+
+    let path = find_controller(&sysfs, Path::new("/dev")).expect("controller should be found");
+    assert_eq!(path, PathBuf::from("/dev/hidraw2"));
+
+    remove_dir_all(sysfs).expect("cleanup");
+}
+
+#[test]
+fn falls_back_to_joycon() {
+    let sysfs = temp_sysfs("hidraw_joycon");
+    add_hidraw(&sysfs, "hidraw0", "0003:0000046D:0000C52B", "Logitech USB Receiver");
+    add_hidraw(&sysfs, "hidraw1", "0003:0000057E:00002007", "Nintendo Co., Ltd. Joy-Con (R)");
+
+    let path = find_controller(&sysfs, Path::new("/dev")).expect("controller should be found");
+    assert_eq!(path, PathBuf::from("/dev/hidraw1"));
+
+    remove_dir_all(sysfs).expect("cleanup");
+}
+
+#[test]
+fn error_lists_candidates() {
+    let sysfs = temp_sysfs("hidraw_none");
+    add_hidraw(&sysfs, "hidraw0", "0003:0000046D:0000C52B", "Logitech USB Receiver");
+    // Entries without a readable uevent are skipped
+    create_dir_all(sysfs.join("class/hidraw/hidraw1")).expect("create dir");
+
+    assert_eq!(scan(&sysfs).unwrap().len(), 1);
+    let error = find_controller(&sysfs, Path::new("/dev")).unwrap_err().to_string();
+    assert!(error.contains("No Nintendo controller found"), "unexpected error: {}", error);
+    assert!(error.contains("hidraw0 (046D:C52B Logitech USB Receiver)"), "unexpected error: {}", error);
+
+    remove_dir_all(sysfs).expect("cleanup");
+}
+
+#[test]
+fn missing_sysfs_is_an_error() {
+    let missing = std::env::temp_dir().join("midi_to_switch_test_no_such_sysfs");
+    assert!(find_controller(&missing, Path::new("/dev")).is_err());
+}
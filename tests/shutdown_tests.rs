@@ -0,0 +1,13 @@
+use midi_to_switch::shutdown;
+
+#[test]
+fn sigterm_requests_shutdown() {
+    shutdown::install_signal_handlers();
+    assert!(!shutdown::requested());
+
+    // systemd stops the service with SIGTERM
+    unsafe {
+        libc::raise(libc::SIGTERM);
+    }
+    assert!(shutdown::requested());
+}
@@ -0,0 +1,32 @@
+use midi_to_switch::shutdown;
+use midi_to_switch::supervisor::Supervisor;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn restarts_failed_worker_and_stops_on_shutdown() {
+    let mut supervisor = Supervisor::new("test");
+    supervisor.initial_backoff = Duration::from_millis(1);
+    supervisor.poll_interval = Duration::from_millis(1);
+
+    let mut starts = 0;
+    let result = supervisor.run(|stop| {
+        starts += 1;
+        let first = starts == 1;
+        let worker = thread::spawn(move || {
+            if first {
+                return Err(String::from("device gone"));
+            }
+            // The restarted worker runs until the service shuts down
+            shutdown::request();
+            while !stop.stopped() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Ok(())
+        });
+        Ok(vec![worker])
+    });
+
+    assert_eq!(starts, 2);
+    assert!(result.is_ok(), "clean shutdown expected: {:?}", result);
+}
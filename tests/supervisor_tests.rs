@@ -0,0 +1,62 @@
+use midi_to_switch::supervisor::{Supervisor, Worker};
+use std::thread;
+use std::time::Duration;
+
+fn fast_supervisor(max_failures: u32) -> Supervisor {
+    let mut supervisor = Supervisor::new("test");
+    supervisor.max_failures = max_failures;
+    supervisor.initial_backoff = Duration::from_millis(1);
+    supervisor.poll_interval = Duration::from_millis(1);
+    supervisor
+}
+
+fn failing_worker() -> Worker {
+    thread::Builder::new()
+        .name(String::from("failing"))
+        .spawn(|| Err(String::from("device gone")))
+        .unwrap()
+}
+
+#[test]
+fn gives_up_after_repeated_failures() {
+    let mut starts = 0;
+    let result = fast_supervisor(3).run(|_stop| {
+        starts += 1;
+        Ok(vec![failing_worker()])
+    });
+
+    assert_eq!(starts, 3);
+    let error = result.expect_err("supervisor should give up");
+    assert!(error.contains("3 times"), "unexpected error: {}", error);
+}
+
+#[test]
+fn failed_start_counts_as_failure() {
+    let mut starts = 0;
+    let result = fast_supervisor(2).run(|_stop| {
+        starts += 1;
+        Err(String::from("no UDC"))
+    });
+
+    assert_eq!(starts, 2);
+    assert!(result.is_err());
+}
+
+#[test]
+fn stops_whole_group_when_one_worker_fails() {
+    let mut starts = 0;
+    let _ = fast_supervisor(2).run(|stop| {
+        starts += 1;
+        // This one only ends when the supervisor stops the group
+        let waiting = thread::spawn(move || {
+            while !stop.stopped() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Ok(())
+        });
+        Ok(vec![failing_worker(), waiting])
+    });
+
+    // Reaching this point means the waiting worker was stopped and joined
+    assert_eq!(starts, 2);
+}